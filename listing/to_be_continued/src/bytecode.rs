@@ -1,27 +1,31 @@
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum ByteCode {
     // local variable
     LoadConst(u8, u16),
+    LoadConstX(u8, u16),
     LoadNil(u8, u8),
     LoadBool(u8, bool),
     LoadInt(u8, i16),
     Move(u8, u8),
+    Move2(u8, u8, u8), // superinstruction of 2 Moves
 
     // upvalues
     GetUpvalue(u8, u8),
     SetUpvalue(u8, u8),
     SetUpvalueConst(u8, u8),
     Close(u8),
+    Tbc(u8),
 
     // table
     NewTable(u8, u8, u8),
+    NewTableConst(u8, u16),
     SetTable(u8, u8, u8),
     SetField(u8, u8, u8),
     SetInt(u8, u8, u8),
     SetTableConst(u8, u8, u8),
     SetFieldConst(u8, u8, u8),
     SetIntConst(u8, u8, u8),
-    SetList(u8, u8),
+    SetList(u8, u8, u8), // (table, n, #batches before), see Table::set_list()
     GetTable(u8, u8, u8),
     GetField(u8, u8, u8),
     GetInt(u8, u8, u8),
@@ -34,6 +38,7 @@ pub enum ByteCode {
 
     // condition structures
     Jump(i16),
+    LongJump(i8, u16),
     TestAndJump(u8, i16),
     TestOrJump(u8, i16),
     TestAndSetJump(u8, u8, u8),
@@ -47,6 +52,8 @@ pub enum ByteCode {
     // function call
     Closure(u8, u16),
     Call(u8, u8, u8),
+    CallField(u8, u8, u8), // superinstruction of GetField and Call without argument
+    CallUpField(u8, u8, u8), // superinstruction of GetUpField and Call without argument
     CallSet(u8, u8, u8),
     TailCall(u8, u8),
     Return0,
@@ -116,7 +123,19 @@ pub enum ByteCode {
     GreaterInt(u8, u8, bool),
     GreaterConst(u8, u8, bool),
 
+    // superinstructions of comparison and Jump, made by peephole optimizer
+    EqualJump(u8, u8, i8),
+    EqualIntJump(u8, u8, i8),
+    EqualConstJump(u8, u8, i8),
+    LessJump(u8, u8, i8),
+    LesEqJump(u8, u8, i8),
+    LessIntJump(u8, u8, i8),
+    GreaterIntJump(u8, u8, i8),
+
     SetFalseSkip(u8),
 
     Concat(u8, u8, u8),
+
+    // extra argument of the previous byte code
+    ExtraArg(u16),
 }
@@ -0,0 +1,1436 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::HashMap;
+use crate::bytecode::ByteCode;
+use crate::parse::{FuncProto, UpIndex};
+use crate::peephole::{Peephole, skips_next};
+use crate::value::Value;
+use crate::packed::slot_ref;
+use crate::utils::{ftoi, idiv_int, idiv_float, mod_int, mod_float, shift_left, shift_right};
+
+// Mid-level IR optimizer over the byte codes of one function, which
+// runs after the single-pass parsing if enabled (`-O` option).
+//
+// The byte codes are split into basic blocks, and each register written
+// by a byte code becomes an SSA value, while phi values merge different
+// values of a register at the beginning of blocks. The passes analyze
+// the SSA values and patch the byte codes in place, so no register
+// allocation is needed to go back:
+//
+// - inline small local functions, which are only called but not escape;
+// - propagate constants across statements, and fold the operations and
+//   the conditional jumps on them;
+// - eliminate dead stores, whose values are never used;
+// - hoist lookups of global variables out of loops, which call nothing
+//   and do not write the variables.
+//
+// The registers captured by closures, or to be closed, may be accessed
+// out of the function, so they are never taken as constants or dead.
+//
+// The function is left unoptimized if some jump becomes too long.
+pub fn optimize(fp: &mut FuncProto, env: Option<usize>) {
+    let mut ph = Peephole::new(&fp.byte_codes);
+
+    // the SSA is built on reachable byte codes only
+    ph.simplify();
+    inline_calls(&mut ph, fp);
+    ph.simplify();
+    propagate_constants(&mut ph, fp);
+    ph.simplify();
+    eliminate_dead_stores(&mut ph, fp);
+    ph.simplify();
+    if let Some(env) = env {
+        hoist_global_lookups(&mut ph, fp, env);
+    }
+
+    if let Some(byte_codes) = ph.encode() {
+        fp.byte_codes = byte_codes;
+    }
+}
+
+// definition of SSA value
+enum Def {
+    Entry, // at function entry, parameters or undefined
+    Code(usize), // written by the byte code at pc
+    Phi(Vec<usize>), // merged from the values at the end of predecessors
+}
+
+struct SsaValue {
+    reg: usize,
+    def: Def,
+}
+
+struct Block {
+    start: usize,
+    end: usize,
+    preds: Vec<usize>,
+    succs: Vec<usize>,
+}
+
+struct Ssa {
+    nreg: usize, // frame size
+    volatile: Vec<bool>, // registers captured by closures or to be closed
+    blocks: Vec<Block>,
+    rpo: Vec<usize>, // blocks in reverse post-order
+    values: Vec<SsaValue>, // the first @nreg ones are the entry values
+    uses: Vec<Vec<usize>>, // values read by each byte code, in order of regs()
+    defs: Vec<Vec<usize>>, // values written by each byte code
+}
+
+impl Ssa {
+    fn new(ph: &Peephole, fp: &FuncProto) -> Self {
+        let codes = &ph.codes;
+        let n = codes.len();
+        let nreg = frame_size(codes, fp.nparam);
+
+        let mut volatile = vec![false; nreg];
+        for code in codes.iter() {
+            match *code {
+                ByteCode::Closure(_, inner) => {
+                    if let Value::LuaFunction(inner) = &*fp.constant(inner as usize) {
+                        for up in inner.upindexes.iter() {
+                            if let &UpIndex::Local(i) = up {
+                                if i < nreg {
+                                    volatile[i] = true;
+                                }
+                            }
+                        }
+                    }
+                }
+                ByteCode::Tbc(i) => volatile[i as usize] = true,
+                _ => (),
+            }
+        }
+
+        // split basic blocks
+        let succs: Vec<Vec<usize>> = (0..n).map(|pc| ph.successors(pc)).collect();
+        let mut leader = vec![false; n + 1];
+        leader[0] = true;
+        for pc in 0..n {
+            if succs[pc] != [pc + 1] {
+                leader[pc + 1] = true;
+                for &s in succs[pc].iter() {
+                    leader[s] = true;
+                }
+            }
+        }
+        let mut blocks: Vec<Block> = Vec::new();
+        let mut block_of = vec![0; n];
+        for pc in 0..n {
+            if leader[pc] {
+                blocks.push(Block { start: pc, end: pc, preds: Vec::new(), succs: Vec::new() });
+            }
+            let b = blocks.len() - 1;
+            blocks[b].end = pc + 1;
+            block_of[pc] = b;
+        }
+        for b in 0..blocks.len() {
+            let mut bsuccs: Vec<usize> = succs[blocks[b].end - 1].iter()
+                .filter(|&&s| s < n)
+                .map(|&s| block_of[s])
+                .collect();
+            bsuccs.sort();
+            bsuccs.dedup();
+            for &s in bsuccs.iter() {
+                blocks[s].preds.push(b);
+            }
+            blocks[b].succs = bsuccs;
+        }
+
+        // reverse post-order by depth-first search
+        let mut visited = vec![false; blocks.len()];
+        let mut rpo = Vec::with_capacity(blocks.len());
+        let mut stack = vec![(0, 0)];
+        visited[0] = true;
+        while let Some((b, i)) = stack.pop() {
+            if let Some(&s) = blocks[b].succs.get(i) {
+                stack.push((b, i + 1));
+                if !visited[s] {
+                    visited[s] = true;
+                    stack.push((s, 0));
+                }
+            } else {
+                rpo.push(b);
+            }
+        }
+        rpo.reverse();
+
+        // values written by byte codes
+        let mut values: Vec<SsaValue> = (0..nreg).map(|reg| SsaValue { reg, def: Def::Entry }).collect();
+        let defs: Vec<Vec<usize>> = codes.iter().enumerate().map(|(pc, code)| {
+            regs(code, nreg).1.into_iter().map(|reg| {
+                values.push(SsaValue { reg, def: Def::Code(pc) });
+                values.len() - 1
+            }).collect()
+        }).collect();
+
+        // values of registers at the end of each block, until fixed
+        let mut phis = HashMap::new();
+        let mut outs: Vec<Option<Vec<usize>>> = vec![None; blocks.len()];
+        loop {
+            let mut changed = false;
+            for &b in rpo.iter() {
+                let mut state = merge(b, &blocks[b].preds, &outs, nreg, &mut phis, &mut values);
+                for pc_defs in defs[blocks[b].start .. blocks[b].end].iter() {
+                    for &v in pc_defs.iter() {
+                        state[values[v].reg] = v;
+                    }
+                }
+                if outs[b].as_ref() != Some(&state) {
+                    outs[b] = Some(state);
+                    changed = true;
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+        for (&(b, reg), &phi) in phis.iter() {
+            let mut args: Vec<usize> = blocks[b].preds.iter()
+                .map(|&p| outs[p].as_ref().map_or(reg, |out| out[reg]))
+                .collect();
+            if b == 0 {
+                args.push(reg);
+            }
+            values[phi].def = Def::Phi(args);
+        }
+
+        // values read by byte codes
+        let mut uses = vec![Vec::new(); n];
+        for (b, block) in blocks.iter().enumerate() {
+            let mut state = merge(b, &block.preds, &outs, nreg, &mut phis, &mut values);
+            for pc in block.start .. block.end {
+                uses[pc] = regs(&codes[pc], nreg).0.into_iter().map(|reg| state[reg]).collect();
+                for &v in defs[pc].iter() {
+                    state[values[v].reg] = v;
+                }
+            }
+        }
+
+        Ssa { nreg, volatile, blocks, rpo, values, uses, defs }
+    }
+
+    // Users of each value: byte codes with the operand index in uses,
+    // or None for phi values.
+    fn users(&self) -> Vec<Vec<Option<(usize, usize)>>> {
+        let mut users = vec![Vec::new(); self.values.len()];
+        for (pc, uses) in self.uses.iter().enumerate() {
+            for (i, &v) in uses.iter().enumerate() {
+                users[v].push(Some((pc, i)));
+            }
+        }
+        for value in self.values.iter() {
+            if let Def::Phi(args) = &value.def {
+                for &v in args.iter() {
+                    users[v].push(None);
+                }
+            }
+        }
+        users
+    }
+
+    // Constant propagation. Values start from Top and only go down, so
+    // the loop ends.
+    fn constants(&self, codes: &[ByteCode], fp: &FuncProto) -> Vec<Lattice> {
+        let mut lats = vec![Lattice::Top; self.values.len()];
+        loop {
+            let mut changed = false;
+            for (v, value) in self.values.iter().enumerate() {
+                let lat = match &value.def {
+                    _ if self.volatile[value.reg] => Lattice::Bottom,
+                    Def::Entry => Lattice::Bottom,
+                    Def::Phi(args) => args.iter().fold(Lattice::Top, |acc, &a| acc.meet(&lats[a])),
+                    &Def::Code(pc) => {
+                        let args: Vec<&Lattice> = self.uses[pc].iter().map(|&u| &lats[u]).collect();
+                        eval(&codes[pc], value.reg, &args, fp)
+                    }
+                };
+                if !lat.same(&lats[v]) {
+                    lats[v] = lat;
+                    changed = true;
+                }
+            }
+            if !changed {
+                return lats;
+            }
+        }
+    }
+
+    // Whether the values are numbers surely. They start from true and
+    // only go false, so the loop ends.
+    fn numbers(&self, codes: &[ByteCode], fp: &FuncProto) -> Vec<bool> {
+        let mut nums = vec![true; self.values.len()];
+        loop {
+            let mut changed = false;
+            for (v, value) in self.values.iter().enumerate() {
+                if !nums[v] {
+                    continue;
+                }
+                let num = match &value.def {
+                    _ if self.volatile[value.reg] => false,
+                    Def::Entry => false,
+                    Def::Phi(args) => args.iter().all(|&a| nums[a]),
+                    &Def::Code(pc) => match codes[pc] {
+                        ByteCode::LoadInt(_, _) | ByteCode::ForPrepare(_, _) | ByteCode::ForLoop(_, _)
+                            | ByteCode::Neg(_, _) | ByteCode::BitNot(_, _) | ByteCode::Len(_, _) => true,
+                        ByteCode::LoadConst(_, k) => is_number(&fp.constant(k as usize)),
+                        ByteCode::Move(_, _) => nums[self.uses[pc][0]],
+                        code => decode_arith(&code).is_some(),
+                    }
+                };
+                if !num {
+                    nums[v] = false;
+                    changed = true;
+                }
+            }
+            if !changed {
+                return nums;
+            }
+        }
+    }
+
+    // index of each block in the reverse post-order
+    fn rpo_order(&self) -> Vec<usize> {
+        let mut order = vec![usize::MAX; self.blocks.len()];
+        for (i, &b) in self.rpo.iter().enumerate() {
+            order[b] = i;
+        }
+        order
+    }
+
+    // immediate dominators of blocks, by Cooper-Harvey-Kennedy algorithm
+    fn dominators(&self, order: &[usize]) -> Vec<usize> {
+        let mut idom = vec![usize::MAX; self.blocks.len()];
+        idom[0] = 0;
+        loop {
+            let mut changed = false;
+            for &b in self.rpo[1..].iter() {
+                let mut new = usize::MAX;
+                for &p in self.blocks[b].preds.iter() {
+                    if idom[p] == usize::MAX {
+                        continue;
+                    }
+                    if new == usize::MAX {
+                        new = p;
+                        continue;
+                    }
+                    let mut x = p;
+                    while x != new {
+                        while order[x] > order[new] {
+                            x = idom[x];
+                        }
+                        while order[new] > order[x] {
+                            new = idom[new];
+                        }
+                    }
+                }
+                if idom[b] != new {
+                    idom[b] = new;
+                    changed = true;
+                }
+            }
+            if !changed {
+                return idom;
+            }
+        }
+    }
+
+    // Natural loops, as header and blocks in the loop, with outer loops
+    // first. Loops with the same header are merged.
+    fn loops(&self) -> Vec<(usize, Vec<bool>)> {
+        let order = self.rpo_order();
+        let idom = self.dominators(&order);
+        let dominates = |a: usize, mut b: usize| loop {
+            if a == b {
+                return true;
+            }
+            if b == 0 || idom[b] == usize::MAX {
+                return false;
+            }
+            b = idom[b];
+        };
+
+        let mut bodies: Vec<Option<Vec<bool>>> = vec![None; self.blocks.len()];
+        for &b in self.rpo.iter() {
+            for &h in self.blocks[b].succs.iter() {
+                // the header is not later than the end in reverse
+                // post-order, so check it first which is cheap
+                if order[h] > order[b] || !dominates(h, b) {
+                    continue;
+                }
+                // back edge from @b to @h
+                let body = bodies[h].get_or_insert_with(|| vec![false; self.blocks.len()]);
+                body[h] = true;
+                let mut pending = vec![b];
+                while let Some(x) = pending.pop() {
+                    if !body[x] {
+                        body[x] = true;
+                        pending.extend(self.blocks[x].preds.iter());
+                    }
+                }
+            }
+        }
+
+        let mut loops: Vec<(usize, Vec<bool>)> = bodies.into_iter().enumerate()
+            .filter_map(|(h, body)| body.map(|body| (h, body)))
+            .collect();
+        loops.sort_by_key(|(_, body)| Reverse(body.iter().filter(|&&x| x).count()));
+        loops
+    }
+
+    // Byte codes which call the function value @v, following the copies
+    // by Move. Return None if the function escapes, i.e. it is used in
+    // other ways or may be accessed by closures.
+    fn call_sites(&self, v: usize, users: &[Vec<Option<(usize, usize)>>], codes: &[ByteCode]) -> Option<Vec<usize>> {
+        let mut calls = Vec::new();
+        let mut pending = vec![v];
+        while let Some(v) = pending.pop() {
+            if self.volatile[self.values[v].reg] {
+                return None;
+            }
+            for &user in users[v].iter() {
+                let (pc, i) = user?;
+                match codes[pc] {
+                    ByteCode::Move(_, _) => pending.push(self.defs[pc][0]),
+                    ByteCode::Call(_, narg_plus, _) | ByteCode::CallSet(_, _, narg_plus)
+                        if i == 0 && narg_plus != 0 => calls.push(pc),
+                    _ => return None,
+                }
+            }
+        }
+        Some(calls)
+    }
+}
+
+// Values of registers at the beginning of block @b, merged from the
+// ends of its predecessors. A phi value is created for the register
+// if the values are different, and it is kept even if they become
+// same later.
+fn merge(b: usize, preds: &[usize], outs: &[Option<Vec<usize>>], nreg: usize,
+        phis: &mut HashMap<(usize, usize), usize>, values: &mut Vec<SsaValue>) -> Vec<usize> {
+
+    let entry: Vec<usize> = (0..nreg).collect();
+    let mut ins: Vec<&Vec<usize>> = preds.iter().filter_map(|&p| outs[p].as_ref()).collect();
+    if b == 0 {
+        ins.push(&entry);
+    }
+    if ins.is_empty() {
+        return entry;
+    }
+
+    (0..nreg).map(|reg| {
+        if let Some(&phi) = phis.get(&(b, reg)) {
+            return phi;
+        }
+        let v = ins[0][reg];
+        if ins.iter().all(|state| state[reg] == v) {
+            v
+        } else {
+            values.push(SsaValue { reg, def: Def::Phi(Vec::new()) });
+            phis.insert((b, reg), values.len() - 1);
+            values.len() - 1
+        }
+    }).collect()
+}
+
+// Registers read and written by the byte code, where @nreg is the
+// frame size. The byte codes working on the stack top, e.g. Call,
+// read or clobber all registers above.
+fn regs(code: &ByteCode, nreg: usize) -> (Vec<usize>, Vec<usize>) {
+    let r = |reg: u8| reg as usize;
+    // from @reg to the stack top, but at least @n registers
+    let top = |reg: usize, n: usize| (reg .. nreg.max(reg + n)).collect::<Vec<_>>();
+    // arguments of function call
+    let args = |func: u8, narg_plus: u8| match narg_plus {
+        0 => top(r(func), 1),
+        _ => (r(func) .. r(func) + narg_plus as usize).collect(),
+    };
+
+    if let Some((_, dst, a, b)) = decode_arith(code) {
+        return match b {
+            Operand::Reg(b) => (vec![r(a), r(b)], vec![r(dst)]),
+            _ => (vec![r(a)], vec![r(dst)]),
+        };
+    }
+    if let Some((_, a, b, _)) = decode_compare(code) {
+        return match b {
+            Operand::Reg(b) => (vec![r(a), r(b)], vec![]),
+            _ => (vec![r(a)], vec![]),
+        };
+    }
+
+    match *code {
+        ByteCode::LoadConst(dst, _) | ByteCode::LoadConstX(dst, _) | ByteCode::LoadBool(dst, _)
+            | ByteCode::LoadInt(dst, _) | ByteCode::GetUpvalue(dst, _)
+            | ByteCode::NewTable(dst, _, _) | ByteCode::NewTableConst(dst, _)
+            | ByteCode::GetUpField(dst, _, _) | ByteCode::Closure(dst, _)
+            | ByteCode::SetFalseSkip(dst) => (vec![], vec![r(dst)]),
+        ByteCode::LoadNil(dst, n) => (vec![], top(r(dst), n as usize)),
+        ByteCode::Move(dst, src) | ByteCode::Neg(dst, src) | ByteCode::Not(dst, src)
+            | ByteCode::BitNot(dst, src) | ByteCode::Len(dst, src) => (vec![r(src)], vec![r(dst)]),
+        ByteCode::Move2(dst, src1, src2) => (vec![r(src1), r(src2)], vec![r(dst), r(dst) + 1]),
+
+        ByteCode::SetUpvalue(_, src) | ByteCode::SetUpField(_, _, src) | ByteCode::Tbc(src) => (vec![r(src)], vec![]),
+
+        ByteCode::SetTable(t, k, v) => (vec![r(t), r(k), r(v)], vec![]),
+        ByteCode::SetField(t, _, v) | ByteCode::SetInt(t, _, v) => (vec![r(t), r(v)], vec![]),
+        ByteCode::SetTableConst(t, k, _) => (vec![r(t), r(k)], vec![]),
+        ByteCode::SetFieldConst(t, _, _) | ByteCode::SetIntConst(t, _, _) => (vec![r(t)], vec![]),
+        ByteCode::SetList(t, n, _) => {
+            let values = top(r(t) + 1, n as usize);
+            ([r(t)].into_iter().chain(values.iter().copied()).collect(), values)
+        }
+        ByteCode::GetTable(dst, t, k) => (vec![r(t), r(k)], vec![r(dst)]),
+        ByteCode::GetField(dst, t, _) | ByteCode::GetInt(dst, t, _) => (vec![r(t)], vec![r(dst)]),
+        ByteCode::GetFieldSelf(dst, t, _) => (vec![r(t)], vec![r(dst), r(dst) + 1]),
+
+        ByteCode::TestAndJump(c, _) | ByteCode::TestOrJump(c, _) => (vec![r(c)], vec![]),
+        // @dst keeps the old value if not jump
+        ByteCode::TestAndSetJump(dst, c, _) | ByteCode::TestOrSetJump(dst, c, _) => (vec![r(c), r(dst)], vec![r(dst)]),
+
+        ByteCode::ForPrepare(i, _) => ((r(i)..r(i)+3).collect(), (r(i)..r(i)+3).collect()),
+        ByteCode::ForLoop(i, _) => ((r(i)..r(i)+3).collect(), vec![r(i)]),
+        ByteCode::ForCallLoop(i, nvar, _) => ((r(i)..r(i)+3).collect(), top(r(i) + 2, nvar as usize + 1)),
+
+        ByteCode::Call(func, narg_plus, want) => (args(func, narg_plus), top(r(func), want as usize)),
+        ByteCode::CallField(func, t, _) => (vec![r(t)], top(r(func), 0)),
+        ByteCode::CallUpField(func, _, _) => (vec![], top(r(func), 0)),
+        ByteCode::CallSet(dst, func, narg_plus) => {
+            let mut defs = top(r(func), 1);
+            if dst < func {
+                defs.insert(0, r(dst));
+            }
+            (args(func, narg_plus), defs)
+        }
+        ByteCode::TailCall(func, narg_plus) => (args(func, narg_plus), vec![]),
+        ByteCode::Return(iret, 0) => (top(r(iret), 0), vec![]),
+        ByteCode::Return(iret, nret) => ((r(iret) .. r(iret) + nret as usize).collect(), vec![]),
+        ByteCode::VarArgs(dst, want) => (vec![], top(r(dst), want as usize)),
+
+        ByteCode::EqualJump(a, b, _) | ByteCode::LessJump(a, b, _)
+            | ByteCode::LesEqJump(a, b, _) => (vec![r(a), r(b)], vec![]),
+        ByteCode::EqualIntJump(a, _, _) | ByteCode::EqualConstJump(a, _, _)
+            | ByteCode::LessIntJump(a, _, _) | ByteCode::GreaterIntJump(a, _, _) => (vec![r(a)], vec![]),
+
+        ByteCode::Concat(dst, first, n) => ((r(first) .. r(first) + n as usize).collect(), vec![r(dst)]),
+
+        _ => (vec![], vec![]),
+    }
+}
+
+// number of registers used by the byte codes
+fn frame_size(codes: &[ByteCode], nparam: usize) -> usize {
+    codes.iter()
+        .flat_map(|code| {
+            let (uses, defs) = regs(code, 0);
+            uses.into_iter().chain(defs)
+        })
+        .map(|reg| reg + 1)
+        .max().unwrap_or(0)
+        .max(nparam)
+}
+
+// constant propagation lattice
+#[derive(Clone)]
+enum Lattice {
+    Top, // not decided yet
+    Const(Value),
+    Bottom, // not constant
+}
+
+impl Lattice {
+    // only these types can be constant in byte codes
+    fn constant(v: &Value) -> Self {
+        match v {
+            Value::Nil | Value::Boolean(_) | Value::Integer(_) | Value::Float(_)
+                | Value::ShortStr(_, _) | Value::MidStr(_) | Value::LongStr(_) => Lattice::Const(v.clone()),
+            _ => Lattice::Bottom,
+        }
+    }
+    fn meet(self, other: &Lattice) -> Self {
+        match (self, other) {
+            (Lattice::Top, other) => other.clone(),
+            (lat, Lattice::Top) => lat,
+            (Lattice::Const(v1), Lattice::Const(v2)) if v1.same(v2) => Lattice::Const(v1),
+            (_, _) => Lattice::Bottom,
+        }
+    }
+    fn same(&self, other: &Lattice) -> bool {
+        match (self, other) {
+            (Lattice::Top, Lattice::Top) | (Lattice::Bottom, Lattice::Bottom) => true,
+            (Lattice::Const(v1), Lattice::Const(v2)) => v1.same(v2),
+            (_, _) => false,
+        }
+    }
+    fn value(&self) -> Option<&Value> {
+        match self {
+            Lattice::Const(v) => Some(v),
+            _ => None,
+        }
+    }
+}
+
+// Evaluate the value of register @reg written by @code, with its
+// operand values @args.
+fn eval(code: &ByteCode, reg: usize, args: &[&Lattice], fp: &FuncProto) -> Lattice {
+    if let Some((op, _, _, b)) = decode_arith(code) {
+        let b = match b {
+            Operand::Reg(_) => args[1].clone(),
+            Operand::Int(i) => Lattice::Const(Value::Integer(i as i64)),
+            Operand::Const(k) => Lattice::constant(&fp.constant(k as usize)),
+        };
+        return fold(&[args[0], &b], |vs| arith(op, vs[0], vs[1]));
+    }
+
+    match *code {
+        ByteCode::LoadConst(_, k) => Lattice::constant(&fp.constant(k as usize)),
+        ByteCode::LoadInt(_, i) => Lattice::Const(Value::Integer(i as i64)),
+        ByteCode::LoadBool(_, b) => Lattice::Const(Value::Boolean(b)),
+        ByteCode::LoadNil(dst, n) if reg < dst as usize + n as usize => Lattice::Const(Value::Nil),
+        ByteCode::Move(_, _) => args[0].clone(),
+        // the second Move follows the first one
+        ByteCode::Move2(dst, _, src2) if reg == dst as usize || src2 == dst => args[0].clone(),
+        ByteCode::Move2(_, _, _) => args[1].clone(),
+        ByteCode::Neg(_, _) | ByteCode::Not(_, _) | ByteCode::BitNot(_, _) | ByteCode::Len(_, _) =>
+            fold(args, |vs| unop(code, vs[0])),
+        ByteCode::Concat(_, _, _) => fold(args, |vs| {
+            if vs.iter().all(|v| v.to_str().is_some()) {
+                let vs: Vec<Value> = vs.iter().map(|&v| v.clone()).collect();
+                Some(Value::concat(&vs))
+            } else {
+                None
+            }
+        }),
+        _ => Lattice::Bottom,
+    }
+}
+
+// fold the operation if all operands are constants
+fn fold(args: &[&Lattice], f: impl FnOnce(&[&Value]) -> Option<Value>) -> Lattice {
+    if args.iter().any(|a| matches!(a, Lattice::Bottom)) {
+        return Lattice::Bottom;
+    }
+    let vs: Option<Vec<&Value>> = args.iter().map(|a| a.value()).collect();
+    match vs {
+        Some(vs) => f(&vs).map_or(Lattice::Bottom, Lattice::Const),
+        None => Lattice::Top,
+    }
+}
+
+// Only numbers are folded, while the string coercions and errors are
+// left to runtime.
+fn arith(op: Arith, a: &Value, b: &Value) -> Option<Value> {
+    match op {
+        Arith::Add => arith_num(a, b, i64::wrapping_add, |a,b|a+b),
+        Arith::Sub => arith_num(a, b, i64::wrapping_sub, |a,b|a-b),
+        Arith::Mul => arith_num(a, b, i64::wrapping_mul, |a,b|a*b),
+        Arith::Mod | Arith::Idiv if matches!((a, b), (Value::Integer(_), Value::Integer(0))) => None,
+        Arith::Mod => arith_num(a, b, mod_int, mod_float),
+        Arith::Idiv => arith_num(a, b, idiv_int, idiv_float),
+        Arith::Div => arith_float(a, b, |a,b|a/b),
+        Arith::Pow => arith_float(a, b, |a,b|a.powf(b)),
+        Arith::BitAnd => arith_int(a, b, |a,b|a&b),
+        Arith::BitXor => arith_int(a, b, |a,b|a^b),
+        Arith::BitOr => arith_int(a, b, |a,b|a|b),
+        Arith::ShiftL => arith_int(a, b, shift_left),
+        Arith::ShiftR => arith_int(a, b, shift_right),
+    }
+}
+
+fn arith_num(a: &Value, b: &Value, arith_i: fn(i64,i64)->i64, arith_f: fn(f64,f64)->f64) -> Option<Value> {
+    match (a, b) {
+        (&Value::Integer(i1), &Value::Integer(i2)) => Some(Value::Integer(arith_i(i1, i2))),
+        (&Value::Integer(i1), &Value::Float(f2)) => Some(Value::Float(arith_f(i1 as f64, f2))),
+        (&Value::Float(f1), &Value::Float(f2)) => Some(Value::Float(arith_f(f1, f2))),
+        (&Value::Float(f1), &Value::Integer(i2)) => Some(Value::Float(arith_f(f1, i2 as f64))),
+        (_, _) => None,
+    }
+}
+
+fn arith_float(a: &Value, b: &Value, arith_f: fn(f64,f64)->f64) -> Option<Value> {
+    Some(Value::Float(arith_f(to_float(a)?, to_float(b)?)))
+}
+
+fn arith_int(a: &Value, b: &Value, arith_i: fn(i64,i64)->i64) -> Option<Value> {
+    Some(Value::Integer(arith_i(to_int(a)?, to_int(b)?)))
+}
+
+fn unop(code: &ByteCode, v: &Value) -> Option<Value> {
+    match (code, v) {
+        (ByteCode::Neg(_, _), &Value::Integer(i)) => Some(Value::Integer(i.wrapping_neg())),
+        (ByteCode::Neg(_, _), &Value::Float(f)) => Some(Value::Float(-f)),
+        (ByteCode::Not(_, _), v) => Some(Value::Boolean(!bool::from(v))),
+        (ByteCode::BitNot(_, _), v) => to_int(v).map(|i| Value::Integer(!i)),
+        (ByteCode::Len(_, _), Value::ShortStr(_, _) | Value::MidStr(_) | Value::LongStr(_)) => {
+            let s: &[u8] = v.as_ref();
+            Some(Value::Integer(s.len() as i64))
+        }
+        _ => None,
+    }
+}
+
+// Only numbers and strings are ordered, and the errors are left to
+// runtime. Return the result of comparison.
+fn compare(op: Compare, a: &Value, b: &Value) -> Option<bool> {
+    let is_str = |v: &Value| matches!(v, Value::ShortStr(_, _) | Value::MidStr(_) | Value::LongStr(_));
+    match op {
+        Compare::Equal => Some(a == b),
+        Compare::NotEq => Some(a != b),
+        _ if (is_number(a) && is_number(b)) || (is_str(a) && is_str(b)) => {
+            let cmp = a.partial_cmp(b);
+            Some(match op {
+                Compare::LesEq => matches!(cmp, Some(Ordering::Less | Ordering::Equal)),
+                Compare::GreEq => matches!(cmp, Some(Ordering::Greater | Ordering::Equal)),
+                Compare::Less => cmp == Some(Ordering::Less),
+                _ => cmp == Some(Ordering::Greater),
+            })
+        }
+        _ => None,
+    }
+}
+
+fn is_number(v: &Value) -> bool {
+    matches!(v, Value::Integer(_) | Value::Float(_))
+}
+fn to_float(v: &Value) -> Option<f64> {
+    match *v {
+        Value::Integer(i) => Some(i as f64),
+        Value::Float(f) => Some(f),
+        _ => None,
+    }
+}
+fn to_int(v: &Value) -> Option<i64> {
+    match *v {
+        Value::Integer(i) => Some(i),
+        Value::Float(f) => ftoi(f),
+        _ => None,
+    }
+}
+
+// Replace byte codes by loading the constant, if the only value they
+// write is constant; and fold the conditional jumps on constants.
+// Besides, constant operands are converted into Int or Const forms of
+// byte codes, so the registers may become dead.
+fn propagate_constants(ph: &mut Peephole, fp: &mut FuncProto) {
+    let ssa = Ssa::new(ph, fp);
+    let lats = ssa.constants(&ph.codes, fp);
+
+    for pc in 0..ph.codes.len() {
+        let code = ph.codes[pc];
+        let args: Vec<Option<&Value>> = ssa.uses[pc].iter().map(|&v| lats[v].value()).collect();
+
+        let foldable = decode_arith(&code).is_some() || matches!(code, ByteCode::Move(_, _)
+            | ByteCode::Neg(_, _) | ByteCode::Not(_, _) | ByteCode::BitNot(_, _)
+            | ByteCode::Len(_, _) | ByteCode::Concat(_, _, _));
+        if foldable {
+            let v = ssa.defs[pc][0];
+            if let Some(c) = lats[v].value() {
+                if let Some(load) = load_const(fp, ssa.values[v].reg as u8, c.clone()) {
+                    ph.codes[pc] = load;
+                    continue;
+                }
+            }
+        }
+
+        // the comparisons skip the next Jump if the result matches
+        if let Some((op, a, b, r)) = decode_compare(&code) {
+            let bv = match b {
+                Operand::Reg(_) => args[1].cloned(),
+                Operand::Int(i) => Some(Value::Integer(i as i64)),
+                Operand::Const(k) => Some(fp.constant(k as usize).clone()),
+            };
+            if let (Some(av), Some(bv)) = (args[0], &bv) {
+                if let Some(result) = compare(op, av, bv) {
+                    ph.codes[pc] = ByteCode::Jump(0);
+                    ph.targets[pc] = Some(if result == r { pc + 2 } else { pc + 1 });
+                }
+            } else if let (Operand::Reg(_), Some(bv)) = (b, bv) {
+                if let Some(b) = const_operand(fp, bv) {
+                    ph.codes[pc] = encode_compare(op, a, b, r);
+                }
+            } else if let (Operand::Reg(b), Some(av), Compare::Equal | Compare::NotEq) = (b, args[0], op) {
+                if let Some(a) = const_operand(fp, av.clone()) {
+                    ph.codes[pc] = encode_compare(op, b, a, r);
+                }
+            }
+            continue;
+        }
+
+        if let Some((op, dst, a, Operand::Reg(b))) = decode_arith(&code) {
+            let commutative = matches!(op, Arith::Add | Arith::Mul | Arith::BitAnd | Arith::BitXor | Arith::BitOr);
+            if let Some(bv) = args[1] {
+                if let Some(b) = const_operand(fp, bv.clone()) {
+                    ph.codes[pc] = encode_arith(op, dst, a, b);
+                }
+            } else if let (Some(av), true) = (args[0], commutative) {
+                if let Some(a) = const_operand(fp, av.clone()) {
+                    ph.codes[pc] = encode_arith(op, dst, b, a);
+                }
+            }
+            continue;
+        }
+
+        let (jump, next) = match (code, args.first()) {
+            (ByteCode::TestAndJump(_, _), Some(Some(c))) => (bool::from(*c), true),
+            (ByteCode::TestOrJump(_, _), Some(Some(c))) => (!bool::from(*c), true),
+            // not jump only, since it needs setting the value otherwise
+            (ByteCode::TestAndSetJump(_, _, _), Some(Some(c))) => (false, !bool::from(*c)),
+            (ByteCode::TestOrSetJump(_, _, _), Some(Some(c))) => (false, bool::from(*c)),
+            _ => (false, false),
+        };
+        if jump {
+            ph.codes[pc] = ByteCode::Jump(0);
+        } else if next {
+            ph.codes[pc] = ByteCode::Jump(0);
+            ph.targets[pc] = Some(pc + 1);
+        }
+    }
+}
+
+fn load_const(fp: &mut FuncProto, dst: u8, v: Value) -> Option<ByteCode> {
+    match v {
+        Value::Boolean(b) => Some(ByteCode::LoadBool(dst, b)),
+        Value::Integer(i) if i16::try_from(i).is_ok() => Some(ByteCode::LoadInt(dst, i as i16)),
+        // not LoadNil, which clears the registers above too
+        v => u16::try_from(fp.add_const(v)).ok().map(|k| ByteCode::LoadConst(dst, k)),
+    }
+}
+
+fn const_operand(fp: &mut FuncProto, v: Value) -> Option<Operand> {
+    match v {
+        Value::Integer(i) if u8::try_from(i).is_ok() => Some(Operand::Int(i as u8)),
+        // only the first 256 constants can be operands, so do not
+        // search in the whole large table
+        v => match fp.constants.iter().take(256).position(|c| slot_ref(c).same(&v)) {
+            Some(k) => Some(Operand::Const(k as u8)),
+            None if fp.constants.len() < 256 => Some(Operand::Const(fp.add_const(v) as u8)),
+            None => None,
+        }
+    }
+}
+
+// Remove the byte codes without side effects, whose written values
+// are never used.
+fn eliminate_dead_stores(ph: &mut Peephole, fp: &FuncProto) {
+    let ssa = Ssa::new(ph, fp);
+    let nums = ssa.numbers(&ph.codes, fp);
+
+    let mut live = vec![false; ph.codes.len()];
+    let mut pending: Vec<usize> = Vec::new();
+    for (pc, code) in ph.codes.iter().enumerate() {
+        let removable = !ph.after_skip(pc) && !skips_next(code)
+            && ssa.defs[pc].iter().all(|&v| !ssa.volatile[ssa.values[v].reg])
+            && is_pure(code, &ssa.uses[pc], &nums, fp);
+        if !removable {
+            live[pc] = true;
+            pending.extend(ssa.uses[pc].iter());
+        }
+    }
+
+    let mut live_values = vec![false; ssa.values.len()];
+    while let Some(v) = pending.pop() {
+        if live_values[v] {
+            continue;
+        }
+        live_values[v] = true;
+        match &ssa.values[v].def {
+            &Def::Code(pc) => {
+                if !live[pc] {
+                    live[pc] = true;
+                    pending.extend(ssa.uses[pc].iter());
+                }
+            }
+            Def::Phi(args) => pending.extend(args.iter()),
+            Def::Entry => (),
+        }
+    }
+    ph.compact(&live);
+}
+
+// byte codes which never fail and have no side effects
+fn is_pure(code: &ByteCode, uses: &[usize], nums: &[bool], fp: &FuncProto) -> bool {
+    match *code {
+        ByteCode::LoadConst(_, _) | ByteCode::LoadNil(_, _) | ByteCode::LoadBool(_, _)
+            | ByteCode::LoadInt(_, _) | ByteCode::Move(_, _) | ByteCode::Move2(_, _, _)
+            | ByteCode::GetUpvalue(_, _) | ByteCode::Closure(_, _) | ByteCode::NewTable(_, _, _)
+            | ByteCode::NewTableConst(_, _) | ByteCode::Not(_, _) => true,
+        ByteCode::Neg(_, _) => nums[uses[0]],
+        _ => match decode_arith(code) {
+            Some((Arith::Add | Arith::Sub | Arith::Mul | Arith::Div | Arith::Pow, _, _, b)) => {
+                uses.iter().all(|&v| nums[v]) && match b {
+                    Operand::Const(k) => is_number(&fp.constant(k as usize)),
+                    _ => true,
+                }
+            }
+            _ => false,
+        }
+    }
+}
+
+// Hoist the lookups of global variables, i.e. GetUpField on `_ENV`,
+// out of the loops, into new registers above the frame.
+//
+// The loops must not call any function, and must not write the
+// variables, including writing by SetTable with non-number keys,
+// since the table may be `_ENV` too. Besides, the byte codes that
+// truncate the stack, e.g. LoadNil, are not allowed either, since
+// they clear the new registers.
+//
+// The lookup is executed before the loop even if it is not executed
+// in the loop, which makes no difference since indexing `_ENV` has no
+// side effect.
+fn hoist_global_lookups(ph: &mut Peephole, fp: &FuncProto, env: usize) {
+    let ssa = Ssa::new(ph, fp);
+    let nums = ssa.numbers(&ph.codes, fp);
+
+    let mut nreg = ssa.nreg;
+    let mut inserts = Vec::new();
+    for (header, body) in ssa.loops() {
+        let head = ssa.blocks[header].start;
+        if head > 0 && skips_next(&ph.codes[head - 1]) {
+            continue;
+        }
+        let pcs: Vec<usize> = ssa.blocks.iter().enumerate()
+            .filter(|&(b, _)| body[b])
+            .flat_map(|(_, block)| block.start .. block.end)
+            .collect();
+
+        let mut barrier = false;
+        let mut written = Vec::new(); // keys written
+        for &pc in pcs.iter() {
+            match ph.codes[pc] {
+                ByteCode::Call(_, _, _) | ByteCode::CallField(_, _, _) | ByteCode::CallUpField(_, _, _)
+                    | ByteCode::CallSet(_, _, _) | ByteCode::TailCall(_, _)
+                    | ByteCode::ForCallLoop(_, _, _) | ByteCode::Close(_)
+                    | ByteCode::LoadNil(_, _) | ByteCode::VarArgs(_, _) | ByteCode::SetList(_, _, _) => barrier = true,
+                ByteCode::SetUpvalue(up, _) | ByteCode::SetUpvalueConst(up, _) if up as usize == env => barrier = true,
+                ByteCode::SetTable(_, _, _) | ByteCode::SetTableConst(_, _, _) if !nums[ssa.uses[pc][1]] => barrier = true,
+                ByteCode::SetField(_, k, _) | ByteCode::SetFieldConst(_, k, _)
+                    | ByteCode::SetUpField(_, k, _) | ByteCode::SetUpFieldConst(_, k, _) => written.push(k),
+                _ => (),
+            }
+        }
+        if barrier {
+            continue;
+        }
+
+        let mut loads: Vec<(u8, u8)> = Vec::new(); // (key, register)
+        for &pc in pcs.iter() {
+            let ByteCode::GetUpField(dst, up, k) = ph.codes[pc] else {
+                continue;
+            };
+            if up as usize != env || written.iter().any(|&w| fp.constants[w as usize] == fp.constants[k as usize]) {
+                continue;
+            }
+            let reg = match loads.iter().find(|&&(key, _)| key == k) {
+                Some(&(_, reg)) => reg,
+                None => {
+                    let Ok(reg) = u8::try_from(nreg) else {
+                        break;
+                    };
+                    nreg += 1;
+                    loads.push((k, reg));
+                    reg
+                }
+            };
+            ph.codes[pc] = ByteCode::Move(dst, reg);
+        }
+
+        if !loads.is_empty() {
+            let mut in_loop = vec![false; ph.codes.len()];
+            for &pc in pcs.iter() {
+                in_loop[pc] = true;
+            }
+            let loads = loads.into_iter().map(|(k, reg)| ByteCode::GetUpField(reg, env as u8, k)).collect();
+            inserts.push((head, loads, in_loop));
+        }
+    }
+    insert_before_loops(ph, inserts);
+}
+
+// Insert byte codes before the loop headers. The jumps to the header
+// from outside the loop go to the inserted byte codes, while the ones
+// from inside, e.g. ForLoop, do not.
+fn insert_before_loops(ph: &mut Peephole, inserts: Vec<(usize, Vec<ByteCode>, Vec<bool>)>) {
+    if inserts.is_empty() {
+        return;
+    }
+    let n = ph.codes.len();
+    let mut at = vec![None; n];
+    for (i, (head, _, _)) in inserts.iter().enumerate() {
+        at[*head] = Some(i);
+    }
+
+    // new positions of the inserted and the original byte codes
+    let mut entry_pc = vec![0; n];
+    let mut new_pc = vec![0; n + 1];
+    let mut count = 0;
+    for pc in 0..n {
+        entry_pc[pc] = count;
+        if let Some(i) = at[pc] {
+            count += inserts[i].1.len();
+        }
+        new_pc[pc] = count;
+        count += 1;
+    }
+    new_pc[n] = count;
+
+    let mut codes = Vec::with_capacity(count);
+    let mut targets = Vec::with_capacity(count);
+    for pc in 0..n {
+        if let Some(i) = at[pc] {
+            codes.extend(inserts[i].1.iter());
+            targets.extend(inserts[i].1.iter().map(|_| None));
+        }
+        codes.push(ph.codes[pc]);
+        targets.push(ph.targets[pc].map(|t| match at.get(t) {
+            Some(&Some(i)) if !inserts[i].2[pc] => entry_pc[t],
+            _ => new_pc[t],
+        }));
+    }
+    ph.codes = codes;
+    ph.targets = targets;
+}
+
+// byte codes with jump targets relative to the beginning
+type Seq = Vec<(ByteCode, Option<usize>)>;
+
+// max number of byte codes of functions to be inlined
+const INLINE_MAX: usize = 20;
+
+// Inline the calls to small local functions, if the functions do not
+// escape. The functions are removed as dead stores later then.
+fn inline_calls(ph: &mut Peephole, fp: &mut FuncProto) {
+    let ssa = Ssa::new(ph, fp);
+    let users = ssa.users();
+
+    let mut seqs = vec![None; ph.codes.len()];
+    for pc in 0..ph.codes.len() {
+        // functions without upvalues are loaded as constants
+        let (ByteCode::LoadConst(_, k) | ByteCode::Closure(_, k)) = ph.codes[pc] else {
+            continue;
+        };
+        let Value::LuaFunction(callee) = fp.constant(k as usize).clone() else {
+            continue;
+        };
+        if !can_inline(&callee) {
+            continue;
+        }
+        let Some(calls) = ssa.call_sites(ssa.defs[pc][0], &users, &ph.codes) else {
+            continue;
+        };
+        let captured: Vec<usize> = callee.upindexes.iter()
+            .filter_map(|up| match up {
+                &UpIndex::Local(i) => Some(i),
+                UpIndex::Upvalue(_) => None,
+            })
+            .collect();
+        for call in calls {
+            if !captured.is_empty() && captured_killed(ph, pc, call, &captured, ssa.nreg) {
+                continue;
+            }
+            // Call with want_nret==0 wants all return values if the
+            // following byte code takes them by the stack top, or it
+            // is a call statement otherwise.
+            let statement = !matches!(ph.codes.get(call + 1), Some(ByteCode::Call(_, 0, _)
+                | ByteCode::CallSet(_, _, 0) | ByteCode::TailCall(_, 0)
+                | ByteCode::Return(_, 0) | ByteCode::SetList(_, 0, _)));
+            seqs[call] = inline_call(ph.codes[call], statement, &callee, fp);
+        }
+    }
+    replace_codes(ph, seqs);
+}
+
+// The inlined callee reads the captured local variables from their
+// registers, instead of the upvalues. So it fails if any of them may be
+// closed, e.g. at the end of its block where the register is reused by
+// other locals, or overwritten, on some path from the closure creation
+// at @def to the @call.
+fn captured_killed(ph: &Peephole, def: usize, call: usize, captured: &[usize], nreg: usize) -> bool {
+    let n = ph.codes.len();
+    let mut preds = vec![Vec::new(); n];
+    for pc in 0..n {
+        for succ in ph.successors(pc) {
+            if succ < n {
+                preds[succ].push(pc);
+            }
+        }
+    }
+    // byte codes reachable from @def, and those reaching @call
+    let walk = |start: Vec<usize>, next: &dyn Fn(usize) -> Vec<usize>| {
+        let mut visited = vec![false; n];
+        let mut pending = start;
+        while let Some(pc) = pending.pop() {
+            if pc < n && !visited[pc] {
+                visited[pc] = true;
+                pending.extend(next(pc));
+            }
+        }
+        visited
+    };
+    let after_def = walk(ph.successors(def), &|pc| ph.successors(pc));
+    let before_call = walk(preds[call].clone(), &|pc| preds[pc].clone());
+
+    (0..n).filter(|&pc| after_def[pc] && before_call[pc]).any(|pc| match ph.codes[pc] {
+        ByteCode::Close(r) => captured.iter().any(|&i| i >= r as usize),
+        ref code => regs(code, nreg).1.iter().any(|r| captured.contains(r)),
+    })
+}
+
+fn can_inline(callee: &FuncProto) -> bool {
+    !callee.has_varargs && callee.byte_codes.len() <= INLINE_MAX
+        && callee.byte_codes.iter().all(|code| match code {
+            ByteCode::Closure(_, _) | ByteCode::TailCall(_, _) | ByteCode::VarArgs(_, _)
+                | ByteCode::Close(_) | ByteCode::Tbc(_)
+                | ByteCode::LoadConstX(_, _) | ByteCode::ExtraArg(_) => false,
+            // single return value only
+            ByteCode::Return(_, nret) => *nret == 1,
+            _ => true,
+        })
+}
+
+// Inline the function call @code by the byte codes of @callee, whose
+// registers are moved up onto the arguments. The returns are replaced
+// by moving the return value where the caller wants, and jumping to
+// the end. Return None if fails.
+fn inline_call(code: ByteCode, statement: bool, callee: &FuncProto, fp: &mut FuncProto)
+        -> Option<Seq> {
+
+    let (func, narg_plus, dst) = match code {
+        ByteCode::CallSet(dst, func, narg_plus) => (func, narg_plus, Some(dst)),
+        ByteCode::Call(func, narg_plus, 1) => (func, narg_plus, Some(func)),
+        ByteCode::Call(func, narg_plus, 0) if statement => (func, narg_plus, None),
+        _ => return None,
+    };
+    let base = func as usize + 1;
+    if base + frame_size(&callee.byte_codes, callee.nparam) > u8::MAX as usize + 1 {
+        return None;
+    }
+    let nil = u16::try_from(fp.add_const(Value::Nil)).ok()?;
+
+    // fill nil if #argument < #parameter
+    let mut seq = Vec::new();
+    for i in narg_plus as usize - 1 .. callee.nparam {
+        seq.push((ByteCode::LoadConst((base + i) as u8, nil), None));
+    }
+
+    // positions of the callee's byte codes in @seq
+    let body = Peephole::new(&callee.byte_codes);
+    let mut pos = Vec::with_capacity(body.codes.len() + 1);
+    let mut end = seq.len();
+    for (pc, code) in body.codes.iter().enumerate() {
+        pos.push(end);
+        end += match code {
+            ByteCode::Return(_, _) | ByteCode::Return0 if body.after_skip(pc) => return None,
+            ByteCode::Return(_, _) | ByteCode::Return0 => dst.is_some() as usize + 1,
+            _ => 1,
+        };
+    }
+    pos.push(end);
+
+    let mut inliner = Inliner { fp, callee, base };
+    for (pc, &code) in body.codes.iter().enumerate() {
+        match code {
+            ByteCode::Return(iret, _) => {
+                if let Some(dst) = dst {
+                    seq.push((ByteCode::Move(dst, inliner.reg(iret)?), None));
+                }
+                seq.push((ByteCode::Jump(0), Some(end)));
+            }
+            ByteCode::Return0 => {
+                if let Some(dst) = dst {
+                    seq.push((ByteCode::LoadConst(dst, nil), None));
+                }
+                seq.push((ByteCode::Jump(0), Some(end)));
+            }
+            _ => seq.push((inliner.relocate(code)?, body.targets[pc].map(|t| pos[t]))),
+        }
+    }
+    Some(seq)
+}
+
+// Replace the byte codes by the sequences, whose jump targets are
+// relative to the beginning of the sequence.
+fn replace_codes(ph: &mut Peephole, seqs: Vec<Option<Seq>>) {
+    if seqs.iter().all(Option::is_none) {
+        return;
+    }
+    let mut new_pc = Vec::with_capacity(seqs.len() + 1);
+    let mut count = 0;
+    for seq in seqs.iter() {
+        new_pc.push(count);
+        count += seq.as_ref().map_or(1, |seq| seq.len());
+    }
+    new_pc.push(count);
+
+    let mut codes = Vec::with_capacity(count);
+    let mut targets = Vec::with_capacity(count);
+    for (pc, seq) in seqs.into_iter().enumerate() {
+        match seq {
+            Some(seq) => {
+                for (code, target) in seq {
+                    codes.push(code);
+                    targets.push(target.map(|t| new_pc[pc] + t));
+                }
+            }
+            None => {
+                codes.push(ph.codes[pc]);
+                targets.push(ph.targets[pc].map(|t| new_pc[t]));
+            }
+        }
+    }
+    ph.codes = codes;
+    ph.targets = targets;
+}
+
+// Map the callee's byte codes into the caller. Registers are moved up
+// by @base, constants are added into the caller, and upvalues are
+// resolved in the caller, where the function is created.
+struct Inliner<'a> {
+    fp: &'a mut FuncProto,
+    callee: &'a FuncProto,
+    base: usize,
+}
+
+impl Inliner<'_> {
+    fn reg(&self, reg: u8) -> Option<u8> {
+        u8::try_from(self.base + reg as usize).ok()
+    }
+    fn k8(&mut self, k: u8) -> Option<u8> {
+        u8::try_from(self.fp.add_const(self.callee.constant(k as usize).clone())).ok()
+    }
+    fn k16(&mut self, k: u16) -> Option<u16> {
+        u16::try_from(self.fp.add_const(self.callee.constant(k as usize).clone())).ok()
+    }
+    fn operand(&mut self, b: Operand) -> Option<Operand> {
+        Some(match b {
+            Operand::Reg(b) => Operand::Reg(self.reg(b)?),
+            Operand::Int(i) => Operand::Int(i),
+            Operand::Const(k) => Operand::Const(self.k8(k)?),
+        })
+    }
+    // the caller's local variable, or upvalue
+    fn upvalue(&self, up: u8) -> Result<u8, u8> {
+        match self.callee.upindexes[up as usize] {
+            UpIndex::Local(i) => Ok(i as u8),
+            UpIndex::Upvalue(i) => Err(i as u8),
+        }
+    }
+
+    fn relocate(&mut self, code: ByteCode) -> Option<ByteCode> {
+        if let Some((op, dst, a, b)) = decode_arith(&code) {
+            return Some(encode_arith(op, self.reg(dst)?, self.reg(a)?, self.operand(b)?));
+        }
+        if let Some((op, a, b, r)) = decode_compare(&code) {
+            return Some(encode_compare(op, self.reg(a)?, self.operand(b)?, r));
+        }
+
+        Some(match code {
+            ByteCode::LoadConst(dst, k) => ByteCode::LoadConst(self.reg(dst)?, self.k16(k)?),
+            ByteCode::LoadNil(dst, n) => ByteCode::LoadNil(self.reg(dst)?, n),
+            ByteCode::LoadBool(dst, b) => ByteCode::LoadBool(self.reg(dst)?, b),
+            ByteCode::LoadInt(dst, i) => ByteCode::LoadInt(self.reg(dst)?, i),
+            ByteCode::Move(dst, src) => ByteCode::Move(self.reg(dst)?, self.reg(src)?),
+            ByteCode::Move2(dst, src1, src2) => ByteCode::Move2(self.reg(dst)?, self.reg(src1)?, self.reg(src2)?),
+
+            ByteCode::GetUpvalue(dst, up) => match self.upvalue(up) {
+                Ok(local) => ByteCode::Move(self.reg(dst)?, local),
+                Err(up) => ByteCode::GetUpvalue(self.reg(dst)?, up),
+            },
+            ByteCode::SetUpvalue(up, src) => match self.upvalue(up) {
+                Ok(local) => ByteCode::Move(local, self.reg(src)?),
+                Err(up) => ByteCode::SetUpvalue(up, self.reg(src)?),
+            },
+            ByteCode::SetUpvalueConst(up, k) => match self.upvalue(up) {
+                Ok(local) => ByteCode::LoadConst(local, self.k16(k as u16)?),
+                Err(up) => ByteCode::SetUpvalueConst(up, self.k8(k)?),
+            },
+
+            ByteCode::NewTable(dst, narray, nmap) => ByteCode::NewTable(self.reg(dst)?, narray, nmap),
+            ByteCode::NewTableConst(dst, k) => ByteCode::NewTableConst(self.reg(dst)?, self.k16(k)?),
+            ByteCode::SetTable(t, k, v) => ByteCode::SetTable(self.reg(t)?, self.reg(k)?, self.reg(v)?),
+            ByteCode::SetField(t, k, v) => ByteCode::SetField(self.reg(t)?, self.k8(k)?, self.reg(v)?),
+            ByteCode::SetInt(t, i, v) => ByteCode::SetInt(self.reg(t)?, i, self.reg(v)?),
+            ByteCode::SetTableConst(t, k, v) => ByteCode::SetTableConst(self.reg(t)?, self.reg(k)?, self.k8(v)?),
+            ByteCode::SetFieldConst(t, k, v) => ByteCode::SetFieldConst(self.reg(t)?, self.k8(k)?, self.k8(v)?),
+            ByteCode::SetIntConst(t, i, v) => ByteCode::SetIntConst(self.reg(t)?, i, self.k8(v)?),
+            ByteCode::SetList(t, n, b) => ByteCode::SetList(self.reg(t)?, n, b),
+            ByteCode::GetTable(dst, t, k) => ByteCode::GetTable(self.reg(dst)?, self.reg(t)?, self.reg(k)?),
+            ByteCode::GetField(dst, t, k) => ByteCode::GetField(self.reg(dst)?, self.reg(t)?, self.k8(k)?),
+            ByteCode::GetInt(dst, t, i) => ByteCode::GetInt(self.reg(dst)?, self.reg(t)?, i),
+            ByteCode::GetFieldSelf(dst, t, k) => ByteCode::GetFieldSelf(self.reg(dst)?, self.reg(t)?, self.k8(k)?),
+
+            ByteCode::SetUpField(up, k, v) => match self.upvalue(up) {
+                Ok(local) => ByteCode::SetField(local, self.k8(k)?, self.reg(v)?),
+                Err(up) => ByteCode::SetUpField(up, self.k8(k)?, self.reg(v)?),
+            },
+            ByteCode::SetUpFieldConst(up, k, v) => match self.upvalue(up) {
+                Ok(local) => ByteCode::SetFieldConst(local, self.k8(k)?, self.k8(v)?),
+                Err(up) => ByteCode::SetUpFieldConst(up, self.k8(k)?, self.k8(v)?),
+            },
+            ByteCode::GetUpField(dst, up, k) => match self.upvalue(up) {
+                Ok(local) => ByteCode::GetField(self.reg(dst)?, local, self.k8(k)?),
+                Err(up) => ByteCode::GetUpField(self.reg(dst)?, up, self.k8(k)?),
+            },
+
+            // the jump offsets are fixed by targets at last
+            ByteCode::Jump(_) | ByteCode::LongJump(_, _) => code,
+            ByteCode::TestAndJump(c, jmp) => ByteCode::TestAndJump(self.reg(c)?, jmp),
+            ByteCode::TestOrJump(c, jmp) => ByteCode::TestOrJump(self.reg(c)?, jmp),
+            ByteCode::TestAndSetJump(dst, c, jmp) => ByteCode::TestAndSetJump(self.reg(dst)?, self.reg(c)?, jmp),
+            ByteCode::TestOrSetJump(dst, c, jmp) => ByteCode::TestOrSetJump(self.reg(dst)?, self.reg(c)?, jmp),
+            ByteCode::ForPrepare(i, jmp) => ByteCode::ForPrepare(self.reg(i)?, jmp),
+            ByteCode::ForLoop(i, jmp) => ByteCode::ForLoop(self.reg(i)?, jmp),
+            ByteCode::ForCallLoop(i, nvar, jmp) => ByteCode::ForCallLoop(self.reg(i)?, nvar, jmp),
+
+            ByteCode::Call(func, narg_plus, want) => ByteCode::Call(self.reg(func)?, narg_plus, want),
+            ByteCode::CallField(func, t, k) => ByteCode::CallField(self.reg(func)?, self.reg(t)?, self.k8(k)?),
+            ByteCode::CallUpField(func, up, k) => match self.upvalue(up) {
+                Ok(local) => ByteCode::CallField(self.reg(func)?, local, self.k8(k)?),
+                Err(up) => ByteCode::CallUpField(self.reg(func)?, up, self.k8(k)?),
+            },
+            ByteCode::CallSet(dst, func, narg_plus) => ByteCode::CallSet(self.reg(dst)?, self.reg(func)?, narg_plus),
+
+            ByteCode::Neg(dst, src) => ByteCode::Neg(self.reg(dst)?, self.reg(src)?),
+            ByteCode::Not(dst, src) => ByteCode::Not(self.reg(dst)?, self.reg(src)?),
+            ByteCode::BitNot(dst, src) => ByteCode::BitNot(self.reg(dst)?, self.reg(src)?),
+            ByteCode::Len(dst, src) => ByteCode::Len(self.reg(dst)?, self.reg(src)?),
+
+            ByteCode::EqualJump(a, b, jmp) => ByteCode::EqualJump(self.reg(a)?, self.reg(b)?, jmp),
+            ByteCode::EqualIntJump(a, i, jmp) => ByteCode::EqualIntJump(self.reg(a)?, i, jmp),
+            ByteCode::EqualConstJump(a, k, jmp) => ByteCode::EqualConstJump(self.reg(a)?, self.k8(k)?, jmp),
+            ByteCode::LessJump(a, b, jmp) => ByteCode::LessJump(self.reg(a)?, self.reg(b)?, jmp),
+            ByteCode::LesEqJump(a, b, jmp) => ByteCode::LesEqJump(self.reg(a)?, self.reg(b)?, jmp),
+            ByteCode::LessIntJump(a, i, jmp) => ByteCode::LessIntJump(self.reg(a)?, i, jmp),
+            ByteCode::GreaterIntJump(a, i, jmp) => ByteCode::GreaterIntJump(self.reg(a)?, i, jmp),
+
+            ByteCode::SetFalseSkip(dst) => ByteCode::SetFalseSkip(self.reg(dst)?),
+            ByteCode::Concat(dst, first, n) => ByteCode::Concat(self.reg(dst)?, self.reg(first)?, n),
+
+            // not inlined, see can_inline()
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Arith { Add, Sub, Mul, Mod, Div, Idiv, Pow, BitAnd, BitXor, BitOr, ShiftL, ShiftR }
+
+#[derive(Clone, Copy, PartialEq)]
+enum Compare { Equal, NotEq, LesEq, GreEq, Less, Greater }
+
+// the second operand of arithmetic operations and comparisons
+#[derive(Clone, Copy)]
+enum Operand {
+    Reg(u8),
+    Int(u8),
+    Const(u8),
+}
+
+type FnBc3u8 = fn(u8, u8, u8) -> ByteCode;
+type FnBcBool = fn(u8, u8, bool) -> ByteCode;
+
+fn decode_arith(code: &ByteCode) -> Option<(Arith, u8, u8, Operand)> {
+    use Operand::{Reg, Int, Const};
+    Some(match *code {
+        ByteCode::Add(dst, a, b) => (Arith::Add, dst, a, Reg(b)),
+        ByteCode::AddInt(dst, a, i) => (Arith::Add, dst, a, Int(i)),
+        ByteCode::AddConst(dst, a, k) => (Arith::Add, dst, a, Const(k)),
+        ByteCode::Sub(dst, a, b) => (Arith::Sub, dst, a, Reg(b)),
+        ByteCode::SubInt(dst, a, i) => (Arith::Sub, dst, a, Int(i)),
+        ByteCode::SubConst(dst, a, k) => (Arith::Sub, dst, a, Const(k)),
+        ByteCode::Mul(dst, a, b) => (Arith::Mul, dst, a, Reg(b)),
+        ByteCode::MulInt(dst, a, i) => (Arith::Mul, dst, a, Int(i)),
+        ByteCode::MulConst(dst, a, k) => (Arith::Mul, dst, a, Const(k)),
+        ByteCode::Mod(dst, a, b) => (Arith::Mod, dst, a, Reg(b)),
+        ByteCode::ModInt(dst, a, i) => (Arith::Mod, dst, a, Int(i)),
+        ByteCode::ModConst(dst, a, k) => (Arith::Mod, dst, a, Const(k)),
+        ByteCode::Div(dst, a, b) => (Arith::Div, dst, a, Reg(b)),
+        ByteCode::DivInt(dst, a, i) => (Arith::Div, dst, a, Int(i)),
+        ByteCode::DivConst(dst, a, k) => (Arith::Div, dst, a, Const(k)),
+        ByteCode::Idiv(dst, a, b) => (Arith::Idiv, dst, a, Reg(b)),
+        ByteCode::IdivInt(dst, a, i) => (Arith::Idiv, dst, a, Int(i)),
+        ByteCode::IdivConst(dst, a, k) => (Arith::Idiv, dst, a, Const(k)),
+        ByteCode::Pow(dst, a, b) => (Arith::Pow, dst, a, Reg(b)),
+        ByteCode::PowInt(dst, a, i) => (Arith::Pow, dst, a, Int(i)),
+        ByteCode::PowConst(dst, a, k) => (Arith::Pow, dst, a, Const(k)),
+        ByteCode::BitAnd(dst, a, b) => (Arith::BitAnd, dst, a, Reg(b)),
+        ByteCode::BitAndInt(dst, a, i) => (Arith::BitAnd, dst, a, Int(i)),
+        ByteCode::BitAndConst(dst, a, k) => (Arith::BitAnd, dst, a, Const(k)),
+        ByteCode::BitXor(dst, a, b) => (Arith::BitXor, dst, a, Reg(b)),
+        ByteCode::BitXorInt(dst, a, i) => (Arith::BitXor, dst, a, Int(i)),
+        ByteCode::BitXorConst(dst, a, k) => (Arith::BitXor, dst, a, Const(k)),
+        ByteCode::BitOr(dst, a, b) => (Arith::BitOr, dst, a, Reg(b)),
+        ByteCode::BitOrInt(dst, a, i) => (Arith::BitOr, dst, a, Int(i)),
+        ByteCode::BitOrConst(dst, a, k) => (Arith::BitOr, dst, a, Const(k)),
+        ByteCode::ShiftL(dst, a, b) => (Arith::ShiftL, dst, a, Reg(b)),
+        ByteCode::ShiftLInt(dst, a, i) => (Arith::ShiftL, dst, a, Int(i)),
+        ByteCode::ShiftLConst(dst, a, k) => (Arith::ShiftL, dst, a, Const(k)),
+        ByteCode::ShiftR(dst, a, b) => (Arith::ShiftR, dst, a, Reg(b)),
+        ByteCode::ShiftRInt(dst, a, i) => (Arith::ShiftR, dst, a, Int(i)),
+        ByteCode::ShiftRConst(dst, a, k) => (Arith::ShiftR, dst, a, Const(k)),
+        _ => return None,
+    })
+}
+
+fn encode_arith(op: Arith, dst: u8, a: u8, b: Operand) -> ByteCode {
+    let (opr, opi, opk): (FnBc3u8, FnBc3u8, FnBc3u8) = match op {
+        Arith::Add => (ByteCode::Add, ByteCode::AddInt, ByteCode::AddConst),
+        Arith::Sub => (ByteCode::Sub, ByteCode::SubInt, ByteCode::SubConst),
+        Arith::Mul => (ByteCode::Mul, ByteCode::MulInt, ByteCode::MulConst),
+        Arith::Mod => (ByteCode::Mod, ByteCode::ModInt, ByteCode::ModConst),
+        Arith::Div => (ByteCode::Div, ByteCode::DivInt, ByteCode::DivConst),
+        Arith::Idiv => (ByteCode::Idiv, ByteCode::IdivInt, ByteCode::IdivConst),
+        Arith::Pow => (ByteCode::Pow, ByteCode::PowInt, ByteCode::PowConst),
+        Arith::BitAnd => (ByteCode::BitAnd, ByteCode::BitAndInt, ByteCode::BitAndConst),
+        Arith::BitXor => (ByteCode::BitXor, ByteCode::BitXorInt, ByteCode::BitXorConst),
+        Arith::BitOr => (ByteCode::BitOr, ByteCode::BitOrInt, ByteCode::BitOrConst),
+        Arith::ShiftL => (ByteCode::ShiftL, ByteCode::ShiftLInt, ByteCode::ShiftLConst),
+        Arith::ShiftR => (ByteCode::ShiftR, ByteCode::ShiftRInt, ByteCode::ShiftRConst),
+    };
+    match b {
+        Operand::Reg(b) => opr(dst, a, b),
+        Operand::Int(i) => opi(dst, a, i),
+        Operand::Const(k) => opk(dst, a, k),
+    }
+}
+
+fn decode_compare(code: &ByteCode) -> Option<(Compare, u8, Operand, bool)> {
+    use Operand::{Reg, Int, Const};
+    Some(match *code {
+        ByteCode::Equal(a, b, r) => (Compare::Equal, a, Reg(b), r),
+        ByteCode::EqualInt(a, i, r) => (Compare::Equal, a, Int(i), r),
+        ByteCode::EqualConst(a, k, r) => (Compare::Equal, a, Const(k), r),
+        ByteCode::NotEq(a, b, r) => (Compare::NotEq, a, Reg(b), r),
+        ByteCode::NotEqInt(a, i, r) => (Compare::NotEq, a, Int(i), r),
+        ByteCode::NotEqConst(a, k, r) => (Compare::NotEq, a, Const(k), r),
+        ByteCode::LesEq(a, b, r) => (Compare::LesEq, a, Reg(b), r),
+        ByteCode::LesEqInt(a, i, r) => (Compare::LesEq, a, Int(i), r),
+        ByteCode::LesEqConst(a, k, r) => (Compare::LesEq, a, Const(k), r),
+        ByteCode::GreEq(a, b, r) => (Compare::GreEq, a, Reg(b), r),
+        ByteCode::GreEqInt(a, i, r) => (Compare::GreEq, a, Int(i), r),
+        ByteCode::GreEqConst(a, k, r) => (Compare::GreEq, a, Const(k), r),
+        ByteCode::Less(a, b, r) => (Compare::Less, a, Reg(b), r),
+        ByteCode::LessInt(a, i, r) => (Compare::Less, a, Int(i), r),
+        ByteCode::LessConst(a, k, r) => (Compare::Less, a, Const(k), r),
+        ByteCode::Greater(a, b, r) => (Compare::Greater, a, Reg(b), r),
+        ByteCode::GreaterInt(a, i, r) => (Compare::Greater, a, Int(i), r),
+        ByteCode::GreaterConst(a, k, r) => (Compare::Greater, a, Const(k), r),
+        _ => return None,
+    })
+}
+
+fn encode_compare(op: Compare, a: u8, b: Operand, r: bool) -> ByteCode {
+    let (opr, opi, opk): (FnBcBool, FnBcBool, FnBcBool) = match op {
+        Compare::Equal => (ByteCode::Equal, ByteCode::EqualInt, ByteCode::EqualConst),
+        Compare::NotEq => (ByteCode::NotEq, ByteCode::NotEqInt, ByteCode::NotEqConst),
+        Compare::LesEq => (ByteCode::LesEq, ByteCode::LesEqInt, ByteCode::LesEqConst),
+        Compare::GreEq => (ByteCode::GreEq, ByteCode::GreEqInt, ByteCode::GreEqConst),
+        Compare::Less => (ByteCode::Less, ByteCode::LessInt, ByteCode::LessConst),
+        Compare::Greater => (ByteCode::Greater, ByteCode::GreaterInt, ByteCode::GreaterConst),
+    };
+    match b {
+        Operand::Reg(b) => opr(a, b, r),
+        Operand::Int(i) => opi(a, i, r),
+        Operand::Const(k) => opk(a, k, r),
+    }
+}
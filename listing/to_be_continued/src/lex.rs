@@ -1,6 +1,8 @@
 use std::mem;
 use std::io::{Read, Bytes};
 use std::iter::Peekable;
+use crate::value::Value;
+use crate::utils::{str_to_number, utf8_encode};
 
 #[derive(Debug, PartialEq)]
 pub enum Token {
@@ -81,7 +83,13 @@ impl<R: Read> Lex<R> {
                 b')' => Token::ParR,
                 b'{' => Token::CurlyL,
                 b'}' => Token::CurlyR,
-                b'[' => Token::SqurL,
+                b'[' => {
+                    match self.long_bracket() {
+                        Ok(level) => Token::String(self.read_long_string(level, "string")),
+                        Err(0) => Token::SqurL,
+                        Err(_) => panic!("invalid long string delimiter"),
+                    }
+                }
                 b']' => Token::SqurR,
                 b';' => Token::SemiColon,
                 b',' => Token::Comma,
@@ -102,7 +110,7 @@ impl<R: Read> Lex<R> {
                             Token::Concat
                         }
                     }
-                    b'0'..=b'9' => self.read_decimal('.'),
+                    b'0'..=b'9' => self.read_number(b'.'),
                     _ => Token::Dot,
                 }
                 b'-' => {
@@ -114,7 +122,7 @@ impl<R: Read> Lex<R> {
                         Token::Sub
                     }
                 }
-                ch@b'0'..=b'9' => self.read_decimal(ch as char),
+                b'0'..=b'9' => self.read_number(byt),
                 b'A'..=b'Z' | b'a'..=b'z' | b'_' => self.read_name(byt),
                 _ => panic!("invalid char {byt}"),
             }
@@ -155,28 +163,38 @@ impl<R: Read> Lex<R> {
         }
     }
 
-    fn read_decimal(&mut self, ahead: char) -> Token {
-        // TODO heximal
-        let mut is_float = ahead == '.';
-        let mut buf = String::new();
-        buf.push(ahead);
+    // Read a numeral, as the official implementation does: read all
+    // digits, dots, and exponent marks with signs, and then convert.
+    fn read_number(&mut self, first: u8) -> Token {
+        let mut buf = vec![first];
+        let mut expo = b"Ee";
+        if first == b'0' && matches!(self.peek_byte(), b'x' | b'X') {
+            buf.push(self.next_byte().unwrap());
+            expo = b"Pp";
+        }
         loop {
             let byt = self.peek_byte();
-            match byt {
-                b'0' ..= b'9' => buf.push(byt as char),
-                b'.' | b'e' | b'E' | b'+' | b'-' => {
-                    buf.push(byt as char);
-                    is_float = true;
+            if expo.contains(&byt) { // exponent mark, with optional sign
+                buf.push(self.next_byte().unwrap());
+                if matches!(self.peek_byte(), b'+' | b'-') {
+                    buf.push(self.next_byte().unwrap());
                 }
-                _ => break,
+            } else if byt.is_ascii_hexdigit() || byt == b'.' {
+                buf.push(self.next_byte().unwrap());
+            } else {
+                break;
             }
-            self.next_byte();
         }
 
-        if is_float {
-            Token::Float(buf.parse::<f64>().unwrap())
-        } else {
-            Token::Integer(buf.parse::<i64>().unwrap())
+        // numeral touching a letter, e.g. "3x", is malformed
+        if self.peek_byte().is_ascii_alphabetic() || self.peek_byte() == b'_' {
+            buf.push(self.next_byte().unwrap());
+        }
+
+        match str_to_number(&buf) {
+            Some(Value::Integer(i)) => Token::Integer(i),
+            Some(Value::Float(f)) => Token::Float(f),
+            _ => panic!("malformed number near '{}'", String::from_utf8_lossy(&buf)),
         }
     }
 
@@ -184,44 +202,83 @@ impl<R: Read> Lex<R> {
         let mut s = Vec::new();
         loop {
             match self.next_byte().expect("unfinished string") {
-                b'\n' => panic!("unfinished string"),
-                b'\\' => s.push(self.read_escape()),
+                b'\n' | b'\r' => panic!("unfinished string"),
+                b'\\' => self.read_escape(&mut s),
                 byt if byt == quote => break,
                 byt => s.push(byt),
             }
         }
         Token::String(s)
     }
-    fn read_escape(&mut self) -> u8 {
-        match self.next_byte().expect("string escape") {
-            b'a' => 0x07,
-            b'b' => 0x08,
-            b'f' => 0x0c,
-            b'v' => 0x0b,
-            b'n' => b'\n',
-            b'r' => b'\r',
-            b't' => b'\t',
-            b'\\' => b'\\',
-            b'"' => b'"',
-            b'\'' => b'\'',
-            b'x' => { // format: \xXX
-                let n1 = char::to_digit(self.next_byte().unwrap() as char, 16).unwrap();
-                let n2 = char::to_digit(self.next_byte().unwrap() as char, 16).unwrap();
-                (n1 * 16 + n2) as u8
+    fn read_escape(&mut self, s: &mut Vec<u8>) {
+        let byt = self.next_byte().expect("unfinished string");
+        match byt {
+            b'a' => s.push(0x07),
+            b'b' => s.push(0x08),
+            b'f' => s.push(0x0c),
+            b'v' => s.push(0x0b),
+            b'n' => s.push(b'\n'),
+            b'r' => s.push(b'\r'),
+            b't' => s.push(b'\t'),
+            b'\\' => s.push(b'\\'),
+            b'"' => s.push(b'"'),
+            b'\'' => s.push(b'\''),
+            b'\n' | b'\r' => { // line continuation, "\r\n" or "\n\r" as one
+                let ahead = self.peek_byte();
+                if (ahead == b'\n' || ahead == b'\r') && ahead != byt {
+                    self.next_byte();
+                }
+                s.push(b'\n');
             }
-            ch@b'0'..=b'9' => { // format: \d[d[d]]
-                let mut n = char::to_digit(ch as char, 10).unwrap(); // TODO no unwrap
-                if let Some(d) = char::to_digit(self.peek_byte() as char, 10) {
+            b'z' => { // skip the following white spaces, including newlines
+                while matches!(self.peek_byte(), b' ' | b'\t' | b'\n' | b'\r' | 0x0b | 0x0c) {
                     self.next_byte();
-                    n = n * 10 + d;
-                    if let Some(d) = char::to_digit(self.peek_byte() as char, 10) {
-                        self.next_byte();
-                        n = n * 10 + d;
+                }
+            }
+            b'x' => { // format: \xXX
+                let n1 = self.read_hex_digit("\\x");
+                let n2 = self.read_hex_digit("\\x");
+                s.push((n1 * 16 + n2) as u8);
+            }
+            b'u' => { // format: \u{XXX}
+                if self.next_byte() != Some(b'{') {
+                    panic!("missing '{{' in \\u{{xxxx}}");
+                }
+                let mut n = self.read_hex_digit("\\u");
+                while self.peek_byte().is_ascii_hexdigit() {
+                    if n > 0x7FFFFFF { // check before shifting, to avoid overflow
+                        panic!("UTF-8 value too large");
                     }
+                    n = n * 16 + self.read_hex_digit("\\u");
+                }
+                if self.next_byte() != Some(b'}') {
+                    panic!("missing '}}' in \\u{{xxxx}}");
+                }
+                s.extend(utf8_encode(n));
+            }
+            b'0'..=b'9' => { // format: \d[d[d]]
+                let mut n = (byt - b'0') as u32;
+                for _ in 0..2 {
+                    match self.peek_byte() {
+                        d@b'0'..=b'9' => {
+                            self.next_byte();
+                            n = n * 10 + (d - b'0') as u32;
+                        }
+                        _ => break,
+                    }
+                }
+                if n > 255 {
+                    panic!("decimal escape too large");
                 }
-                u8::try_from(n).expect("decimal escape too large")
+                s.push(n as u8);
             }
-            _ => panic!("invalid string escape")
+            _ => panic!("invalid escape sequence '\\{}'", byt.escape_ascii()),
+        }
+    }
+    fn read_hex_digit(&mut self, what: &str) -> u32 {
+        match self.next_byte().and_then(|b| (b as char).to_digit(16)) {
+            Some(d) => d,
+            None => panic!("hexadecimal digit expected in '{what}'"),
         }
     }
 
@@ -266,17 +323,100 @@ impl<R: Read> Lex<R> {
         }
     }
 
-    // '--' has been read
-    fn read_comment(&mut self) {
-        match self.next_byte() {
-            None => (),
-            Some(b'[') => todo!("long comment"),
-            Some(_) => { // line comment
-                while let Some(byt) = self.next_byte() {
-                    if byt == b'\n' {
-                        break;
+    // '[' has been read. Read the following `=`s and the second '['.
+    // Return the level (the number of `=`s) if it is a long bracket,
+    // or Err(the-number-of-`=`s) otherwise.
+    fn long_bracket(&mut self) -> Result<usize, usize> {
+        let mut level = 0;
+        while self.peek_byte() == b'=' {
+            self.next_byte();
+            level += 1;
+        }
+        if self.peek_byte() == b'[' {
+            self.next_byte();
+            Ok(level)
+        } else {
+            Err(level)
+        }
+    }
+
+    // The opening long bracket has been read. Read until the closing
+    // long bracket of the same level. The first newline is skipped,
+    // and all kinds of newline ("\r\n", "\n\r", "\r") turn to "\n".
+    // @what is "string" or "comment", used in error message.
+    fn read_long_string(&mut self, level: usize, what: &str) -> Vec<u8> {
+        let mut s = Vec::new();
+        if matches!(self.peek_byte(), b'\n' | b'\r') {
+            self.read_newline();
+        }
+        loop {
+            match self.peek_byte() {
+                b'\0' if self.input.peek().is_none() => panic!("unfinished long {what}"),
+                b'\n' | b'\r' => {
+                    self.read_newline();
+                    s.push(b'\n');
+                }
+                b']' => {
+                    self.next_byte();
+                    match self.long_bracket_close() {
+                        Ok(n) if n == level => {
+                            self.next_byte();
+                            break;
+                        }
+                        Ok(n) | Err(n) => {
+                            // not matched, so save them as content
+                            s.push(b']');
+                            s.resize(s.len() + n, b'=');
+                        }
                     }
                 }
+                byt => {
+                    self.next_byte();
+                    s.push(byt);
+                }
+            }
+        }
+        s
+    }
+
+    // same with long_bracket() but for closing bracket, and the second
+    // ']' is read only if the level matches
+    fn long_bracket_close(&mut self) -> Result<usize, usize> {
+        let mut level = 0;
+        while self.peek_byte() == b'=' {
+            self.next_byte();
+            level += 1;
+        }
+        if self.peek_byte() == b']' {
+            Ok(level)
+        } else {
+            Err(level)
+        }
+    }
+
+    // skip a newline, "\n", "\r", "\n\r" or "\r\n"
+    fn read_newline(&mut self) {
+        let byt = self.next_byte().unwrap();
+        let ahead = self.peek_byte();
+        if (ahead == b'\n' || ahead == b'\r') && ahead != byt {
+            self.next_byte();
+        }
+    }
+
+    // '--' has been read
+    fn read_comment(&mut self) {
+        if self.peek_byte() == b'[' {
+            self.next_byte();
+            if let Ok(level) = self.long_bracket() {
+                self.read_long_string(level, "comment");
+                return;
+            }
+        }
+
+        // line comment
+        while let Some(byt) = self.next_byte() {
+            if byt == b'\n' {
+                break;
             }
         }
     }
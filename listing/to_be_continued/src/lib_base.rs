@@ -0,0 +1,305 @@
+use std::fs;
+use std::io::{self, Read};
+use std::rc::Rc;
+use std::cell::RefCell;
+use crate::value::{Value, Table};
+use crate::vm::{ExeState, LuaError};
+
+pub fn open(env: &mut Table) {
+    env.map.insert("load".into(), Value::RustFunction(lib_load));
+    env.map.insert("loadfile".into(), Value::RustFunction(lib_loadfile));
+    env.map.insert("dofile".into(), Value::RustFunction(lib_dofile));
+    env.map.insert("tonumber".into(), Value::RustFunction(lib_tonumber));
+    env.map.insert("tostring".into(), Value::RustFunction(lib_tostring));
+    env.map.insert("next".into(), Value::RustFunction(lib_next));
+    env.map.insert("pairs".into(), Value::RustFunction(lib_pairs));
+    env.map.insert("getmetatable".into(), Value::RustFunction(lib_getmetatable));
+    env.map.insert("setmetatable".into(), Value::RustFunction(lib_setmetatable));
+}
+
+fn is_string(v: &Value) -> bool {
+    matches!(v, Value::ShortStr(_, _) | Value::MidStr(_) | Value::LongStr(_))
+}
+
+// binary chunks are not supported, but the mode is checked still
+fn check_mode(code: &[u8], mode: &[u8], chunkname: &str) -> Result<(), String> {
+    let (kind, need) = if code.first() == Some(&0x1b) { ("binary", b'b') } else { ("text", b't') };
+    if !mode.contains(&need) {
+        let mode = String::from_utf8_lossy(mode);
+        return Err(format!("attempt to load a {kind} chunk (mode is '{mode}')"));
+    }
+    if need == b'b' {
+        return Err(format!("{chunkname}: binary chunks are not supported"));
+    }
+    Ok(())
+}
+
+fn load_chunk(state: &ExeState, code: &[u8], chunkname: &str, mode: &[u8], env: Option<Value>)
+        -> Result<Value, String> {
+    check_mode(code, mode, chunkname)?;
+    state.load(code, chunkname, env)
+}
+
+// Load the file, or the standard input if @filename is None. The
+// first line is skipped if it starts with '#', e.g. "#!/usr/bin/lua".
+pub fn load_file(state: &ExeState, filename: Option<&str>, mode: &[u8], env: Option<Value>)
+        -> Result<Value, String> {
+    let (code, chunkname) = match filename {
+        Some(filename) => {
+            let code = fs::read(filename).map_err(|e|
+                format!("cannot open {filename}: {}", io_error_msg(&e)))?;
+            (code, format!("@{filename}"))
+        }
+        None => {
+            let mut code = Vec::new();
+            io::stdin().read_to_end(&mut code).map_err(|e|
+                format!("cannot read stdin: {}", io_error_msg(&e)))?;
+            (code, String::from("=stdin"))
+        }
+    };
+
+    let code = if code.first() == Some(&b'#') {
+        // keep the newline, for line numbers
+        let nl = code.iter().position(|&b| b == b'\n').unwrap_or(code.len());
+        &code[nl..]
+    } else {
+        &code[..]
+    };
+    load_chunk(state, code, &chunkname, mode, env)
+}
+
+// strip the " (os error N)" suffix of io::Error
+fn io_error_msg(e: &io::Error) -> String {
+    let msg = e.to_string();
+    match msg.find(" (os error") {
+        Some(i) => msg[..i].to_string(),
+        None => msg,
+    }
+}
+
+// push the chunk as function, or nil plus the message
+fn push_load_result(state: &mut ExeState, result: Result<Value, String>) -> Result<i32, LuaError> {
+    match result {
+        Ok(f) => {
+            state.push(f);
+            Ok(1)
+        }
+        Err(msg) => {
+            state.push(());
+            state.push(msg);
+            Ok(2)
+        }
+    }
+}
+
+fn opt_string(state: &ExeState, i: usize, default: &[u8]) -> Vec<u8> {
+    match &*state.arg(i) {
+        Value::Nil => default.to_vec(),
+        _ => state.check_string(i).to_vec(),
+    }
+}
+
+// `env` is set as `_ENV` upvalue if present, even if nil
+fn opt_env(state: &ExeState, i: usize) -> Option<Value> {
+    if state.get_top() >= i { Some(state.arg(i).clone()) } else { None }
+}
+
+// load(chunk [, chunkname [, mode [, env]]])
+fn lib_load(state: &mut ExeState) -> Result<i32, LuaError> {
+    let mode = opt_string(state, 3, b"bt");
+    let env = opt_env(state, 4);
+
+    let chunk = state.arg(1).clone();
+    let (code, default_name) = if is_string(&chunk) {
+        let code: &[u8] = chunk.as_ref();
+        (code.to_vec(), code.to_vec())
+    } else if matches!(chunk, Value::RustFunction(_) | Value::RustClosure(_)
+            | Value::LuaFunction(_) | Value::LuaClosure(_)) {
+        // call the reader function repeatedly to get pieces of code,
+        // until it returns nil or empty string
+        let mut code = Vec::new();
+        loop {
+            match state.call(chunk.clone(), &[])?.into_iter().next() {
+                None | Some(Value::Nil) => break,
+                Some(piece) if is_string(&piece) => {
+                    let piece: &[u8] = piece.as_ref();
+                    if piece.is_empty() {
+                        break;
+                    }
+                    code.extend_from_slice(piece);
+                }
+                Some(_) => {
+                    let msg = String::from("reader function must return a string");
+                    return push_load_result(state, Err(msg));
+                }
+            }
+        }
+        (code, b"=(load)".to_vec())
+    } else {
+        panic!("bad argument #1 to 'load' (string expected, got {})", chunk.ty());
+    };
+
+    let chunkname = opt_string(state, 2, &default_name);
+    let chunkname = String::from_utf8_lossy(&chunkname);
+    let result = load_chunk(state, &code, &chunkname, &mode, env);
+    push_load_result(state, result)
+}
+
+// loadfile([filename [, mode [, env]]])
+fn lib_loadfile(state: &mut ExeState) -> Result<i32, LuaError> {
+    let filename = match &*state.arg(1) {
+        Value::Nil => None,
+        _ => Some(String::from_utf8_lossy(state.check_string(1)).into_owned()),
+    };
+    let mode = opt_string(state, 2, b"bt");
+    let env = opt_env(state, 3);
+
+    let result = load_file(state, filename.as_deref(), &mode, env);
+    push_load_result(state, result)
+}
+
+// dofile([filename])
+fn lib_dofile(state: &mut ExeState) -> Result<i32, LuaError> {
+    let filename = match &*state.arg(1) {
+        Value::Nil => None,
+        _ => Some(String::from_utf8_lossy(state.check_string(1)).into_owned()),
+    };
+    let f = load_file(state, filename.as_deref(), b"bt", None)
+        .unwrap_or_else(|msg| panic!("{msg}"));
+
+    let rets = state.call(f, &[])?;
+    let n = rets.len() as i32;
+    for v in rets {
+        state.push(v);
+    }
+    Ok(n)
+}
+
+// tonumber(e [, base])
+fn lib_tonumber(state: &mut ExeState) -> Result<i32, LuaError> {
+    if state.get_top() < 1 {
+        panic!("bad argument #1 to 'tonumber' (value expected)");
+    }
+    let n = if *state.arg(2) == Value::Nil {
+        state.arg(1).to_number()
+    } else {
+        let base = state.check_integer(2);
+        let s = state.check_string(1);
+        if !(2..=36).contains(&base) {
+            panic!("bad argument #2 to 'tonumber' (base out of range)");
+        }
+        str_to_int_base(s, base as u32).map(Value::Integer)
+    };
+    state.push(n.unwrap_or(Value::Nil));
+    Ok(1)
+}
+
+// integer in @base with optional spaces and minus sign, which wraps
+// around on overflow as Lua does
+fn str_to_int_base(s: &[u8], base: u32) -> Option<i64> {
+    let s = s.trim_ascii();
+    let (neg, digits) = match s.strip_prefix(b"-") {
+        Some(digits) => (true, digits),
+        None => (false, s),
+    };
+    if digits.is_empty() {
+        return None;
+    }
+    let mut n: i64 = 0;
+    for &b in digits {
+        let d = (b as char).to_digit(base)?;
+        n = n.wrapping_mul(base as i64).wrapping_add(d as i64);
+    }
+    Some(if neg { n.wrapping_neg() } else { n })
+}
+
+// tostring(v)
+fn lib_tostring(state: &mut ExeState) -> Result<i32, LuaError> {
+    if state.get_top() < 1 {
+        panic!("bad argument #1 to 'tostring' (value expected)");
+    }
+    let s = state.arg(1).to_string();
+    state.push(s);
+    Ok(1)
+}
+
+fn check_table(state: &ExeState, i: usize, fname: &str) -> Rc<RefCell<Table>> {
+    match &*state.arg(i) {
+        Value::Table(t) => t.clone(),
+        v => panic!("bad argument #{i} to '{fname}' (table expected, got {})", v.ty()),
+    }
+}
+
+// next(table [, key])
+fn lib_next(state: &mut ExeState) -> Result<i32, LuaError> {
+    let t = check_table(state, 1, "next");
+    let next = t.borrow().next(&state.arg(2));
+    match next {
+        Some((k, v)) => {
+            state.push(k);
+            state.push(v);
+            Ok(2)
+        }
+        None => {
+            state.push(());
+            Ok(1)
+        }
+    }
+}
+
+// pairs(t)
+// return `next, t, nil`, or the first 3 results of `__pairs(t)`
+fn lib_pairs(state: &mut ExeState) -> Result<i32, LuaError> {
+    let v = state.arg(1).clone();
+    let meta_pairs = match v.metatable() {
+        Some(mt) => mt.borrow().index(&"__pairs".into()).clone(),
+        None => Value::Nil,
+    };
+    if meta_pairs != Value::Nil {
+        let mut rets = state.call(meta_pairs, &[v])?.into_iter();
+        for _ in 0..3 {
+            state.push(rets.next().unwrap_or(Value::Nil));
+        }
+    } else {
+        check_table(state, 1, "pairs");
+        state.push(Value::RustFunction(lib_next));
+        state.push(v);
+        state.push(());
+    }
+    Ok(3)
+}
+
+// getmetatable(object)
+fn lib_getmetatable(state: &mut ExeState) -> Result<i32, LuaError> {
+    match state.arg(1).metatable() {
+        Some(mt) => {
+            // the `__metatable` field hides the real metatable
+            let protected = mt.borrow().index(&"__metatable".into()).clone();
+            if protected != Value::Nil {
+                state.push(protected);
+            } else {
+                state.push(Value::Table(mt));
+            }
+        }
+        None => state.push(()),
+    }
+    Ok(1)
+}
+
+// setmetatable(table, metatable)
+fn lib_setmetatable(state: &mut ExeState) -> Result<i32, LuaError> {
+    let t = check_table(state, 1, "setmetatable");
+    let mt = match &*state.arg(2) {
+        Value::Nil => None,
+        Value::Table(mt) => Some(mt.clone()),
+        _ => panic!("bad argument #2 to 'setmetatable' (nil or table expected)"),
+    };
+    if let Some(old) = &t.borrow().metatable {
+        if *old.borrow().index(&"__metatable".into()) != Value::Nil {
+            panic!("cannot change a protected metatable");
+        }
+    }
+    t.borrow_mut().metatable = mt;
+    state.push(Value::Table(t));
+    Ok(1)
+}
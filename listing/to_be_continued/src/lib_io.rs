@@ -0,0 +1,678 @@
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write, Seek, SeekFrom};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use crate::value::{Value, Table, UserData};
+use crate::vm::{ExeState, LuaError};
+use crate::utils::str_to_number;
+
+// There is no type in Rust like C's `FILE` which covers standard
+// input/output and files in all modes. So we make one. It wraps the
+// underlying stream, and keeps the read and write buffers by itself,
+// so the position is consistent when switching between reading,
+// writing and seeking on the same file.
+pub struct LuaFile {
+    stream: Option<Stream>, // None if closed
+    rbuf: Vec<u8>, // read ahead
+    rpos: usize,
+    wbuf: Vec<u8>,
+    vbuf: BufMode,
+}
+
+enum Stream {
+    Stdin(io::Stdin),
+    Stdout(io::Stdout),
+    Stderr(io::Stderr),
+    File(File),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum BufMode {
+    No,
+    Full(usize),
+    Line(usize),
+}
+
+const BUF_SIZE: usize = 4096;
+
+fn ebadf() -> io::Error {
+    io::Error::from_raw_os_error(9) // EBADF: Bad file descriptor
+}
+
+impl LuaFile {
+    fn new(stream: Stream) -> Self {
+        let vbuf = match stream {
+            // Rust's Stdout is line buffered already, and keep
+            // the output order with `print()`
+            Stream::Stdout(_) | Stream::Stderr(_) => BufMode::No,
+            _ => BufMode::Full(BUF_SIZE),
+        };
+        LuaFile {
+            stream: Some(stream),
+            rbuf: Vec::new(),
+            rpos: 0,
+            wbuf: Vec::new(),
+            vbuf,
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.stream.is_none()
+    }
+
+    fn stream(&mut self) -> io::Result<&mut Stream> {
+        self.stream.as_mut().ok_or_else(ebadf)
+    }
+
+    // make sure there are bytes in read buffer, return false if EOF
+    fn fill(&mut self) -> io::Result<bool> {
+        if self.rpos < self.rbuf.len() {
+            return Ok(true);
+        }
+        self.flush()?;
+
+        self.rbuf.resize(BUF_SIZE, 0);
+        self.rpos = 0;
+        let n = match self.stream.as_mut() {
+            Some(Stream::Stdin(s)) => s.read(&mut self.rbuf),
+            Some(Stream::File(f)) => f.read(&mut self.rbuf),
+            _ => Err(ebadf()),
+        };
+        self.rbuf.truncate(*n.as_ref().unwrap_or(&0));
+        Ok(n? > 0)
+    }
+
+    fn peek_byte(&mut self) -> io::Result<Option<u8>> {
+        Ok(if self.fill()? { Some(self.rbuf[self.rpos]) } else { None })
+    }
+    fn next_byte(&mut self) -> io::Result<Option<u8>> {
+        let byt = self.peek_byte()?;
+        if byt.is_some() {
+            self.rpos += 1;
+        }
+        Ok(byt)
+    }
+
+    // return None if EOF and nothing read
+    fn read_line(&mut self, keep_newline: bool) -> io::Result<Option<Vec<u8>>> {
+        let mut line = Vec::new();
+        while self.fill()? {
+            let buf = &self.rbuf[self.rpos..];
+            if let Some(i) = buf.iter().position(|&b| b == b'\n') {
+                let end = if keep_newline { i + 1 } else { i };
+                line.extend_from_slice(&buf[..end]);
+                self.rpos += i + 1;
+                return Ok(Some(line));
+            }
+            line.extend_from_slice(buf);
+            self.rpos = self.rbuf.len();
+        }
+        Ok(if line.is_empty() { None } else { Some(line) })
+    }
+
+    fn read_all(&mut self) -> io::Result<Vec<u8>> {
+        let mut all = Vec::new();
+        while self.fill()? {
+            all.extend_from_slice(&self.rbuf[self.rpos..]);
+            self.rpos = self.rbuf.len();
+        }
+        Ok(all)
+    }
+
+    // return None if EOF and nothing read, even if @n==0
+    fn read_count(&mut self, n: usize) -> io::Result<Option<Vec<u8>>> {
+        let mut buf = Vec::new();
+        if !self.fill()? {
+            return Ok(None);
+        }
+        while buf.len() < n && self.fill()? {
+            let want = (n - buf.len()).min(self.rbuf.len() - self.rpos);
+            buf.extend_from_slice(&self.rbuf[self.rpos .. self.rpos + want]);
+            self.rpos += want;
+        }
+        Ok(Some(buf))
+    }
+
+    // read a numeral, following the rules of official implement:
+    // read as many as possible chars which may form a numeral, then
+    // check if it's a valid one.
+    fn read_number(&mut self) -> io::Result<Option<Value>> {
+        const MAX_LEN: usize = 200;
+        let mut buf = Vec::new();
+
+        // skip whitespaces
+        while let Some(byt) = self.peek_byte()? {
+            if !byt.is_ascii_whitespace() {
+                break;
+            }
+            self.next_byte()?;
+        }
+
+        let accept = |f: &mut Self, buf: &mut Vec<u8>, set: &[u8]| -> io::Result<bool> {
+            match f.peek_byte()? {
+                Some(byt) if buf.len() < MAX_LEN && set.contains(&byt) => {
+                    buf.push(byt);
+                    f.next_byte()?;
+                    Ok(true)
+                }
+                _ => Ok(false),
+            }
+        };
+        let read_digits = |f: &mut Self, buf: &mut Vec<u8>, hex: bool| -> io::Result<usize> {
+            let mut n = 0;
+            while let Some(byt) = f.peek_byte()? {
+                if buf.len() >= MAX_LEN || !(byt.is_ascii_digit() || hex && byt.is_ascii_hexdigit()) {
+                    break;
+                }
+                buf.push(byt);
+                f.next_byte()?;
+                n += 1;
+            }
+            Ok(n)
+        };
+
+        accept(self, &mut buf, b"+-")?;
+        let mut count = 0;
+        let mut hex = false;
+        if accept(self, &mut buf, b"0")? {
+            count = 1;
+            hex = accept(self, &mut buf, b"xX")?;
+            if hex {
+                count = 0;
+            }
+        }
+        count += read_digits(self, &mut buf, hex)?;
+        if accept(self, &mut buf, b".")? {
+            count += read_digits(self, &mut buf, hex)?;
+        }
+        if count > 0 && accept(self, &mut buf, if hex { b"pP" } else { b"eE" })? {
+            accept(self, &mut buf, b"+-")?;
+            read_digits(self, &mut buf, false)?;
+        }
+
+        Ok(str_to_number(&buf))
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.drop_read_ahead()?;
+        match self.vbuf {
+            BufMode::No => return self.write_stream(buf),
+            BufMode::Full(size) => {
+                self.wbuf.extend_from_slice(buf);
+                if self.wbuf.len() >= size {
+                    self.flush()?;
+                }
+            }
+            BufMode::Line(size) => {
+                self.wbuf.extend_from_slice(buf);
+                if self.wbuf.len() >= size || buf.contains(&b'\n') {
+                    self.flush()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_stream(&mut self, buf: &[u8]) -> io::Result<()> {
+        match self.stream()? {
+            Stream::Stdout(s) => s.write_all(buf),
+            Stream::Stderr(s) => s.write_all(buf),
+            Stream::File(f) => f.write_all(buf),
+            Stream::Stdin(_) => Err(ebadf()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.wbuf.is_empty() {
+            let buf = std::mem::take(&mut self.wbuf);
+            self.write_stream(&buf)?;
+        }
+        match self.stream()? {
+            Stream::Stdout(s) => s.flush(),
+            Stream::Stderr(s) => s.flush(),
+            Stream::File(f) => f.flush(),
+            Stream::Stdin(_) => Ok(()),
+        }
+    }
+
+    // move the file position back to the logical one, which is
+    // behind the read-ahead bytes
+    fn drop_read_ahead(&mut self) -> io::Result<()> {
+        let unread = self.rbuf.len() - self.rpos;
+        self.rbuf.clear();
+        self.rpos = 0;
+        if let (Stream::File(f), true) = (self.stream()?, unread > 0) {
+            f.seek(SeekFrom::Current(-(unread as i64)))?;
+        }
+        Ok(())
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.flush()?;
+        self.drop_read_ahead()?;
+        match self.stream()? {
+            Stream::File(f) => f.seek(pos),
+            _ => Err(io::Error::from_raw_os_error(29)), // ESPIPE: Illegal seek
+        }
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        if matches!(self.stream, Some(Stream::File(_))) {
+            let r = self.flush();
+            self.stream = None; // drop the File to close it
+            r
+        } else {
+            Err(io::Error::other("cannot close standard file"))
+        }
+    }
+}
+
+// close on collection
+impl Drop for LuaFile {
+    fn drop(&mut self) {
+        if self.stream.is_some() {
+            let _ = self.flush();
+        }
+    }
+}
+
+// state shared by io library functions
+struct IoState {
+    metatable: Rc<RefCell<Table>>,
+    input: Value,
+    output: Value,
+}
+
+impl IoState {
+    fn new_file(&self, file: LuaFile) -> Value {
+        UserData::new_value(file, Some(self.metatable.clone()))
+    }
+
+    fn open_file(&self, name: &str, mode: &str) -> io::Result<Value> {
+        let mut opts = OpenOptions::new();
+        let mode = mode.strip_suffix('b').unwrap_or(mode); // ignore binary mode
+        match mode {
+            "r" => opts.read(true),
+            "w" => opts.write(true).create(true).truncate(true),
+            "a" => opts.append(true).create(true),
+            "r+" => opts.read(true).write(true),
+            "w+" => opts.read(true).write(true).create(true).truncate(true),
+            "a+" => opts.read(true).append(true).create(true),
+            _ => panic!("bad argument #2 to 'open' (invalid mode)"),
+        };
+        let file = opts.open(name)?;
+        Ok(self.new_file(LuaFile::new(Stream::File(file))))
+    }
+}
+
+pub fn open() -> Value {
+    // methods of file
+    let mut methods = Table::new(0, 8);
+    methods.map.insert("read".into(), Value::RustFunction(f_read));
+    methods.map.insert("write".into(), Value::RustFunction(f_write));
+    methods.map.insert("lines".into(), Value::RustFunction(f_lines));
+    methods.map.insert("seek".into(), Value::RustFunction(f_seek));
+    methods.map.insert("setvbuf".into(), Value::RustFunction(f_setvbuf));
+    methods.map.insert("flush".into(), Value::RustFunction(f_flush));
+    methods.map.insert("close".into(), Value::RustFunction(f_close));
+
+    let mut metatable = Table::new(0, 3);
+    metatable.map.insert("__name".into(), "FILE*".into());
+    metatable.map.insert("__index".into(), methods.into());
+    metatable.map.insert("__close".into(), Value::RustFunction(f_tbc_close));
+    let metatable = Rc::new(RefCell::new(metatable));
+
+    let new_std = |stream| UserData::new_value(LuaFile::new(stream), Some(metatable.clone()));
+    let stdin = new_std(Stream::Stdin(io::stdin()));
+    let stdout = new_std(Stream::Stdout(io::stdout()));
+    let stderr = new_std(Stream::Stderr(io::stderr()));
+
+    let io_state = Rc::new(RefCell::new(IoState {
+        metatable,
+        input: stdin.clone(),
+        output: stdout.clone(),
+    }));
+
+    // library functions which need the shared state are closures
+    let closure = |f: fn(&mut ExeState, &mut IoState) -> Result<i32, LuaError>| {
+        let io_state = io_state.clone();
+        let c = move |state: &mut ExeState| f(state, &mut io_state.borrow_mut());
+        Value::RustClosure(Rc::new(RefCell::new(Box::new(c))))
+    };
+
+    let mut lib = Table::new(0, 16);
+    lib.map.insert("open".into(), closure(io_open));
+    lib.map.insert("close".into(), closure(io_close));
+    lib.map.insert("read".into(), closure(io_read));
+    lib.map.insert("write".into(), closure(io_write));
+    lib.map.insert("lines".into(), closure(io_lines));
+    lib.map.insert("input".into(), closure(io_input));
+    lib.map.insert("output".into(), closure(io_output));
+    lib.map.insert("tmpfile".into(), closure(io_tmpfile));
+    lib.map.insert("type".into(), Value::RustFunction(io_type));
+    lib.map.insert("stdin".into(), stdin);
+    lib.map.insert("stdout".into(), stdout);
+    lib.map.insert("stderr".into(), stderr);
+    lib.into()
+}
+
+// error message like C's strerror()
+fn error_msg(e: &io::Error, filename: Option<&str>) -> String {
+    // remove the " (os error N)" suffix of Rust's message
+    let msg = e.to_string();
+    let msg = msg.split(" (os error").next().unwrap();
+    match filename {
+        Some(name) => format!("{name}: {msg}"),
+        None => msg.to_string(),
+    }
+}
+
+// push (nil, message, errno) on failure
+pub fn push_error(state: &mut ExeState, e: io::Error, filename: Option<&str>) -> Result<i32, LuaError> {
+    state.push(());
+    state.push(error_msg(&e, filename));
+    state.push(e.raw_os_error().unwrap_or(0) as i64);
+    Ok(3)
+}
+
+// push the @v on success, or the error on failure
+fn push_result(state: &mut ExeState, r: io::Result<Value>) -> Result<i32, LuaError> {
+    match r {
+        Ok(v) => {
+            state.push(v);
+            Ok(1)
+        }
+        Err(e) => push_error(state, e, None),
+    }
+}
+
+// check the @i-th argument is a file and run @f on it
+fn with_file<T>(v: &Value, i: usize, f: impl FnOnce(&mut LuaFile) -> T) -> T {
+    let Value::UserData(u) = v else {
+        panic!("bad argument #{i} (FILE* expected, got {})", v.ty());
+    };
+    let mut data = u.data.borrow_mut();
+    let Some(file) = data.downcast_mut::<LuaFile>() else {
+        panic!("bad argument #{i} (FILE* expected, got userdata)");
+    };
+    if file.is_closed() {
+        panic!("attempt to use a closed file");
+    }
+    f(file)
+}
+
+fn str_arg(v: &Value) -> String {
+    String::from_utf8_lossy(v.as_ref()).into_owned()
+}
+
+// read by formats in stack[first..], and push the results
+fn read_formats(state: &mut ExeState, file: &Value, first: usize) -> Result<i32, LuaError> {
+    let formats: Vec<Value> = if state.get_top() < first {
+        vec!["l".into()]
+    } else {
+        (first ..= state.get_top()).map(|i| state.arg(i).clone()).collect()
+    };
+    match do_read(state, file, &formats, first) {
+        Ok(n) => Ok(n),
+        Err(e) => push_error(state, e, None),
+    }
+}
+
+// push the results, and return the number of them
+fn do_read(state: &mut ExeState, file: &Value, formats: &[Value], first: usize) -> io::Result<i32> {
+    let mut n = 0;
+    for (i, fmt) in formats.iter().enumerate() {
+        let r = with_file(file, 1, |f| match fmt {
+            &Value::Integer(count) => Ok(f.read_count(count.max(0) as usize)?.map(Value::from)),
+            &Value::Float(_) => panic!("bad argument #{} (number has no integer representation)", first + i),
+            Value::ShortStr(_, _) | Value::MidStr(_) | Value::LongStr(_) => {
+                let fmt: &[u8] = fmt.as_ref();
+                match fmt.strip_prefix(b"*").unwrap_or(fmt).first() { // `*` is optional
+                    Some(b'n') => f.read_number(),
+                    Some(b'l') => Ok(f.read_line(false)?.map(Value::from)),
+                    Some(b'L') => Ok(f.read_line(true)?.map(Value::from)),
+                    Some(b'a') => Ok(Some(f.read_all()?.into())),
+                    _ => panic!("bad argument #{} (invalid format)", first + i),
+                }
+            }
+            v => panic!("bad argument #{} (string expected, got {})", first + i, v.ty()),
+        });
+        match r? {
+            Some(v) => {
+                state.push(v);
+                n += 1;
+            }
+            None => { // fail and ignore the following formats
+                state.push(());
+                return Ok(n + 1);
+            }
+        }
+    }
+    Ok(n)
+}
+
+// write values in stack[first..], and push the file on success
+fn do_write(state: &mut ExeState, file: &Value, first: usize) -> Result<i32, LuaError> {
+    for i in first ..= state.get_top() {
+        let r = match &*state.arg(i) {
+            v@(Value::Integer(_) | Value::Float(_)) => {
+                let s = v.to_string();
+                with_file(file, 1, |f| f.write(s.as_bytes()))
+            }
+            v@(Value::ShortStr(_, _) | Value::MidStr(_) | Value::LongStr(_)) =>
+                with_file(file, 1, |f| f.write(v.as_ref())),
+            v => panic!("bad argument #{} (string expected, got {})", i - first + 1, v.ty()),
+        };
+        if let Err(e) = r {
+            return push_error(state, e, None);
+        }
+    }
+    state.push(file.clone());
+    Ok(1)
+}
+
+// create an iterator function which reads @file by @formats
+fn new_lines_iter(file: Value, formats: Vec<Value>, close_at_eof: bool) -> Value {
+    let formats = if formats.is_empty() { vec!["l".into()] } else { formats };
+    let iter = move |state: &mut ExeState| {
+        if is_closed_file(&file) {
+            panic!("file is already closed");
+        }
+        let top = state.get_top();
+        let n = do_read(state, &file, &formats, 1)
+            .unwrap_or_else(|e| panic!("{}", error_msg(&e, None)));
+        if *state.arg(top + 1) == Value::Nil && close_at_eof {
+            let _ = with_file(&file, 1, |f| f.close());
+        }
+        Ok(n)
+    };
+    Value::RustClosure(Rc::new(RefCell::new(Box::new(iter))))
+}
+
+fn io_open(state: &mut ExeState, io_state: &mut IoState) -> Result<i32, LuaError> {
+    let name = String::from_utf8_lossy(state.check_string(1)).into_owned();
+    let mode = if *state.arg(2) == Value::Nil { "r".into() } else { str_arg(&state.arg(2)) };
+    match io_state.open_file(&name, &mode) {
+        Ok(file) => {
+            state.push(file);
+            Ok(1)
+        }
+        Err(e) => push_error(state, e, Some(&name)),
+    }
+}
+
+fn io_close(state: &mut ExeState, io_state: &mut IoState) -> Result<i32, LuaError> {
+    let file = match &*state.arg(1) {
+        Value::Nil => io_state.output.clone(),
+        v => v.clone(),
+    };
+    let r = with_file(&file, 1, |f| f.close());
+    push_result(state, r.map(|_| Value::Boolean(true)))
+}
+
+fn io_read(state: &mut ExeState, io_state: &mut IoState) -> Result<i32, LuaError> {
+    let input = io_state.input.clone();
+    read_formats(state, &input, 1)
+}
+
+fn io_write(state: &mut ExeState, io_state: &mut IoState) -> Result<i32, LuaError> {
+    let output = io_state.output.clone();
+    do_write(state, &output, 1)
+}
+
+fn io_lines(state: &mut ExeState, io_state: &mut IoState) -> Result<i32, LuaError> {
+    let formats = (2 ..= state.get_top()).map(|i| state.arg(i).clone()).collect();
+    let iter = if *state.arg(1) == Value::Nil {
+        new_lines_iter(io_state.input.clone(), formats, false)
+    } else {
+        let name = String::from_utf8_lossy(state.check_string(1)).into_owned();
+        match io_state.open_file(&name, "r") {
+            Ok(file) => new_lines_iter(file, formats, true),
+            Err(e) => panic!("{}", error_msg(&e, Some(&name))),
+        }
+    };
+    state.push(iter);
+    Ok(1)
+}
+
+// common for io.input() and io.output()
+fn set_default_file(state: &mut ExeState, io_state: &IoState, current: &mut Value, mode: &str) {
+    match &*state.arg(1) {
+        Value::Nil => (),
+        v@Value::UserData(_) => {
+            with_file(v, 1, |_| ()); // check file
+            *current = v.clone();
+        }
+        _ => {
+            let name = String::from_utf8_lossy(state.check_string(1)).into_owned();
+            match io_state.open_file(&name, mode) {
+                Ok(file) => *current = file,
+                Err(e) => panic!("{}", error_msg(&e, Some(&name))),
+            }
+        }
+    }
+    state.push(current.clone());
+}
+
+fn io_input(state: &mut ExeState, io_state: &mut IoState) -> Result<i32, LuaError> {
+    let mut input = io_state.input.clone();
+    set_default_file(state, io_state, &mut input, "r");
+    io_state.input = input;
+    Ok(1)
+}
+
+fn io_output(state: &mut ExeState, io_state: &mut IoState) -> Result<i32, LuaError> {
+    let mut output = io_state.output.clone();
+    set_default_file(state, io_state, &mut output, "w");
+    io_state.output = output;
+    Ok(1)
+}
+
+fn io_tmpfile(state: &mut ExeState, io_state: &mut IoState) -> Result<i32, LuaError> {
+    let r = (|| {
+        let path = tmpname();
+        let file = OpenOptions::new().read(true).write(true).create_new(true).open(&path)?;
+        let _ = fs::remove_file(&path); // removed when closed, on Unix at least
+        Ok(io_state.new_file(LuaFile::new(Stream::File(file))))
+    })();
+    push_result(state, r)
+}
+
+// a unique file name in the temporary directory
+pub fn tmpname() -> String {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+    let name = format!("lua_{}_{n}", process::id());
+    env::temp_dir().join(name).to_string_lossy().into_owned()
+}
+
+fn is_closed_file(v: &Value) -> bool {
+    match v {
+        Value::UserData(u) => u.data.borrow().downcast_ref::<LuaFile>()
+            .is_some_and(LuaFile::is_closed),
+        _ => false,
+    }
+}
+
+fn io_type(state: &mut ExeState) -> Result<i32, LuaError> {
+    let r = match &*state.arg(1) {
+        Value::UserData(u) if u.data.borrow().is::<LuaFile>() =>
+            if is_closed_file(&state.arg(1)) { "closed file" } else { "file" }.into(),
+        _ => Value::Nil,
+    };
+    state.push(r);
+    Ok(1)
+}
+
+fn f_read(state: &mut ExeState) -> Result<i32, LuaError> {
+    let file = state.arg(1).clone();
+    read_formats(state, &file, 2)
+}
+
+fn f_write(state: &mut ExeState) -> Result<i32, LuaError> {
+    let file = state.arg(1).clone();
+    do_write(state, &file, 2)
+}
+
+fn f_lines(state: &mut ExeState) -> Result<i32, LuaError> {
+    let file = state.arg(1).clone();
+    with_file(&file, 1, |_| ()); // check file
+    let formats = (2 ..= state.get_top()).map(|i| state.arg(i).clone()).collect();
+    state.push(new_lines_iter(file, formats, false));
+    Ok(1)
+}
+
+fn f_seek(state: &mut ExeState) -> Result<i32, LuaError> {
+    let file = state.arg(1).clone();
+    let whence = if *state.arg(2) == Value::Nil { "cur".into() } else { str_arg(&state.arg(2)) };
+    let offset = state.opt_integer(3, 0);
+    let pos = match whence.as_str() {
+        "set" => SeekFrom::Start(offset as u64),
+        "cur" => SeekFrom::Current(offset),
+        "end" => SeekFrom::End(offset),
+        _ => panic!("bad argument #1 to 'seek' (invalid option '{whence}')"),
+    };
+    let r = with_file(&file, 1, |f| f.seek(pos));
+    push_result(state, r.map(|pos| Value::Integer(pos as i64)))
+}
+
+fn f_setvbuf(state: &mut ExeState) -> Result<i32, LuaError> {
+    let file = state.arg(1).clone();
+    let size = state.opt_integer(3, BUF_SIZE as i64).max(1) as usize;
+    let mode = match state.check_string(2) {
+        b"no" => BufMode::No,
+        b"full" => BufMode::Full(size),
+        b"line" => BufMode::Line(size),
+        m => panic!("bad argument #1 to 'setvbuf' (invalid option '{}')", String::from_utf8_lossy(m)),
+    };
+    let r = with_file(&file, 1, |f| {
+        f.vbuf = mode;
+        f.flush()
+    });
+    push_result(state, r.map(|_| Value::Boolean(true)))
+}
+
+fn f_flush(state: &mut ExeState) -> Result<i32, LuaError> {
+    let file = state.arg(1).clone();
+    let r = with_file(&file, 1, |f| f.flush());
+    push_result(state, r.map(|_| file.clone()))
+}
+
+fn f_close(state: &mut ExeState) -> Result<i32, LuaError> {
+    let file = state.arg(1).clone();
+    let r = with_file(&file, 1, |f| f.close());
+    push_result(state, r.map(|_| Value::Boolean(true)))
+}
+
+// `__close` metamethod, for `local f <close> = io.open(...)`. Close the
+// file if not closed yet, and ignore errors, including standard files.
+fn f_tbc_close(state: &mut ExeState) -> Result<i32, LuaError> {
+    let file = state.arg(1).clone();
+    if !is_closed_file(&file) {
+        let _ = with_file(&file, 1, |f| f.close());
+    }
+    Ok(0)
+}
@@ -0,0 +1,323 @@
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::value::{Value, Table};
+use crate::vm::{ExeState, LuaError};
+use crate::utils::ftoi;
+
+pub fn open() -> Value {
+    let mut lib = Table::new(0, 32);
+    let mut set = |name: &str, v: Value| { lib.map.insert(name.into(), v); };
+
+    set("abs", Value::RustFunction(math_abs));
+    set("ceil", Value::RustFunction(math_ceil));
+    set("floor", Value::RustFunction(math_floor));
+    set("fmod", Value::RustFunction(math_fmod));
+    set("modf", Value::RustFunction(math_modf));
+    set("sqrt", Value::RustFunction(math_sqrt));
+    set("exp", Value::RustFunction(math_exp));
+    set("log", Value::RustFunction(math_log));
+    set("sin", Value::RustFunction(math_sin));
+    set("cos", Value::RustFunction(math_cos));
+    set("tan", Value::RustFunction(math_tan));
+    set("asin", Value::RustFunction(math_asin));
+    set("acos", Value::RustFunction(math_acos));
+    set("atan", Value::RustFunction(math_atan));
+    set("tointeger", Value::RustFunction(math_tointeger));
+    set("type", Value::RustFunction(math_type));
+    set("ult", Value::RustFunction(math_ult));
+    set("max", Value::RustFunction(math_max));
+    set("min", Value::RustFunction(math_min));
+
+    set("huge", Value::Float(f64::INFINITY));
+    set("pi", Value::Float(std::f64::consts::PI));
+    set("maxinteger", Value::Integer(i64::MAX));
+    set("mininteger", Value::Integer(i64::MIN));
+
+    // The random state is shared by `random` and `randomseed` by
+    // capturing it in both closures, instead of putting it in registry
+    // as the official implementation does.
+    let state = Rc::new(RefCell::new(Xoshiro256::from_time()));
+
+    let s = state.clone();
+    let random = move |state: &mut ExeState| math_random(state, &mut s.borrow_mut());
+    set("random", Value::RustClosure(Rc::new(RefCell::new(Box::new(random)))));
+
+    let s = state;
+    let randomseed = move |state: &mut ExeState| math_randomseed(state, &mut s.borrow_mut());
+    set("randomseed", Value::RustClosure(Rc::new(RefCell::new(Box::new(randomseed)))));
+
+    lib.into()
+}
+
+// push the float @f as integer if it fits, or as float otherwise
+fn push_float_int(state: &mut ExeState, f: f64) {
+    match ftoi(f) {
+        Some(i) => state.push(i),
+        None => state.push(f),
+    }
+}
+
+fn math_abs(state: &mut ExeState) -> Result<i32, LuaError> {
+    match &*state.arg(1) {
+        &Value::Integer(i) => state.push(i.wrapping_abs()),
+        _ => state.push(state.check_number(1).abs()),
+    }
+    Ok(1)
+}
+
+fn math_ceil(state: &mut ExeState) -> Result<i32, LuaError> {
+    match &*state.arg(1) {
+        &Value::Integer(i) => state.push(i),
+        _ => push_float_int(state, state.check_number(1).ceil()),
+    }
+    Ok(1)
+}
+
+fn math_floor(state: &mut ExeState) -> Result<i32, LuaError> {
+    match &*state.arg(1) {
+        &Value::Integer(i) => state.push(i),
+        _ => push_float_int(state, state.check_number(1).floor()),
+    }
+    Ok(1)
+}
+
+fn math_fmod(state: &mut ExeState) -> Result<i32, LuaError> {
+    match (&*state.arg(1), &*state.arg(2)) {
+        (&Value::Integer(a), &Value::Integer(b)) => {
+            let r = match b {
+                0 => panic!("bad argument #2 to 'fmod' (zero)"),
+                -1 => 0, // avoid overflow with i64::MIN % -1
+                _ => a % b, // truncated, same with C's fmod
+            };
+            state.push(r);
+        }
+        _ => {
+            let r = state.check_number(1) % state.check_number(2);
+            state.push(r);
+        }
+    }
+    Ok(1)
+}
+
+fn math_modf(state: &mut ExeState) -> Result<i32, LuaError> {
+    match &*state.arg(1) {
+        &Value::Integer(i) => { // number is its own integer part
+            state.push(i);
+            state.push(0.0);
+        }
+        _ => {
+            let f = state.check_number(1);
+            let ip = f.trunc();
+            state.push(ip);
+            // test needed for inf/-inf
+            state.push(if f == ip { 0.0 } else { f - ip });
+        }
+    }
+    Ok(2)
+}
+
+fn math_sqrt(state: &mut ExeState) -> Result<i32, LuaError> {
+    state.push(state.check_number(1).sqrt());
+    Ok(1)
+}
+fn math_exp(state: &mut ExeState) -> Result<i32, LuaError> {
+    state.push(state.check_number(1).exp());
+    Ok(1)
+}
+fn math_sin(state: &mut ExeState) -> Result<i32, LuaError> {
+    state.push(state.check_number(1).sin());
+    Ok(1)
+}
+fn math_cos(state: &mut ExeState) -> Result<i32, LuaError> {
+    state.push(state.check_number(1).cos());
+    Ok(1)
+}
+fn math_tan(state: &mut ExeState) -> Result<i32, LuaError> {
+    state.push(state.check_number(1).tan());
+    Ok(1)
+}
+fn math_asin(state: &mut ExeState) -> Result<i32, LuaError> {
+    state.push(state.check_number(1).asin());
+    Ok(1)
+}
+fn math_acos(state: &mut ExeState) -> Result<i32, LuaError> {
+    state.push(state.check_number(1).acos());
+    Ok(1)
+}
+
+fn math_atan(state: &mut ExeState) -> Result<i32, LuaError> {
+    let y = state.check_number(1);
+    let x = if state.get_top() >= 2 { state.check_number(2) } else { 1.0 };
+    state.push(y.atan2(x));
+    Ok(1)
+}
+
+fn math_log(state: &mut ExeState) -> Result<i32, LuaError> {
+    let x = state.check_number(1);
+    let r = if state.get_top() < 2 || *state.arg(2) == Value::Nil {
+        x.ln()
+    } else {
+        let base = state.check_number(2);
+        if base == 2.0 {
+            x.log2()
+        } else if base == 10.0 {
+            x.log10()
+        } else {
+            x.ln() / base.ln()
+        }
+    };
+    state.push(r);
+    Ok(1)
+}
+
+fn math_tointeger(state: &mut ExeState) -> Result<i32, LuaError> {
+    let r = match *state.arg(1) {
+        Value::Integer(i) => Value::Integer(i),
+        Value::Float(f) => ftoi(f).map_or(Value::Nil, Value::Integer),
+        _ => Value::Nil,
+    };
+    state.push(r);
+    Ok(1)
+}
+
+fn math_type(state: &mut ExeState) -> Result<i32, LuaError> {
+    if state.get_top() == 0 {
+        panic!("bad argument #1 to 'type' (value expected)");
+    }
+    match &*state.arg(1) {
+        Value::Integer(_) => state.push("integer"),
+        Value::Float(_) => state.push("float"),
+        _ => state.push(()),
+    }
+    Ok(1)
+}
+
+fn math_ult(state: &mut ExeState) -> Result<i32, LuaError> {
+    let a = state.check_integer(1) as u64;
+    let b = state.check_integer(2) as u64;
+    state.push(a < b);
+    Ok(1)
+}
+
+fn math_max(state: &mut ExeState) -> Result<i32, LuaError> {
+    math_select(state, Ordering::Greater)
+}
+fn math_min(state: &mut ExeState) -> Result<i32, LuaError> {
+    math_select(state, Ordering::Less)
+}
+
+// select the max or min argument, keeping its subtype
+fn math_select(state: &mut ExeState, want: Ordering) -> Result<i32, LuaError> {
+    let n = state.get_top();
+    state.check_number(1);
+    let mut imax = 1;
+    for i in 2..=n {
+        state.check_number(i);
+        if state.arg(i).partial_cmp(&state.arg(imax)) == Some(want) {
+            imax = i;
+        }
+    }
+    let v = state.arg(imax).clone();
+    state.push(v);
+    Ok(1)
+}
+
+fn math_random(state: &mut ExeState, rng: &mut Xoshiro256) -> Result<i32, LuaError> {
+    let rv = rng.next();
+    let (low, up) = match state.get_top() {
+        0 => { // float in [0, 1)
+            state.push(Xoshiro256::to_float(rv));
+            return Ok(1);
+        }
+        1 => {
+            let up = state.check_integer(1);
+            if up == 0 { // full random integer
+                state.push(rv as i64);
+                return Ok(1);
+            }
+            (1, up)
+        }
+        2 => (state.check_integer(1), state.check_integer(2)),
+        _ => panic!("wrong number of arguments"),
+    };
+    if low > up {
+        panic!("bad argument #1 to 'random' (interval is empty)");
+    }
+
+    let r = rng.project(rv, (up as u64).wrapping_sub(low as u64));
+    state.push(r.wrapping_add(low as u64) as i64);
+    Ok(1)
+}
+
+fn math_randomseed(state: &mut ExeState, rng: &mut Xoshiro256) -> Result<i32, LuaError> {
+    let (n1, n2) = if state.get_top() == 0 {
+        let Xoshiro256(s) = Xoshiro256::from_time();
+        (s[0] as i64, s[2] as i64)
+    } else {
+        let n1 = state.check_integer(1);
+        let n2 = state.opt_integer(2, 0);
+        (n1, n2)
+    };
+    *rng = Xoshiro256::new(n1 as u64, n2 as u64);
+    state.push(n1);
+    state.push(n2);
+    Ok(2)
+}
+
+// Pseudo-random number generator xoshiro256**, the same with the
+// official implementation, so seeded sequences are the same too.
+struct Xoshiro256([u64; 4]);
+
+impl Xoshiro256 {
+    fn new(n1: u64, n2: u64) -> Self {
+        let mut rng = Xoshiro256([n1, 0xff, n2, 0]); // avoid a zero state
+        for _ in 0..16 {
+            rng.next(); // discard initial values to "spread" seed
+        }
+        rng
+    }
+
+    fn from_time() -> Self {
+        let t = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64);
+        let addr = &t as *const u64 as u64; // some randomness from ASLR
+        Xoshiro256::new(t, addr)
+    }
+
+    fn next(&mut self) -> u64 {
+        let s = &mut self.0;
+        let state0 = s[0];
+        let state1 = s[1];
+        let state2 = s[2] ^ state0;
+        let state3 = s[3] ^ state1;
+        let res = state1.wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+        s[0] = state0 ^ state3;
+        s[1] = state1 ^ state2;
+        s[2] = state2 ^ (state1 << 17);
+        s[3] = state3.rotate_left(45);
+        res
+    }
+
+    // take the higher 53 bits as float in [0, 1)
+    fn to_float(rv: u64) -> f64 {
+        (rv >> 11) as f64 * (0.5 / (1_u64 << 52) as f64)
+    }
+
+    // project a random integer @rv into the interval [0, n]
+    fn project(&mut self, mut rv: u64, n: u64) -> u64 {
+        if n & n.wrapping_add(1) == 0 { // is 'n + 1' a power of 2?
+            return rv & n; // no bias
+        }
+
+        // compute the smallest (2^b - 1) not smaller than n
+        let lim = u64::MAX >> n.leading_zeros();
+        loop {
+            rv &= lim;
+            if rv <= n {
+                return rv;
+            }
+            rv = self.next(); // not inside [0, n]? Try again
+        }
+    }
+}
@@ -0,0 +1,250 @@
+use std::rc::{Rc, Weak};
+use std::cell::RefCell;
+use std::env;
+use std::fs::{self, File};
+use crate::value::{Value, Table};
+use crate::vm::{ExeState, LuaError};
+
+const LUA_PATH_DEFAULT: &str = "/usr/local/share/lua/5.4/?.lua;/usr/local/share/lua/5.4/?/init.lua;\
+    /usr/local/lib/lua/5.4/?.lua;/usr/local/lib/lua/5.4/?/init.lua;./?.lua;./?/init.lua";
+
+// module found by ModuleLoader
+pub enum Module {
+    // Lua source code, to be compiled and run in the global environment
+    Source(Vec<u8>),
+
+    // loader function, called with the module name and the extra
+    // string, and returns the module value, such as a function
+    // building a table of Rust functions
+    Native(Value),
+}
+
+// Searcher of modules for `require`. Besides the built-in ones for
+// `package.preload` and `package.path`, hosts can supply modules from
+// memory or a virtual filesystem by `ExeState::add_module_loader()`.
+pub trait ModuleLoader {
+    // Return the module and an extra string passed to its loader,
+    // usually the file name. Or return a message explaining where
+    // it has looked for, which is shown in a line prefixed by a tab
+    // if no loader finds it.
+    fn search(&self, name: &str) -> Result<(Module, String), String>;
+}
+
+impl<F> ModuleLoader for F where F: Fn(&str) -> Result<(Module, String), String> {
+    fn search(&self, name: &str) -> Result<(Module, String), String> {
+        self(name)
+    }
+}
+
+// The searchers are stored in `package.searchers`, so they refer
+// to the package table by Weak to avoid reference cycle.
+struct PreloadLoader(Weak<RefCell<Table>>);
+struct PathLoader(Weak<RefCell<Table>>);
+
+impl ModuleLoader for PreloadLoader {
+    fn search(&self, name: &str) -> Result<(Module, String), String> {
+        let package = self.0.upgrade().expect("package table dropped");
+        let preload = match *package.borrow().index(&"preload".into()) {
+            Value::Table(ref t) => t.clone(),
+            _ => panic!("'package.preload' must be a table"),
+        };
+        let preload = preload.borrow();
+        match preload.index(&name.into()).clone() {
+            Value::Nil => Err(format!("no field package.preload['{name}']")),
+            loader => Ok((Module::Native(loader), String::from(":preload:"))),
+        }
+    }
+}
+
+impl ModuleLoader for PathLoader {
+    fn search(&self, name: &str) -> Result<(Module, String), String> {
+        let package = self.0.upgrade().expect("package table dropped");
+        let path = to_string(&package.borrow().index(&"path".into()))
+            .unwrap_or_else(|| panic!("'package.path' must be a string"));
+        let filename = search_path(name, &path, ".", "/")?;
+        match fs::read(&filename) {
+            Ok(code) => Ok((Module::Source(code), filename)),
+            Err(e) => panic!("error loading module '{name}' from file '{filename}':\n\t{e}"),
+        }
+    }
+}
+
+fn to_string(v: &Value) -> Option<String> {
+    match v {
+        Value::ShortStr(_, _) | Value::MidStr(_) | Value::LongStr(_) =>
+            Some(String::from_utf8_lossy(v.as_ref()).into_owned()),
+        _ => None,
+    }
+}
+
+fn is_function(v: &Value) -> bool {
+    matches!(v, Value::RustFunction(_) | Value::RustClosure(_)
+        | Value::LuaFunction(_) | Value::LuaClosure(_))
+}
+
+// @loaded is shared with `require`
+pub fn open(loaded: &Rc<RefCell<Table>>) -> Value {
+    let package = Rc::new(RefCell::new(Table::new(0, 8)));
+    {
+        let mut lib = package.borrow_mut();
+        lib.map.insert("loaded".into(), Value::Table(loaded.clone()));
+        lib.map.insert("preload".into(), Table::new(0, 0).into());
+        lib.map.insert("path".into(), default_path().into());
+        lib.map.insert("config".into(), "/\n;\n?\n!\n-\n".into());
+        lib.map.insert("searchers".into(), Table::new(4, 0).into());
+        lib.map.insert("searchpath".into(), Value::RustFunction(package_searchpath));
+    }
+    let preload = PreloadLoader(Rc::downgrade(&package));
+    let path = PathLoader(Rc::downgrade(&package));
+
+    let package = Value::Table(package);
+    add_searcher(&package, preload);
+    add_searcher(&package, path);
+    package
+}
+
+// append a searcher made from @loader to `package.searchers`
+pub fn add_searcher(package: &Value, loader: impl ModuleLoader + 'static) {
+    let searcher = move |state: &mut ExeState| {
+        let name = String::from_utf8_lossy(state.check_string(1)).into_owned();
+        match loader.search(&name) {
+            Ok((Module::Source(code), extra)) => {
+                let chunkname = format!("@{extra}");
+                let loader = state.load(&code[..], &chunkname, None).unwrap_or_else(|msg|
+                    panic!("error loading module '{name}' from file '{extra}':\n\t{msg}"));
+                state.push(loader);
+                state.push(extra);
+                Ok(2)
+            }
+            Ok((Module::Native(loader), extra)) => {
+                state.push(loader);
+                state.push(extra);
+                Ok(2)
+            }
+            Err(msg) => {
+                state.push(msg);
+                Ok(1)
+            }
+        }
+    };
+    let searcher = Value::RustClosure(Rc::new(RefCell::new(Box::new(searcher))));
+
+    let searchers = match package {
+        Value::Table(t) => t.borrow().index(&"searchers".into()).clone(),
+        _ => panic!("package is not a table"),
+    };
+    match searchers {
+        Value::Table(t) => {
+            let mut t = t.borrow_mut();
+            let n = t.array.len() as i64;
+            t.new_index_array(n + 1, searcher);
+        }
+        _ => panic!("'package.searchers' must be a table"),
+    }
+}
+
+// This is a Rust function but not closure, since it may be called
+// recursively when loading modules which require other modules.
+pub fn lib_require(state: &mut ExeState) -> Result<i32, LuaError> {
+    let name = state.check_string(1).to_vec();
+    let key = Value::from(&name[..]);
+
+    let Value::Table(loaded) = state.loaded() else { unreachable!() };
+    let module = loaded.borrow().index(&key).clone();
+    if bool::from(&module) { // package is already loaded
+        state.push(module);
+        return Ok(1);
+    }
+
+    // iterate over available searchers to find a loader
+    let searchers = match *loaded.borrow().index(&"package".into()) {
+        Value::Table(ref t) => t.borrow().index(&"searchers".into()).clone(),
+        _ => panic!("'package' must be a table"),
+    };
+    let Value::Table(searchers) = searchers else {
+        panic!("'package.searchers' must be a table");
+    };
+    let mut msg = format!("module '{}' not found:", String::from_utf8_lossy(&name));
+    let mut i = 1;
+    let (loader, extra) = loop {
+        let searcher = searchers.borrow().index_array(i).clone();
+        if searcher == Value::Nil {
+            panic!("{msg}");
+        }
+        let mut rets = state.call(searcher, std::slice::from_ref(&key))?.into_iter();
+        match rets.next() {
+            Some(loader) if is_function(&loader) => break (loader, rets.next().unwrap_or(Value::Nil)),
+            Some(s) => if let Some(s) = to_string(&s) {
+                msg.push_str("\n\t");
+                msg.push_str(&s);
+            }
+            None => (),
+        }
+        i += 1;
+    };
+
+    let module = state.call(loader, &[key.clone(), extra.clone()])?
+        .into_iter().next().unwrap_or(Value::Nil);
+    if module != Value::Nil {
+        loaded.borrow_mut().new_index(key.clone(), module);
+    }
+    let module = loaded.borrow().index(&key).clone();
+    let module = if module == Value::Nil { // module set no value?
+        loaded.borrow_mut().new_index(key, Value::Boolean(true));
+        Value::Boolean(true)
+    } else {
+        module
+    };
+    state.push(module);
+    state.push(extra);
+    Ok(2)
+}
+
+// package.searchpath(name, path [, sep [, rep]])
+fn package_searchpath(state: &mut ExeState) -> Result<i32, LuaError> {
+    let name = String::from_utf8_lossy(state.check_string(1)).into_owned();
+    let path = String::from_utf8_lossy(state.check_string(2)).into_owned();
+    let sep = match &*state.arg(3) {
+        Value::Nil => String::from("."),
+        _ => String::from_utf8_lossy(state.check_string(3)).into_owned(),
+    };
+    let rep = match &*state.arg(4) {
+        Value::Nil => String::from("/"),
+        _ => String::from_utf8_lossy(state.check_string(4)).into_owned(),
+    };
+    match search_path(&name, &path, &sep, &rep) {
+        Ok(filename) => {
+            state.push(filename);
+            Ok(1)
+        }
+        Err(msg) => {
+            state.push(());
+            state.push(msg);
+            Ok(2)
+        }
+    }
+}
+
+// replace @sep in @name by @rep, and try each template in @path
+// separated by ';', replacing '?' by the name, to find a readable file
+fn search_path(name: &str, path: &str, sep: &str, rep: &str) -> Result<String, String> {
+    let name = if sep.is_empty() { name.to_string() } else { name.replace(sep, rep) };
+    let mut tried = Vec::new();
+    for template in path.split(';').filter(|t| !t.is_empty()) {
+        let filename = template.replace('?', &name);
+        if File::open(&filename).is_ok() {
+            return Ok(filename);
+        }
+        tried.push(format!("no file '{filename}'"));
+    }
+    Err(tried.join("\n\t"))
+}
+
+// from environment variable, where ";;" means the default path
+fn default_path() -> String {
+    match env::var("LUA_PATH_5_4").or_else(|_| env::var("LUA_PATH")) {
+        Ok(path) => path.replacen(";;", &format!(";{LUA_PATH_DEFAULT};"), 1)
+            .trim_matches(';').to_string(),
+        Err(_) => LUA_PATH_DEFAULT.to_string(),
+    }
+}
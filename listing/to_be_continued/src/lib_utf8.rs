@@ -0,0 +1,231 @@
+use crate::value::{Value, Table};
+use crate::vm::{ExeState, LuaError};
+use crate::utils::utf8_encode;
+
+const MAXUNICODE: u32 = 0x10FFFF;
+const MAXUTF: u32 = 0x7FFFFFFF;
+
+pub fn open() -> Value {
+    let mut lib = Table::new(0, 8);
+    lib.map.insert("char".into(), Value::RustFunction(utf8_char));
+    lib.map.insert("charpattern".into(), b"[\0-\x7F\xC2-\xFD][\x80-\xBF]*"[..].into());
+    lib.map.insert("codepoint".into(), Value::RustFunction(utf8_codepoint));
+    lib.map.insert("len".into(), Value::RustFunction(utf8_len));
+    lib.map.insert("offset".into(), Value::RustFunction(utf8_offset));
+    lib.map.insert("codes".into(), Value::RustFunction(utf8_codes));
+    lib.into()
+}
+
+fn is_cont(s: &[u8], i: usize) -> bool {
+    s.get(i).is_some_and(|&b| b & 0xC0 == 0x80)
+}
+
+// translate a relative string position: negative means back from end
+fn posrelat(pos: i64, len: usize) -> i64 {
+    if pos >= 0 {
+        pos
+    } else if pos.unsigned_abs() > len as u64 {
+        0
+    } else {
+        len as i64 + pos + 1
+    }
+}
+
+// Decode one UTF-8 sequence at the beginning of @s, and return the code
+// point and its length. Sequences of up to 6 bytes are accepted, and in
+// @strict mode surrogates and values above MAXUNICODE are rejected.
+fn utf8_decode(s: &[u8], strict: bool) -> Option<(u32, usize)> {
+    const LIMITS: [u32; 6] = [!0, 0x80, 0x800, 0x10000, 0x200000, 0x4000000];
+
+    let mut c = *s.first()? as u32;
+    let mut res: u32 = 0;
+    let mut count = 0;
+    if c >= 0x80 {
+        while c & 0x40 != 0 { // while it needs continuation bytes
+            count += 1;
+            if count > 5 {
+                return None;
+            }
+            let cc = *s.get(count)? as u32;
+            if cc & 0xC0 != 0x80 {
+                return None;
+            }
+            res = (res << 6) | (cc & 0x3F);
+            c <<= 1;
+        }
+        res |= (c & 0x7F) << (count * 5);
+        if res > MAXUTF || res < LIMITS[count] {
+            return None; // overlong or invalid
+        }
+    } else {
+        res = c;
+    }
+    if strict && (res > MAXUNICODE || (0xD800..=0xDFFF).contains(&res)) {
+        return None;
+    }
+    Some((res, count + 1))
+}
+
+// utf8.char(...)
+fn utf8_char(state: &mut ExeState) -> Result<i32, LuaError> {
+    let mut s = Vec::new();
+    for i in 1..=state.get_top() {
+        let code = state.check_integer(i) as u64;
+        if code > MAXUTF as u64 {
+            panic!("bad argument #{i} to 'char' (value out of range)");
+        }
+        s.extend(utf8_encode(code as u32));
+    }
+    state.push(s);
+    Ok(1)
+}
+
+// utf8.codepoint(s [, i [, j [, lax]]])
+fn utf8_codepoint(state: &mut ExeState) -> Result<i32, LuaError> {
+    let s = state.check_string(1).to_vec();
+    let posi = posrelat(state.opt_integer(2, 1), s.len());
+    let pose = posrelat(state.opt_integer(3, posi), s.len());
+    let strict = !bool::from(&*state.arg(4));
+    if posi < 1 {
+        panic!("bad argument #2 to 'codepoint' (out of bounds)");
+    }
+    if pose > s.len() as i64 {
+        panic!("bad argument #3 to 'codepoint' (out of bounds)");
+    }
+    if posi > pose {
+        return Ok(0); // empty interval
+    }
+
+    let mut n = 0;
+    let mut i = posi as usize - 1;
+    while i < pose as usize {
+        let (code, len) = utf8_decode(&s[i..], strict).unwrap_or_else(||
+            panic!("invalid UTF-8 code at position {}", i + 1));
+        state.push(code as i64);
+        n += 1;
+        i += len;
+    }
+    Ok(n)
+}
+
+// utf8.len(s [, i [, j [, lax]]])
+// return the number of characters, or fail plus the position
+// of the first invalid byte
+fn utf8_len(state: &mut ExeState) -> Result<i32, LuaError> {
+    let s = state.check_string(1).to_vec();
+    let mut posi = posrelat(state.opt_integer(2, 1), s.len());
+    let mut posj = posrelat(state.opt_integer(3, -1), s.len());
+    let strict = !bool::from(&*state.arg(4));
+    if posi < 1 || posi - 1 > s.len() as i64 {
+        panic!("bad argument #2 to 'len' (initial position out of bounds)");
+    }
+    posi -= 1;
+    posj -= 1;
+    if posj >= s.len() as i64 {
+        panic!("bad argument #3 to 'len' (final position out of bounds)");
+    }
+
+    let mut n: i64 = 0;
+    while posi <= posj {
+        match utf8_decode(&s[posi as usize..], strict) {
+            Some((_, len)) => posi += len as i64,
+            None => {
+                state.push(());
+                state.push(posi + 1);
+                return Ok(2);
+            }
+        }
+        n += 1;
+    }
+    state.push(n);
+    Ok(1)
+}
+
+// utf8.offset(s, n [, i])
+// return the position (in bytes) where the n-th character
+// counting from position i starts
+fn utf8_offset(state: &mut ExeState) -> Result<i32, LuaError> {
+    let s = state.check_string(1).to_vec();
+    let mut n = state.check_integer(2);
+    let default = if n >= 0 { 1 } else { s.len() as i64 + 1 };
+    let posi = posrelat(state.opt_integer(3, default), s.len());
+    if posi < 1 || posi - 1 > s.len() as i64 {
+        panic!("bad argument #3 to 'offset' (position out of bounds)");
+    }
+
+    let mut posi = posi as usize - 1;
+    if n == 0 {
+        // find beginning of current byte sequence
+        while posi > 0 && is_cont(&s, posi) {
+            posi -= 1;
+        }
+    } else {
+        if is_cont(&s, posi) {
+            panic!("initial position is a continuation byte");
+        }
+        if n < 0 {
+            while n < 0 && posi > 0 { // move back
+                posi -= 1;
+                while posi > 0 && is_cont(&s, posi) {
+                    posi -= 1;
+                }
+                n += 1;
+            }
+        } else {
+            n -= 1; // do not move for 1st character
+            while n > 0 && posi < s.len() {
+                posi += 1;
+                while is_cont(&s, posi) {
+                    posi += 1;
+                }
+                n -= 1;
+            }
+        }
+    }
+    if n == 0 { // did it find given character?
+        state.push(posi as i64 + 1);
+    } else { // no such character
+        state.push(());
+    }
+    Ok(1)
+}
+
+// utf8.codes(s [, lax])
+fn utf8_codes(state: &mut ExeState) -> Result<i32, LuaError> {
+    let s = state.check_string(1);
+    if is_cont(s, 0) {
+        panic!("bad argument #1 to 'codes' (invalid UTF-8 code at position 1)");
+    }
+    let iter = if bool::from(&*state.arg(2)) { iter_codes_lax } else { iter_codes_strict };
+    state.push(Value::RustFunction(iter));
+    state.push(state.arg(1).clone());
+    state.push(0);
+    Ok(3)
+}
+
+fn iter_codes_strict(state: &mut ExeState) -> Result<i32, LuaError> {
+    iter_codes(state, true)
+}
+fn iter_codes_lax(state: &mut ExeState) -> Result<i32, LuaError> {
+    iter_codes(state, false)
+}
+
+// the iterator of generic-for, with the string and the position of
+// last character as control variable
+fn iter_codes(state: &mut ExeState, strict: bool) -> Result<i32, LuaError> {
+    let s = state.check_string(1);
+    let mut i = state.check_integer(2) as usize;
+    while is_cont(s, i) { // skip continuation bytes of last character
+        i += 1;
+    }
+    if i >= s.len() {
+        return Ok(0); // no more codepoints
+    }
+    let code = match utf8_decode(&s[i..], strict) {
+        Some((code, len)) if !is_cont(s, i + len) => code,
+        _ => panic!("invalid UTF-8 code at position {}", i + 1),
+    };
+    state.push(i as i64 + 1);
+    state.push(code as i64);
+    Ok(2)
+}
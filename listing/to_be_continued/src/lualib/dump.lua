@@ -0,0 +1,35 @@
+-- dump(v): string of value @v, with tables expanded recursively in
+-- the order of pairs(). The array entries are shown without keys.
+-- A table shown again inside itself is shown as "<cycle>".
+
+local function dump(v, seen)
+  if type(v) == "string" then
+    return '"' .. v .. '"'
+  elseif type(v) ~= "table" then
+    return tostring(v)
+  end
+
+  seen = seen or {}
+  if seen[v] then
+    return "<cycle>"
+  end
+  seen[v] = true
+
+  local s = ""
+  local n = 0
+  for k, x in pairs(v) do
+    if s ~= "" then
+      s = s .. ", "
+    end
+    if k == n + 1 then
+      n = k
+      s = s .. dump(x, seen)
+    else
+      s = s .. "[" .. dump(k, seen) .. "] = " .. dump(x, seen)
+    end
+  end
+  seen[v] = nil
+  return "{" .. s .. "}"
+end
+
+return dump
@@ -1,6 +1,5 @@
 use std::env;
-use std::fs::File;
-use std::io::BufReader;
+use std::process;
 
 mod value;
 mod bytecode;
@@ -8,15 +7,83 @@ mod lex;
 mod parse;
 mod vm;
 mod utils;
+mod ordered_map;
+mod packed;
+mod peephole;
+mod ir;
+mod lib_base;
+mod lib_package;
+mod lib_math;
+mod lib_io;
+mod lib_os;
+mod lib_utf8;
+
+use value::{Value, Table};
+use lib_package::Module;
+
+// Lua modules built into the interpreter, found by `require` after
+// `package.preload` and `package.path`
+const EMBEDDED_MODULES: &[(&str, &str)] = &[
+    ("dump", include_str!("lualib/dump.lua")),
+];
+
+fn search_embedded(name: &str) -> Result<(Module, String), String> {
+    match EMBEDDED_MODULES.iter().find(|(n, _)| *n == name) {
+        Some((_, code)) => Ok((Module::Source(code.as_bytes().to_vec()), format!(":embedded:{name}"))),
+        None => Err(format!("no embedded module '{name}'")),
+    }
+}
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        println!("Usage: {} script", args[0]);
+    let mut args: Vec<String> = env::args().collect();
+
+    // options before script
+    let mut peephole = false;
+    let mut optimize = false;
+    while args.len() > 1 && args[1].starts_with('-') {
+        match args.remove(1).as_str() {
+            "-p" => peephole = true, // enable peephole optimizer
+            "-O" => optimize = true, // enable IR optimizer
+            opt => {
+                eprintln!("{}: unrecognized option '{opt}'", args[0]);
+                process::exit(1);
+            }
+        }
+    }
+
+    if args.len() < 2 {
+        println!("Usage: {} [-p] [-O] script [args]", args[0]);
         return;
     }
-    let file = File::open(&args[1]).unwrap();
 
-    let proto = parse::load(BufReader::new(file));
-    vm::ExeState::new().execute(&proto, &Vec::new());
+    let mut state = vm::ExeState::new();
+    state.set_peephole(peephole);
+    state.set_optimize(optimize);
+    state.add_module_loader(search_embedded);
+
+    // global `arg` table: script name at index 0, interpreter name at
+    // index -1, and script arguments from index 1
+    let mut arg = Table::new(args.len() - 2, 2);
+    for (i, a) in args.iter().enumerate() {
+        arg.new_index(Value::Integer(i as i64 - 1), a.as_str().into());
+    }
+    state.set_global("arg", arg);
+
+    let chunk = match lib_base::load_file(&state, Some(&args[1]), b"bt", None) {
+        Ok(chunk) => chunk,
+        Err(msg) => {
+            eprintln!("{}: {msg}", args[0]);
+            process::exit(1);
+        }
+    };
+
+    // script arguments are passed as `...` too
+    let script_args: Vec<Value> = args[2..].iter().map(|a| a.as_str().into()).collect();
+    let result = state.call(chunk, &script_args);
+
+    // the Lua state is dropped here, so files are closed
+    drop(state);
+    if let Err(vm::LuaError::Exit(code)) = result {
+        process::exit(code);
+    }
 }
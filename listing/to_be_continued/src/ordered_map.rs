@@ -0,0 +1,136 @@
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::mem;
+use crate::utils::FastBuildHasher;
+
+// Hash map keeping the insertion order, used as the map part of Lua
+// table. The entries are stored in a Vec, and a HashMap maps keys to
+// their positions. So `next()` can find the entry following a key in
+// O(1), which is hard for HashMap. Besides the iteration order does
+// not depend on the hash seed, so it is always reproducible.
+//
+// Removed entries leave holes in the Vec, which are compacted when
+// there are too many.
+pub struct OrderedMap<K, V> {
+    indexes: HashMap<K, usize, FastBuildHasher>,
+    entries: Vec<Option<(K, V)>>,
+}
+
+impl<K: Hash + Eq + Clone, V> OrderedMap<K, V> {
+    pub fn with_capacity(n: usize) -> Self {
+        OrderedMap {
+            indexes: HashMap::with_capacity_and_hasher(n, Default::default()),
+            entries: Vec::with_capacity(n),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.indexes.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.indexes.is_empty()
+    }
+    pub fn capacity(&self) -> usize {
+        self.indexes.capacity()
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        let &i = self.indexes.get(key)?;
+        self.entries[i].as_ref().map(|(_, v)| v)
+    }
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        let &i = self.indexes.get(key)?;
+        self.entries[i].as_mut().map(|(_, v)| v)
+    }
+
+    // Get with a hint of the entry position, e.g. the inline cache of a
+    // byte code. The hint is checked by comparing the key, which is much
+    // cheaper than hashing for short strings, so it never gives wrong
+    // result even if the map has changed. The hint is updated on miss.
+    pub fn get_hinted(&self, key: &K, hint: &Cell<u32>) -> Option<&V> {
+        let i = self.find_hinted(key, hint)?;
+        self.entries[i].as_ref().map(|(_, v)| v)
+    }
+    pub fn get_mut_hinted(&mut self, key: &K, hint: &Cell<u32>) -> Option<&mut V> {
+        let i = self.find_hinted(key, hint)?;
+        self.entries[i].as_mut().map(|(_, v)| v)
+    }
+    fn find_hinted(&self, key: &K, hint: &Cell<u32>) -> Option<usize> {
+        match self.entries.get(hint.get() as usize) {
+            Some(Some((k, _))) if k == key => Some(hint.get() as usize),
+            _ => {
+                let &i = self.indexes.get(key)?;
+                hint.set(i as u32);
+                Some(i)
+            }
+        }
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.indexes.contains_key(key)
+    }
+
+    // new key is appended at the end, while existing key keeps its position
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        if let Some(v) = self.get_mut(&key) {
+            return Some(mem::replace(v, value));
+        }
+        self.indexes.insert(key.clone(), self.entries.len());
+        self.entries.push(Some((key, value)));
+        None
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let i = self.indexes.remove(key)?;
+        let (_, v) = self.entries[i].take().unwrap();
+        if self.entries.len() > self.indexes.len() * 2 + 8 {
+            self.compact();
+        }
+        Some(v)
+    }
+
+    pub fn retain(&mut self, mut f: impl FnMut(&K, &mut V) -> bool) {
+        for entry in self.entries.iter_mut() {
+            if let Some((k, v)) = entry {
+                if !f(k, v) {
+                    self.indexes.remove(k);
+                    *entry = None;
+                }
+            }
+        }
+        self.compact();
+    }
+
+    pub fn clear(&mut self) {
+        self.indexes.clear();
+        self.entries.clear();
+    }
+
+    pub fn shrink_to(&mut self, n: usize) {
+        self.indexes.shrink_to(n);
+        self.entries.shrink_to(n);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.entries.iter().flatten().map(|(k, v)| (k, v))
+    }
+
+    // Return the entries after @key in insertion order, or all entries if
+    // @key is None. Return None if @key is not present.
+    pub fn iter_after(&self, key: Option<&K>) -> Option<impl Iterator<Item = (&K, &V)>> {
+        let start = match key {
+            Some(key) => self.indexes.get(key)? + 1,
+            None => 0,
+        };
+        Some(self.entries[start..].iter().flatten().map(|(k, v)| (k, v)))
+    }
+
+    // remove the holes, and update positions of the moved entries
+    fn compact(&mut self) {
+        self.entries.retain(Option::is_some);
+        for (i, (k, _)) in self.entries.iter().flatten().enumerate() {
+            *self.indexes.get_mut(k).unwrap() = i;
+        }
+    }
+}
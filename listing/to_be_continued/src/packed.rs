@@ -0,0 +1,270 @@
+// Storage of values in the VM stack, the constants of functions and
+// the array part of tables.
+//
+// By default they are plain `Value`s, which take 16 bytes. With the
+// `nan-boxing` feature, they are packed into 8 bytes by NaN-boxing,
+// which saves half of the memory of these slots, e.g. for the tree
+// nodes in binary-trees.lua. `Value` is still used to operate on and
+// pass values around, and the map part of tables keeps it too.
+//
+// Since a packed slot is decoded on access, it can not be borrowed as
+// `&Value`. So the accessors, e.g. Table::index() and ExeState::arg(),
+// return ValueRef, which dereferences to `&Value` in both layouts.
+// Strings in slots are borrowed by slot_str(), since their bytes are
+// not in the decoded value but in the heap.
+
+#[cfg(not(feature = "nan-boxing"))]
+pub use plain::*;
+#[cfg(feature = "nan-boxing")]
+pub use boxed::*;
+
+#[cfg(not(feature = "nan-boxing"))]
+mod plain {
+    use std::ops::Deref;
+    use crate::value::Value;
+
+    pub type Slot = Value;
+
+    pub struct ValueRef<'a>(&'a Value);
+
+    impl<'a> From<&'a Value> for ValueRef<'a> {
+        fn from(v: &'a Value) -> Self {
+            ValueRef(v)
+        }
+    }
+
+    impl Deref for ValueRef<'_> {
+        type Target = Value;
+        fn deref(&self) -> &Value {
+            self.0
+        }
+    }
+
+    pub fn to_slot(v: Value) -> Slot {
+        v
+    }
+    pub fn from_slot(s: Slot) -> Value {
+        s
+    }
+    pub fn slot_ref(s: &Slot) -> ValueRef<'_> {
+        ValueRef(s)
+    }
+    pub fn slot_str(s: &Slot) -> Option<&[u8]> {
+        match s {
+            Value::ShortStr(_, _) | Value::MidStr(_) | Value::LongStr(_) => Some(s.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+// A float is stored as its bits. Other types are stored in the payload
+// of negative quiet NaN, which is not used by floats because all NaNs
+// with that prefix are converted into another negative NaN. The low 48
+// bits are the payload, and the 3 bits above are the tag:
+//
+//     float:  any bits, but not with the QNAN prefix
+//     others: 1111111111111 ttt pppp...(48 bits)
+//
+// Pointers fit in 48 bits on the 64-bit platforms we run on. Integers
+// out of 48 bits, short strings and the other types are boxed in an
+// `Rc<Value>`, so they are converted back transparently.
+#[cfg(feature = "nan-boxing")]
+mod boxed {
+    use std::cell::RefCell;
+    use std::fmt;
+    use std::hash::{Hash, Hasher};
+    use std::mem::{self, ManuallyDrop};
+    use std::ops::Deref;
+    use std::rc::Rc;
+    use crate::value::{Value, Table, MidStr, LongStr};
+    use crate::vm::{ExeState, LuaClosure, LuaError};
+
+    const QNAN: u64 = 0xfff8_0000_0000_0000; // sign, exponent and quiet bit
+    const NEG_NAN: u64 = 0xfff4_0000_0000_0000; // replaces the NaNs with QNAN prefix
+    const TAG_SHIFT: u32 = 48;
+    const PAYLOAD: u64 = (1 << TAG_SHIFT) - 1;
+
+    const TAG_NIL: u64 = 0; // and booleans, with payload 2|b
+    const TAG_RUSTFN: u64 = 1;
+    const TAG_INT: u64 = 2;
+    const TAG_TABLE: u64 = 3;
+    const TAG_CLOSURE: u64 = 4;
+    const TAG_MIDSTR: u64 = 5;
+    const TAG_LONGSTR: u64 = 6;
+    const TAG_BOXED: u64 = 7;
+
+    pub struct PackedValue(u64);
+
+    const _: () = assert!(mem::size_of::<PackedValue>() == 8);
+
+    pub type Slot = PackedValue;
+
+    fn pack_ptr<T>(p: *const T) -> u64 {
+        let p = p as u64;
+        assert!(p & !PAYLOAD == 0, "pointer out of 48 bits");
+        p
+    }
+
+    impl From<Value> for PackedValue {
+        fn from(v: Value) -> Self {
+            let (tag, payload) = match v {
+                Value::Float(f) => {
+                    let bits = f.to_bits();
+                    return PackedValue(if bits & QNAN == QNAN { NEG_NAN } else { bits });
+                }
+                Value::Nil => (TAG_NIL, 0),
+                Value::Boolean(b) => (TAG_NIL, 2 | b as u64),
+                Value::RustFunction(f) => (TAG_RUSTFN, pack_ptr(f as *const ())),
+                Value::Integer(i) if (i << 16) >> 16 == i => (TAG_INT, i as u64 & PAYLOAD),
+                Value::Table(t) => (TAG_TABLE, pack_ptr(Rc::into_raw(t))),
+                Value::LuaClosure(c) => (TAG_CLOSURE, pack_ptr(Rc::into_raw(c))),
+                Value::MidStr(s) => (TAG_MIDSTR, pack_ptr(Rc::into_raw(s))),
+                Value::LongStr(s) => (TAG_LONGSTR, pack_ptr(Rc::into_raw(s))),
+                v => (TAG_BOXED, pack_ptr(Rc::into_raw(Rc::new(v)))),
+            };
+            PackedValue(QNAN | (tag << TAG_SHIFT) | payload)
+        }
+    }
+
+    impl PackedValue {
+        // None for float
+        fn tag(&self) -> Option<u64> {
+            (self.0 & QNAN == QNAN).then_some((self.0 >> TAG_SHIFT) & 7)
+        }
+
+        pub fn get(&self) -> ValueRef<'_> {
+            let Some(tag) = self.tag() else {
+                return ValueRef::Decoded(ManuallyDrop::new(Value::Float(f64::from_bits(self.0))));
+            };
+            let p = self.0 & PAYLOAD;
+
+            // The decoded Rc does not own the reference count, so it is
+            // wrapped in ManuallyDrop and never dropped.
+            let v = unsafe {
+                match tag {
+                    TAG_NIL if p == 0 => Value::Nil,
+                    TAG_NIL => Value::Boolean(p & 1 != 0),
+                    TAG_RUSTFN => Value::RustFunction(mem::transmute::<*const (), fn (&mut ExeState) -> Result<i32, LuaError>>(p as *const ())),
+                    TAG_INT => Value::Integer(((self.0 << 16) as i64) >> 16),
+                    TAG_TABLE => Value::Table(Rc::from_raw(p as *const RefCell<Table>)),
+                    TAG_CLOSURE => Value::LuaClosure(Rc::from_raw(p as *const LuaClosure)),
+                    TAG_MIDSTR => Value::MidStr(Rc::from_raw(p as *const MidStr)),
+                    TAG_LONGSTR => Value::LongStr(Rc::from_raw(p as *const LongStr)),
+                    _ => return ValueRef::Borrowed(&*(p as *const Value)),
+                }
+            };
+            ValueRef::Decoded(ManuallyDrop::new(v))
+        }
+    }
+
+    impl Clone for PackedValue {
+        fn clone(&self) -> Self {
+            let p = self.0 & PAYLOAD;
+            unsafe {
+                match self.tag() {
+                    Some(TAG_TABLE) => Rc::increment_strong_count(p as *const RefCell<Table>),
+                    Some(TAG_CLOSURE) => Rc::increment_strong_count(p as *const LuaClosure),
+                    Some(TAG_MIDSTR) => Rc::increment_strong_count(p as *const MidStr),
+                    Some(TAG_LONGSTR) => Rc::increment_strong_count(p as *const LongStr),
+                    Some(TAG_BOXED) => Rc::increment_strong_count(p as *const Value),
+                    _ => (),
+                }
+            }
+            PackedValue(self.0)
+        }
+    }
+
+    impl Drop for PackedValue {
+        fn drop(&mut self) {
+            let p = self.0 & PAYLOAD;
+            unsafe {
+                match self.tag() {
+                    Some(TAG_TABLE) => Rc::decrement_strong_count(p as *const RefCell<Table>),
+                    Some(TAG_CLOSURE) => Rc::decrement_strong_count(p as *const LuaClosure),
+                    Some(TAG_MIDSTR) => Rc::decrement_strong_count(p as *const MidStr),
+                    Some(TAG_LONGSTR) => Rc::decrement_strong_count(p as *const LongStr),
+                    Some(TAG_BOXED) => Rc::decrement_strong_count(p as *const Value),
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    // same semantics with Value
+    impl PartialEq for PackedValue {
+        fn eq(&self, other: &Self) -> bool {
+            *self.get() == *other.get()
+        }
+    }
+    impl PartialEq<Value> for PackedValue {
+        fn eq(&self, other: &Value) -> bool {
+            *self.get() == *other
+        }
+    }
+    impl Eq for PackedValue {}
+
+    impl Hash for PackedValue {
+        fn hash<H: Hasher>(&self, state: &mut H) {
+            self.get().hash(state)
+        }
+    }
+
+    impl fmt::Debug for PackedValue {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            self.get().fmt(f)
+        }
+    }
+
+    pub enum ValueRef<'a> {
+        Borrowed(&'a Value),
+        Decoded(ManuallyDrop<Value>), // borrowing from a PackedValue
+    }
+
+    impl<'a> From<&'a Value> for ValueRef<'a> {
+        fn from(v: &'a Value) -> Self {
+            ValueRef::Borrowed(v)
+        }
+    }
+
+    impl Deref for ValueRef<'_> {
+        type Target = Value;
+        fn deref(&self) -> &Value {
+            match self {
+                ValueRef::Borrowed(v) => v,
+                ValueRef::Decoded(v) => v,
+            }
+        }
+    }
+
+    pub fn to_slot(v: Value) -> Slot {
+        PackedValue::from(v)
+    }
+    pub fn from_slot(s: Slot) -> Value {
+        match s.get() {
+            // take over the reference count of @s
+            ValueRef::Decoded(v) => {
+                mem::forget(s);
+                ManuallyDrop::into_inner(v)
+            }
+            ValueRef::Borrowed(v) => v.clone(),
+        }
+    }
+    pub fn slot_ref(s: &Slot) -> ValueRef<'_> {
+        s.get()
+    }
+    pub fn slot_str(s: &Slot) -> Option<&[u8]> {
+        let p = s.0 & PAYLOAD;
+        let v = unsafe {
+            match s.tag() {
+                Some(TAG_MIDSTR) => return Some((*(p as *const MidStr)).as_bytes()),
+                Some(TAG_LONGSTR) => return Some((*(p as *const LongStr)).as_bytes()),
+                Some(TAG_BOXED) => &*(p as *const Value),
+                _ => return None,
+            }
+        };
+        match v {
+            Value::ShortStr(_, _) => Some(v.as_ref()),
+            _ => None,
+        }
+    }
+}
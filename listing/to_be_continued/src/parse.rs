@@ -1,17 +1,23 @@
 use std::rc::Rc;
+use std::cell::Cell;
 use std::io::Read;
 use std::cmp::Ordering;
+use std::panic::{self, AssertUnwindSafe};
 use crate::lex::{Lex, Token};
 use crate::bytecode::ByteCode;
-use crate::value::Value;
-use crate::utils::ftoi;
+use crate::peephole::{self, Peephole};
+use crate::ir;
+use crate::value::{Value, Table};
+use crate::packed::{Slot, ValueRef, to_slot, slot_ref};
+use crate::utils::{ftoi, int2fb, fb2int, idiv_int, idiv_float, mod_int, mod_float, shift_left, shift_right};
 
 type FnBc2u8 = fn(u8, u8) -> ByteCode;
 type FnBc3u8 = fn(u8, u8, u8) -> ByteCode;
 type FnBcBool = fn(u8, u8, bool) -> ByteCode;
+type FnBcTest = fn(u8, i16) -> ByteCode;
 
 // expression description, inner layer between source code and byte code
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 enum ExpDesc {
     // constants
     Nil,
@@ -39,6 +45,10 @@ enum ExpDesc {
     // arithmetic operators
     UnaryOp(FnBc2u8, usize), // (opcode, operand)
     BinaryOp(FnBc3u8, usize, usize), // (opcode, left-operand, right-operand)
+    Concat(usize, usize), // (first-operand, number of operands)
+
+    // table constructor with constant entries only, see table_constructor()
+    TableConst(usize), // template table in constants
 
     // binaray logical operators: 'and', 'or'
     Test(Box<ExpDesc>, Vec<usize>, Vec<usize>), // (condition, true-list, false-list)
@@ -73,22 +83,81 @@ pub enum UpIndex {
 pub struct FuncProto {
     pub has_varargs: bool,
     pub nparam: usize,
-    pub constants: Vec<Value>,
+    pub constants: Vec<Slot>, // see packed.rs
     pub upindexes: Vec<UpIndex>,
     pub byte_codes: Vec<ByteCode>,
+    pub caches: Vec<Cell<u32>>, // inline caches of byte codes by pc, see Table::index_hinted()
+}
+
+impl FuncProto {
+    // add the constant if not exists, and return its index
+    pub fn add_const(&mut self, c: Value) -> usize {
+        self.constants.iter().position(|v| slot_ref(v).same(&c)).unwrap_or_else(|| {
+            self.constants.push(to_slot(c));
+            self.constants.len() - 1
+        })
+    }
+    pub fn constant(&self, i: usize) -> ValueRef<'_> {
+        slot_ref(&self.constants[i])
+    }
+}
+
+// attribute of local variable
+#[derive(Debug, PartialEq)]
+enum LocalAttr {
+    Regular,
+    Const, // `<const>`, read-only
+    CompileConst(ExpDesc), // `<const>` with constant value, occupying no register
+    Close, // `<close>`, read-only, and its `__close` is called when out of scope
+}
+
+#[derive(Debug)]
+struct LocalVar {
+    name: String,
+    referred: bool, // referred as upvalue
+    attr: LocalAttr,
+}
+
+impl LocalVar {
+    fn new(name: String, attr: LocalAttr) -> Self {
+        LocalVar { name, referred: false, attr }
+    }
+    fn has_reg(&self) -> bool {
+        !matches!(self.attr, LocalAttr::CompileConst(_))
+    }
+    // need ByteCode::Close when out of scope
+    fn need_close(&self) -> bool {
+        self.referred || self.attr == LocalAttr::Close
+    }
 }
 
 // level of inner functions, used for matching upvalue
 #[derive(Debug, Default)]
 struct Level {
-    locals: Vec<(String, bool)>, // (name, referred-as-upvalue)
+    locals: Vec<LocalVar>,
     upvalues: Vec<(String, UpIndex)>,
 }
 
+impl Level {
+    // register of the @i-th local variable, skipping the compile-time
+    // constants before it
+    fn local_reg(&self, i: usize) -> usize {
+        self.locals[..i].iter().filter(|v| v.has_reg()).count()
+    }
+
+    // if the local variable in register @reg is `<const>` or `<close>`
+    fn is_readonly_reg(&self, reg: usize) -> bool {
+        self.locals.iter().filter(|v| v.has_reg()).nth(reg)
+            .is_some_and(|v| matches!(v.attr, LocalAttr::Const | LocalAttr::Close))
+    }
+}
+
 #[derive(Debug)]
 struct ParseContext<R: Read> {
     levels: Vec<Level>,
     lex: Lex<R>,
+    peephole: bool,
+    optimize: bool,
 }
 
 #[derive(Debug)]
@@ -100,8 +169,10 @@ struct ParseProto<'a, R: Read> {
     sp: usize,
     break_blocks: Vec<Vec<usize>>,
     continue_blocks: Vec<Vec<(usize, usize)>>,
+    loop_nvars: Vec<usize>, // number of locals at beginning of loop blocks
     gotos: Vec<GotoLabel>,
     labels: Vec<GotoLabel>,
+    long_tests: Vec<(usize, usize)>, // TestAndJump/TestOrJump overflowing i16, and targets
     ctx: &'a mut ParseContext<R>,
 }
 
@@ -136,7 +207,7 @@ impl<'a, R: Read> ParseProto<'a, R> {
         let ilabel = self.labels.len();
         loop {
             // reset sp before each statement
-            self.sp = self.local_num();
+            self.sp = self.reg_num();
 
             match self.ctx.lex.next() {
                 Token::SemiColon => (),
@@ -148,7 +219,7 @@ impl<'a, R: Read> ParseProto<'a, R> {
 
                     // functioncall and var-assignment both begin with
                     // `prefixexp` which begins with `Name` or `(`.
-                    let desc = self.prefixexp(t);
+                    let (name, desc) = self.prefixexp_named(t);
                     if let ExpDesc::Call(ifunc, narg_plus) = desc {
                         // prefixexp() matches the whole functioncall statement.
                         let code = ByteCode::Call(ifunc as u8, narg_plus as u8, 0);
@@ -156,6 +227,7 @@ impl<'a, R: Read> ParseProto<'a, R> {
                     } else {
                         // prefixexp() matches only the first variable, so we
                         // continue the statement
+                        self.check_readonly(name, &desc);
                         self.assignment(desc);
                     }
                 }
@@ -187,17 +259,37 @@ impl<'a, R: Read> ParseProto<'a, R> {
     //   local attnamelist [`=` explist]
     //   attnamelist ::=  Name attrib {`,` Name attrib}
     fn local_variables(&mut self) {
-        // variable names
-        let mut vars = vec![self.read_name()];
+        // variable names and attributes
+        let mut vars = vec![self.local_attname()];
         while self.ctx.lex.peek() == &Token::Comma {
             self.ctx.lex.next();
-            vars.push(self.read_name());
+            vars.push(self.local_attname());
+        }
+        let iclose = vars.iter().position(|(_, attr)| attr == &LocalAttr::Close);
+        if vars.iter().filter(|(_, attr)| attr == &LocalAttr::Close).count() > 1 {
+            panic!("multiple to-be-closed variables in local list");
         }
 
         if self.ctx.lex.peek() == &Token::Assign {
             // explist
             self.ctx.lex.next();
-            self.explist_want(vars.len());
+            let want = vars.len();
+            let (nexp, last_exp) = self.explist();
+
+            // The last variable is a compile-time constant, if it is
+            // `<const>` and initialized by a constant expression directly.
+            // The front expressions have been discharged into registers,
+            // so they can not be folded.
+            if nexp + 1 == want && vars[want - 1].1 == LocalAttr::Const
+                    && is_const_exp(&last_exp) {
+                let (name, _) = vars.pop().unwrap();
+                for (var, attr) in vars.into_iter() {
+                    self.local_new(var, attr);
+                }
+                self.local_new(name, LocalAttr::CompileConst(last_exp));
+                return;
+            }
+            self.explist_adjust(nexp, last_exp, want);
         } else {
             // no exp, load nils
             let code = ByteCode::LoadNil(self.sp as u8, vars.len() as u8);
@@ -205,8 +297,34 @@ impl<'a, R: Read> ParseProto<'a, R> {
         }
 
         // append vars into self.locals after evaluating explist
-        for var in vars.into_iter() {
-            self.local_new(var);
+        let nvar = self.local_num();
+        for (var, attr) in vars.into_iter() {
+            self.local_new(var, attr);
+        }
+
+        // mark the variable to be closed, after it is assigned
+        if let Some(i) = iclose {
+            let reg = self.local_reg(nvar + i);
+            self.fp.byte_codes.push(ByteCode::Tbc(reg as u8));
+        }
+    }
+
+    // BNF:
+    //   Name attrib
+    //   attrib ::= [`<` Name `>`]
+    fn local_attname(&mut self) -> (String, LocalAttr) {
+        let name = self.read_name();
+        if self.ctx.lex.peek() != &Token::Less {
+            return (name, LocalAttr::Regular);
+        }
+        self.ctx.lex.next();
+        let attr = self.read_name();
+        self.ctx.lex.expect(Token::Greater);
+
+        match attr.as_str() {
+            "const" => (name, LocalAttr::Const),
+            "close" => (name, LocalAttr::Close),
+            _ => panic!("unknown attribute '{attr}'"),
         }
     }
 
@@ -219,7 +337,7 @@ impl<'a, R: Read> ParseProto<'a, R> {
 
         // create `name` local variable before parsing funcbody(),
         // so the function can be called in body as recursion.
-        self.local_new(name);
+        self.local_new(name, LocalAttr::Regular);
 
         let f = self.funcbody(false);
         self.discharge(self.sp, f);
@@ -230,21 +348,26 @@ impl<'a, R: Read> ParseProto<'a, R> {
     //   funcname = Name {`.` Name} [`:` Name]
     fn function_stat(&mut self) {
         let name = self.read_name();
-        let mut desc = self.simple_name(name);
+        let mut desc = self.simple_name(name.clone());
+        if !matches!(self.ctx.lex.peek(), Token::Dot | Token::Colon) {
+            self.check_readonly(Some(name), &desc);
+        }
 
         let with_self = loop {
             match self.ctx.lex.peek() {
                 Token::Dot => { // `.` Name
                     self.ctx.lex.next();
                     let name = self.read_name();
+                    let ikey = self.add_const(name);
                     let t = self.discharge_any(desc);
-                    desc = ExpDesc::IndexField(t, self.add_const(name));
+                    desc = self.index_field(t, ikey);
                 }
                 Token::Colon => { // `:` Name
                     self.ctx.lex.next();
                     let name = self.read_name();
+                    let ikey = self.add_const(name);
                     let t = self.discharge_any(desc);
-                    desc = ExpDesc::IndexField(t, self.add_const(name));
+                    desc = self.index_field(t, ikey);
 
                     break true;
                 }
@@ -312,7 +435,9 @@ impl<'a, R: Read> ParseProto<'a, R> {
             match self.ctx.lex.next() {
                 Token::Comma => { // more variable
                     let token = self.ctx.lex.next();
-                    vars.push(self.prefixexp(token));
+                    let (name, var) = self.prefixexp_named(token);
+                    self.check_readonly(name, &var);
+                    vars.push(var);
                 }
                 Token::Assign => break,
                 t => panic!("invalid assign {t:?}"),
@@ -369,7 +494,7 @@ impl<'a, R: Read> ParseProto<'a, R> {
 
         let iend = self.fp.byte_codes.len() - 1;
         for i in jmp_ends.into_iter() {
-            self.fp.byte_codes[i] = ByteCode::Jump((iend - i) as i16);
+            self.fp.byte_codes[i] = jump_code((iend - i) as isize);
         }
     }
 
@@ -412,7 +537,7 @@ impl<'a, R: Read> ParseProto<'a, R> {
 
         // jump back
         let iend = self.fp.byte_codes.len();
-        self.fp.byte_codes.push(ByteCode::Jump(-((iend - istart) as i16) - 1));
+        self.fp.byte_codes.push(jump_code(istart as isize - iend as isize - 1));
 
         self.pop_loop_block(istart);
 
@@ -433,7 +558,18 @@ impl<'a, R: Read> ParseProto<'a, R> {
 
         let condition = self.exp();
         let false_list = self.test_or_jump(condition);
-        self.fix_test_list_to(false_list, istart);
+        if self.ctx.levels.last().unwrap().locals[nvar..].iter().any(LocalVar::need_close) {
+            // close internal local variables before jumping back
+            self.fp.byte_codes.push(ByteCode::Jump(0));
+            let iexit = self.fp.byte_codes.len() - 1;
+            self.fix_test_list(false_list);
+            self.local_check_close(nvar);
+            let iback = self.fp.byte_codes.len();
+            self.fp.byte_codes.push(jump_code(istart as isize - iback as isize - 1));
+            self.fp.byte_codes[iexit] = jump_code((iback - iexit) as isize);
+        } else {
+            self.fix_test_list_to(false_list, istart);
+        }
 
         self.pop_loop_block(iend);
 
@@ -470,9 +606,9 @@ impl<'a, R: Read> ParseProto<'a, R> {
 
         // create 3 local variables: the first is iterator,
         // and the other two to keep stack positions.
-        self.local_new(name);
-        self.local_new(String::from(""));
-        self.local_new(String::from(""));
+        self.local_new(name, LocalAttr::Regular);
+        self.local_new(String::from(""), LocalAttr::Regular);
+        self.local_new(String::from(""), LocalAttr::Regular);
 
         self.ctx.lex.expect(Token::Do);
 
@@ -491,8 +627,9 @@ impl<'a, R: Read> ParseProto<'a, R> {
 
         // ByteCode::ForLoop, and fix ByteCode::ForPrepare above
         let d = self.fp.byte_codes.len() - iprepare;
-        self.fp.byte_codes.push(ByteCode::ForLoop(iname as u8, d as u16));
-        self.fp.byte_codes[iprepare] = ByteCode::ForPrepare(iname as u8, d as u16);
+        let d = u16::try_from(d).unwrap_or_else(|_| panic!("control structure too long"));
+        self.fp.byte_codes.push(ByteCode::ForLoop(iname as u8, d));
+        self.fp.byte_codes[iprepare] = ByteCode::ForPrepare(iname as u8, d);
 
         self.pop_loop_block(self.fp.byte_codes.len() - 1);
     }
@@ -517,11 +654,11 @@ impl<'a, R: Read> ParseProto<'a, R> {
         self.explist_want(3);
 
         let nvar = vars.len();
-        self.local_new(String::from("")); // iterator function
-        self.local_new(String::from("")); // immutable state
-        self.local_new(String::from("")); // control variable
+        self.local_new(String::from(""), LocalAttr::Regular); // iterator function
+        self.local_new(String::from(""), LocalAttr::Regular); // immutable state
+        self.local_new(String::from(""), LocalAttr::Regular); // control variable
         for var in vars.into_iter() {
-            self.local_new(var);
+            self.local_new(var, LocalAttr::Regular);
         }
 
         self.ctx.lex.expect(Token::Do);
@@ -541,21 +678,24 @@ impl<'a, R: Read> ParseProto<'a, R> {
         // ByteCode::ForCallLoop
         // call the iter function and check the control variable
         let d = self.fp.byte_codes.len() - ijump;
-        self.fp.byte_codes[ijump] = ByteCode::Jump(d as i16 - 1);
+        self.fp.byte_codes[ijump] = jump_code(d as isize - 1);
         if let Ok(d) = u8::try_from(d) {
-            self.fp.byte_codes.push(ByteCode::ForCallLoop(iter as u8, nvar as u8, d as u8));
+            self.fp.byte_codes.push(ByteCode::ForCallLoop(iter as u8, nvar as u8, d));
         } else {
             self.fp.byte_codes.push(ByteCode::ForCallLoop(iter as u8, nvar as u8, 0));
-            self.fp.byte_codes.push(ByteCode::Jump(-(d as i16) - 1));
+            self.fp.byte_codes.push(jump_code(-(d as isize) - 1));
         }
 
         self.pop_loop_block(self.fp.byte_codes.len() - 1);
     }
 
     fn break_stat(&mut self) {
-        let Some(breaks) = self.break_blocks.last_mut() else {
+        let Some(&nvar) = self.loop_nvars.last() else {
             panic!("break outside loop");
         };
+        self.local_check_close(nvar);
+
+        let breaks = self.break_blocks.last_mut().unwrap();
         self.fp.byte_codes.push(ByteCode::Jump(0));
         breaks.push(self.fp.byte_codes.len() - 1);
     }
@@ -569,10 +709,13 @@ impl<'a, R: Read> ParseProto<'a, R> {
             return false;
         }
 
-        let nvar = self.local_num();
-        let Some(continues) = self.continue_blocks.last_mut() else {
+        let Some(&loop_nvar) = self.loop_nvars.last() else {
             panic!("continue outside loop");
         };
+        self.local_check_close(loop_nvar);
+
+        let nvar = self.local_num();
+        let continues = self.continue_blocks.last_mut().unwrap();
         self.fp.byte_codes.push(ByteCode::Jump(0));
         continues.push((self.fp.byte_codes.len() - 1, nvar));
         true
@@ -582,13 +725,16 @@ impl<'a, R: Read> ParseProto<'a, R> {
     fn push_loop_block(&mut self) {
         self.break_blocks.push(Vec::new());
         self.continue_blocks.push(Vec::new());
+        self.loop_nvars.push(self.local_num());
     }
     // after leaving loop block, fix `break` and `continue` Jumps
     fn pop_loop_block(&mut self, icontinue: usize) {
+        self.loop_nvars.pop();
+
         // breaks
         let iend = self.fp.byte_codes.len() - 1;
         for i in self.break_blocks.pop().unwrap().into_iter() {
-            self.fp.byte_codes[i] = ByteCode::Jump((iend - i) as i16);
+            self.fp.byte_codes[i] = jump_code((iend - i) as isize);
         }
 
         // continues
@@ -597,7 +743,7 @@ impl<'a, R: Read> ParseProto<'a, R> {
             if i_nvar < end_nvar {
                 panic!("continue jump into local scope");
             }
-            self.fp.byte_codes[i] = ByteCode::Jump((icontinue as isize - i as isize) as i16 - 1);
+            self.fp.byte_codes[i] = jump_code(icontinue as isize - i as isize - 1);
         }
     }
 
@@ -636,6 +782,13 @@ impl<'a, R: Read> ParseProto<'a, R> {
         let icode = self.fp.byte_codes.len();
         let nvar = self.local_num();
 
+        // Gotos jumping out of blocks skip the Close of the blocks, so
+        // generate Close here for them. The dropped local variables are
+        // unknown now, so do it anyway.
+        if self.gotos[igoto..].iter().any(|g| g.name == name && g.nvar > nvar) {
+            self.fp.byte_codes.push(ByteCode::Close(self.reg_num() as u8));
+        }
+
         // match previous gotos
         let mut no_dsts = Vec::new();
         for goto in self.gotos.drain(igoto..) {
@@ -644,7 +797,7 @@ impl<'a, R: Read> ParseProto<'a, R> {
                     panic!("goto jump into scope {}", goto.name);
                 }
                 let dist = icode - goto.icode;
-                self.fp.byte_codes[goto.icode] = ByteCode::Jump(dist as i16 - 1);
+                self.fp.byte_codes[goto.icode] = jump_code(dist as isize - 1);
             } else {
                 // no matched label
                 no_dsts.push(goto);
@@ -664,9 +817,10 @@ impl<'a, R: Read> ParseProto<'a, R> {
         // match previous label
         if let Some(label) = self.labels.iter().rev().find(|l|l.name == name) {
             // find label
-            let dist = self.fp.byte_codes.len() - label.icode;
-            self.local_check_close(label.nvar);
-            self.fp.byte_codes.push(ByteCode::Jump(-(dist as i16) - 1));
+            let (icode, nvar) = (label.icode, label.nvar);
+            self.local_check_close(nvar);
+            let dist = self.fp.byte_codes.len() - icode;
+            self.fp.byte_codes.push(jump_code(-(dist as isize) - 1));
 
         } else {
             // not find label, push a fake byte code and save the goto
@@ -709,8 +863,11 @@ impl<'a, R: Read> ParseProto<'a, R> {
                     // stack top for continuity
                     ByteCode::Return(i as u8, 1)
 
-                } else if let (0, &ExpDesc::Call(func, narg_plus)) = (nexp, &last_exp) {
-                    // tail call
+                } else if let (0, &ExpDesc::Call(func, narg_plus), false) =
+                        (nexp, &last_exp, self.inside_tbc()) {
+                    // tail call, which is not allowed inside the scope of
+                    // to-be-closed variables, because they must be closed
+                    // after the call
                     ByteCode::TailCall(func as u8, narg_plus as u8)
 
                 } else if self.discharge_try_expand(last_exp, 0) {
@@ -768,12 +925,7 @@ impl<'a, R: Read> ParseProto<'a, R> {
 
     // add the value to constants
     fn add_const(&mut self, c: impl Into<Value>) -> usize {
-        let c = c.into();
-        let constants = &mut self.fp.constants;
-        constants.iter().position(|v| v.same(&c)).unwrap_or_else(|| {
-            constants.push(c);
-            constants.len() - 1
-        })
+        self.fp.add_const(c.into())
     }
 
     // explist ::= exp {`,` exp}
@@ -798,6 +950,11 @@ impl<'a, R: Read> ParseProto<'a, R> {
 
     fn explist_want(&mut self, want: usize) {
         let (nexp, last_exp) = self.explist();
+        self.explist_adjust(nexp, last_exp, want);
+    }
+
+    // adjust the result of explist() to @want values
+    fn explist_adjust(&mut self, nexp: usize, last_exp: ExpDesc, want: usize) {
         match (nexp + 1).cmp(&want) {
             Ordering::Equal => {
                 self.discharge(self.sp, last_exp);
@@ -870,6 +1027,10 @@ impl<'a, R: Read> ParseProto<'a, R> {
             }
 
             let binop = self.ctx.lex.next();
+            if binop == Token::Concat {
+                desc = self.concat_exp(desc, right_pri);
+                continue;
+            }
             desc = self.preprocess_binop_left(desc, &binop);
             let right_desc = self.exp_limit(right_pri);
             desc = self.process_binop(binop, desc, right_desc);
@@ -936,14 +1097,25 @@ impl<'a, R: Read> ParseProto<'a, R> {
                     desc = match (desc, key) {
                         // special case: upvalue-table and string-key
                         (ExpDesc::Upvalue(itable), ExpDesc::String(key)) => {
-                            ExpDesc::IndexUpField(itable, self.add_const(key))
+                            let ikey = self.add_const(key);
+                            self.index_up_field(itable, ikey)
                         }
                         // normal case
                         (table, key) => {
-                            let itable = self.discharge_if_need(sp0, table);
+                            // Discharge the key before the table, because the
+                            // key may be pending in registers from sp0, e.g.
+                            // the operands of Concat or the function of Call.
+                            let key = match key {
+                                ExpDesc::String(_) => key,
+                                ExpDesc::Integer(i) if u8::try_from(i).is_ok() => key,
+                                key => ExpDesc::Local(self.discharge_any(key)),
+                            };
+                            let itable = self.discharge_if_need(self.sp, table);
                             match key {
-                                ExpDesc::String(key) =>
-                                    ExpDesc::IndexField(itable, self.add_const(key)),
+                                ExpDesc::String(key) => {
+                                    let ikey = self.add_const(key);
+                                    self.index_field(itable, ikey)
+                                }
                                 ExpDesc::Integer(i) if u8::try_from(i).is_ok() =>
                                     ExpDesc::IndexInt(itable, u8::try_from(i).unwrap()),
                                 _ =>
@@ -958,10 +1130,10 @@ impl<'a, R: Read> ParseProto<'a, R> {
                     let ikey = self.add_const(name);
 
                     desc = if let ExpDesc::Upvalue(itable) = desc {
-                        ExpDesc::IndexUpField(itable, ikey)
+                        self.index_up_field(itable, ikey)
                     } else {
                         let itable = self.discharge_if_need(sp0, desc);
-                        ExpDesc::IndexField(itable, ikey)
+                        self.index_field(itable, ikey)
                     };
                 }
                 Token::Colon => { // :Name args
@@ -973,8 +1145,17 @@ impl<'a, R: Read> ParseProto<'a, R> {
                     // GetFieldSelf:
                     //   stack[sp0] := itable[ikey]  # load function
                     //   stack[sp0+1] := itable      # load table as first argument
-                    self.fp.byte_codes.push(
-                        ByteCode::GetFieldSelf(sp0 as u8, itable as u8, ikey as u8));
+                    if let Ok(ikey) = u8::try_from(ikey) {
+                        check_reg(sp0 + 1);
+                        self.fp.byte_codes.push(
+                            ByteCode::GetFieldSelf(sp0 as u8, itable as u8, ikey));
+                    } else {
+                        // load the key into register if it overflows u8
+                        self.discharge(sp0 + 1, ExpDesc::Local(itable));
+                        self.load_const(sp0 + 2, ikey);
+                        let code = ByteCode::GetTable(sp0 as u8, sp0 as u8 + 1, sp0 as u8 + 2);
+                        self.fp.byte_codes.push(code);
+                    }
 
                     // discharge following arguments begin at sp0+2
                     self.sp = sp0 + 2;
@@ -994,25 +1175,87 @@ impl<'a, R: Read> ParseProto<'a, R> {
         self.ctx.levels.last().unwrap().locals.len()
     }
 
-    fn local_new(&mut self, name: String) {
-        self.ctx.levels.last_mut().unwrap().locals.push((name, false));
+    // number of registers occupied by local variables
+    fn reg_num(&self) -> usize {
+        self.local_reg(self.local_num())
+    }
+    fn local_reg(&self, i: usize) -> usize {
+        self.ctx.levels.last().unwrap().local_reg(i)
+    }
+
+    fn local_new(&mut self, name: String, attr: LocalAttr) {
+        if self.reg_num() >= 200 {
+            panic!("too many local variables (limit is 200)");
+        }
+        self.ctx.levels.last_mut().unwrap().locals.push(LocalVar::new(name, attr));
     }
 
     fn local_expire(&mut self, from: usize) {
+        let reg = self.local_reg(from);
+
         // drop locals
         let mut vars = self.ctx.levels.last_mut().unwrap().locals.drain(from..);
 
-        // generate Close if any dropped local variable referred as upvalue
-        if vars.any(|v| v.1) {
-            self.fp.byte_codes.push(ByteCode::Close(from as u8));
+        // generate Close if any dropped local variable referred as
+        // upvalue or to-be-closed
+        if vars.any(|v| v.need_close()) {
+            self.fp.byte_codes.push(ByteCode::Close(reg as u8));
         }
     }
 
     // generate Close if any local variable in [from..] referred as upvalue
+    // or to-be-closed
     fn local_check_close(&mut self, from: usize) {
         let mut vars = self.ctx.levels.last().unwrap().locals[from..].iter();
-        if vars.any(|v| v.1) {
-            self.fp.byte_codes.push(ByteCode::Close(from as u8));
+        if vars.any(LocalVar::need_close) {
+            self.fp.byte_codes.push(ByteCode::Close(self.local_reg(from) as u8));
+        }
+    }
+
+    fn inside_tbc(&self) -> bool {
+        let locals = &self.ctx.levels.last().unwrap().locals;
+        locals.iter().any(|v| v.attr == LocalAttr::Close)
+    }
+
+    // Parse prefixexp, and return the name too if it is a single Name,
+    // which maybe a variable to be assigned.
+    fn prefixexp_named(&mut self, ahead: Token) -> (Option<String>, ExpDesc) {
+        let name = match &ahead {
+            Token::Name(name) => Some(name.clone()),
+            _ => None,
+        };
+        let desc = self.prefixexp(ahead);
+        match desc {
+            ExpDesc::Local(_) | ExpDesc::Upvalue(_) => (name, desc),
+            _ if is_const_exp(&desc) => (name, desc), // compile-time constant
+            _ => (None, desc),
+        }
+    }
+
+    // raise error if assigning to `<const>` variable @name, which is
+    // parsed into @var
+    fn check_readonly(&self, name: Option<String>, var: &ExpDesc) {
+        let Some(name) = name else { return; };
+        let levels = &self.ctx.levels;
+        let readonly = match *var {
+            ExpDesc::Local(reg) => levels.last().unwrap().is_readonly_reg(reg),
+            ExpDesc::Upvalue(mut i) => {
+                // follow the upvalue to the local variable in upper levels
+                let mut depth = levels.len() - 1;
+                loop {
+                    match levels[depth].upvalues[i].1 {
+                        UpIndex::Local(reg) => break levels[depth - 1].is_readonly_reg(reg),
+                        UpIndex::Upvalue(up) => {
+                            i = up;
+                            depth -= 1;
+                        }
+                    }
+                }
+            }
+            _ => is_const_exp(var), // compile-time constant
+        };
+        if readonly {
+            panic!("attempt to assign to const variable '{name}'");
         }
     }
 
@@ -1022,9 +1265,12 @@ impl<'a, R: Read> ParseProto<'a, R> {
 
         // search from locals and upvalues in current level
         let level = level_iter.next().unwrap();
-        if let Some(i) = level.locals.iter().rposition(|v| v.0 == name) {
+        if let Some(i) = level.locals.iter().rposition(|v| v.name == name) {
             // search reversely, so new variable covers old one with same name
-            return ExpDesc::Local(i);
+            return match &level.locals[i].attr {
+                LocalAttr::CompileConst(desc) => desc.clone(),
+                _ => ExpDesc::Local(level.local_reg(i)),
+            };
         }
         if let Some(i) = level.upvalues.iter().position(|v| v.0 == name) {
             return ExpDesc::Upvalue(i);
@@ -1032,9 +1278,14 @@ impl<'a, R: Read> ParseProto<'a, R> {
 
         // search in upper levels
         for (depth, level) in level_iter.enumerate() {
-            if let Some(i) = level.locals.iter().rposition(|v| v.0 == name) {
-                level.locals[i].1 = true; // mark it referred as upvalue
-                return self.create_upvalue(name, UpIndex::Local(i), depth);
+            if let Some(i) = level.locals.iter().rposition(|v| v.name == name) {
+                // compile-time constant needs no upvalue
+                if let LocalAttr::CompileConst(desc) = &level.locals[i].attr {
+                    return desc.clone();
+                }
+                level.locals[i].referred = true; // mark it referred as upvalue
+                let reg = level.local_reg(i);
+                return self.create_upvalue(name, UpIndex::Local(reg), depth);
             }
             if let Some(i) = level.upvalues.iter().position(|v| v.0 == name) {
                 return self.create_upvalue(name, UpIndex::Upvalue(i), depth);
@@ -1044,8 +1295,8 @@ impl<'a, R: Read> ParseProto<'a, R> {
         // not matched as local or upvalue, so global variable, by _ENV[name]
         let iname = self.add_const(name);
         match self.simple_name("_ENV".into()) {
-            ExpDesc::Local(i) => ExpDesc::IndexField(i, iname),
-            ExpDesc::Upvalue(i) => ExpDesc::IndexUpField(i, iname),
+            ExpDesc::Local(i) => self.index_field(i, iname),
+            ExpDesc::Upvalue(i) => self.index_up_field(i, iname),
             _ => panic!("no here"), // because "_ENV" must exist!
         }
     }
@@ -1056,12 +1307,14 @@ impl<'a, R: Read> ParseProto<'a, R> {
 
         // create upvalue in middle levels, if any
         for Level { upvalues, .. } in levels[last-depth .. last].iter_mut() {
+            check_upvalues(upvalues);
             upvalues.push((name.clone(), upidx));
             upidx = UpIndex::Upvalue(upvalues.len() - 1);
         }
 
         // create upvalue in current level
         let upvalues = &mut levels[last].upvalues;
+        check_upvalues(upvalues);
         upvalues.push((name, upidx));
         ExpDesc::Upvalue(upvalues.len() - 1)
     }
@@ -1069,9 +1322,9 @@ impl<'a, R: Read> ParseProto<'a, R> {
     // unop `-`
     fn unop_neg(&mut self) -> ExpDesc {
         match self.exp_unop() {
-            ExpDesc::Integer(i) => ExpDesc::Integer(-i),
+            ExpDesc::Integer(i) => ExpDesc::Integer(i.wrapping_neg()),
             ExpDesc::Float(f) => ExpDesc::Float(-f),
-            ExpDesc::Nil | ExpDesc::Boolean(_) | ExpDesc::String(_) => panic!("invalid - operator"),
+            ExpDesc::Nil | ExpDesc::Boolean(_) => panic!("invalid - operator"),
             desc => ExpDesc::UnaryOp(ByteCode::Neg, self.discharge_any(desc))
         }
     }
@@ -1089,7 +1342,11 @@ impl<'a, R: Read> ParseProto<'a, R> {
     fn unop_bitnot(&mut self) -> ExpDesc {
         match self.exp_unop() {
             ExpDesc::Integer(i) => ExpDesc::Integer(!i),
-            ExpDesc::Nil | ExpDesc::Boolean(_) | ExpDesc::Float(_) | ExpDesc::String(_) => panic!("invalid ~ operator"),
+            desc@ExpDesc::Float(f) => match ftoi(f) {
+                Some(i) => ExpDesc::Integer(!i),
+                None => ExpDesc::UnaryOp(ByteCode::BitNot, self.discharge_any(desc)), // error at runtime
+            }
+            ExpDesc::Nil | ExpDesc::Boolean(_) => panic!("invalid ~ operator"),
             desc => ExpDesc::UnaryOp(ByteCode::BitNot, self.discharge_any(desc)),
         }
     }
@@ -1102,6 +1359,45 @@ impl<'a, R: Read> ParseProto<'a, R> {
         }
     }
 
+    // `..` is right associative, so `a .. b .. c` is parsed as
+    // `a .. (b .. c)`. Put all operands into consecutive registers,
+    // and concatenate them by one Concat byte code.
+    fn concat_exp(&mut self, left: ExpDesc, right_pri: i32) -> ExpDesc {
+        let first = if let &ExpDesc::Call(ifunc, _) = &left {
+            ifunc
+        } else {
+            self.sp
+        };
+
+        // Do not discharge constants, for folding. But reserve the
+        // register for them.
+        let left = if matches!(left, ExpDesc::Integer(_) | ExpDesc::Float(_) | ExpDesc::String(_)) {
+            self.sp += 1;
+            left
+        } else {
+            self.discharge(first, left);
+            ExpDesc::Local(first)
+        };
+
+        let right = self.exp_limit(right_pri);
+        if let Some(r) = fold_const(&Token::Concat, &left, &right) {
+            self.sp = first;
+            return r;
+        }
+
+        self.discharge(first, left);
+        let nright = match right {
+            // the right operand is concatenation too, merge it, unless
+            // the number of operands overflows u8
+            ExpDesc::Concat(rfirst, n) if rfirst == first + 1 && n < u8::MAX as usize => n,
+            _ => {
+                self.discharge(first + 1, right);
+                1
+            }
+        };
+        ExpDesc::Concat(first, nright + 1)
+    }
+
     fn preprocess_binop_left(&mut self, left: ExpDesc, binop: &Token) -> ExpDesc {
         // Generate TestOrJump/TestAndJump before reading right operand,
         // because of short-circuit evaluation.
@@ -1150,13 +1446,6 @@ impl<'a, R: Read> ParseProto<'a, R> {
             Token::Less => self.do_compare(left, right, ByteCode::Less, ByteCode::LessInt, ByteCode::LessConst),
             Token::Greater => self.do_compare(left, right, ByteCode::Greater, ByteCode::GreaterInt, ByteCode::GreaterConst),
 
-            Token::Concat => {
-                // TODO support multiple operants
-                let left = self.discharge_any(left);
-                let right = self.discharge_any(right);
-                ExpDesc::BinaryOp(ByteCode::Concat, left, right)
-            }
-
             Token::And | Token::Or => {
                 // left operand has been made into ExpDesc::Test in preprocess_binop_left()
                 let ExpDesc::Test(_, mut left_true_list, mut left_false_list) = left else {
@@ -1197,9 +1486,9 @@ impl<'a, R: Read> ParseProto<'a, R> {
                 if let Ok(i) = u8::try_from(i) {
                     (opi, i as usize)
                 } else {
-                    (opk, self.add_const(i))
+                    self.const_op(opr, opk, i)
                 }
-            ExpDesc::Float(f) => (opk, self.add_const(f)),
+            ExpDesc::Float(f) => self.const_op(opr, opk, f),
             _ => (opr, self.discharge_any(right)),
         };
 
@@ -1223,10 +1512,10 @@ impl<'a, R: Read> ParseProto<'a, R> {
                 if let Ok(i) = u8::try_from(i) {
                     (opi, i as usize)
                 } else {
-                    (opk, self.add_const(i))
+                    self.const_op(opr, opk, i)
                 }
-            ExpDesc::Float(f) => (opk, self.add_const(f)),
-            ExpDesc::String(s) => (opk, self.add_const(s)),
+            ExpDesc::Float(f) => self.const_op(opr, opk, f),
+            ExpDesc::String(s) => self.const_op(opr, opk, s),
             _ => (opr, self.discharge_any(right)),
         };
 
@@ -1311,14 +1600,22 @@ impl<'a, R: Read> ParseProto<'a, R> {
     // fix TestAndJump/TestOrJump list to jump to $to
     fn fix_test_list_to(&mut self, list: Vec<usize>, to: usize) {
         for i in list.into_iter() {
-            let jmp = (to as isize - i as isize - 1) as i16;
-            let code = match self.fp.byte_codes[i] {
-                ByteCode::Jump(0) => ByteCode::Jump(jmp),
-                ByteCode::TestOrJump(icondition, 0) => ByteCode::TestOrJump(icondition, jmp),
-                ByteCode::TestAndJump(icondition, 0) => ByteCode::TestAndJump(icondition, jmp),
+            match self.fp.byte_codes[i] {
+                ByteCode::Jump(0) => self.fp.byte_codes[i] = jump_code(to as isize - i as isize - 1),
+                ByteCode::TestOrJump(icondition, 0) => self.fix_test_jump(i, ByteCode::TestOrJump, icondition, to),
+                ByteCode::TestAndJump(icondition, 0) => self.fix_test_jump(i, ByteCode::TestAndJump, icondition, to),
                 _ => panic!("invalid Test"),
-            };
-            self.fp.byte_codes[i] = code;
+            }
+        }
+    }
+
+    // fix TestAndJump/TestOrJump at @i to jump to @to. If the offset
+    // overflows i16, it is expanded after the whole function is parsed,
+    // see Peephole::expand_long_tests().
+    fn fix_test_jump(&mut self, i: usize, op: FnBcTest, icondition: u8, to: usize) {
+        match i16::try_from(to as isize - i as isize - 1) {
+            Ok(jmp) => self.fp.byte_codes[i] = op(icondition, jmp),
+            Err(_) => self.long_tests.push((i, to)),
         }
     }
 
@@ -1326,26 +1623,50 @@ impl<'a, R: Read> ParseProto<'a, R> {
     fn fix_test_set_list(&mut self, list: Vec<usize>, dst: usize) {
         let here = self.fp.byte_codes.len();
         let dst = dst as u8;
+        let mut pads: Vec<(usize, FnBcTest, u8)> = Vec::new();
         for i in list.into_iter() {
             let jmp = here - i - 1; // should not be negative
             let code = match self.fp.byte_codes[i] {
-                ByteCode::Jump(0) => ByteCode::Jump(jmp as i16),
+                ByteCode::Jump(0) => jump_code(jmp as isize),
                 ByteCode::TestOrJump(icondition, 0) =>
                     if icondition == dst {
-                        ByteCode::TestOrJump(icondition, jmp as i16)
+                        self.fix_test_jump(i, ByteCode::TestOrJump, icondition, here);
+                        continue;
+                    } else if let Ok(jmp) = u8::try_from(jmp) {
+                        ByteCode::TestOrSetJump(dst, icondition, jmp)
                     } else {
-                        ByteCode::TestOrSetJump(dst as u8, icondition, jmp as u8)
+                        pads.push((i, ByteCode::TestOrJump, icondition));
+                        continue;
                     }
                 ByteCode::TestAndJump(icondition, 0) =>
                     if icondition == dst {
-                        ByteCode::TestAndJump(icondition, jmp as i16)
+                        self.fix_test_jump(i, ByteCode::TestAndJump, icondition, here);
+                        continue;
+                    } else if let Ok(jmp) = u8::try_from(jmp) {
+                        ByteCode::TestAndSetJump(dst, icondition, jmp)
                     } else {
-                        ByteCode::TestAndSetJump(dst as u8, icondition, jmp as u8)
+                        pads.push((i, ByteCode::TestAndJump, icondition));
+                        continue;
                     }
                 _ => panic!("invalid Test"),
             };
             self.fp.byte_codes[i] = code;
         }
+
+        // The jumps are too far for TestAndSetJump/TestOrSetJump, so
+        // they jump to pads here, which set @dst and then jump to the end.
+        // The normal path skips the pads.
+        if !pads.is_empty() {
+            self.fp.byte_codes.push(ByteCode::Jump(pads.len() as i16 * 2));
+            let mut ends = Vec::new();
+            for (i, op, icondition) in pads {
+                self.fix_test_jump(i, op, icondition, self.fp.byte_codes.len());
+                self.fp.byte_codes.push(ByteCode::Move(dst, icondition));
+                self.fp.byte_codes.push(ByteCode::Jump(0));
+                ends.push(self.fp.byte_codes.len() - 1);
+            }
+            self.fix_test_list(ends);
+        }
     }
 
     // args ::= `(` [explist] `)` | tableconstructor | LiteralString
@@ -1367,7 +1688,8 @@ impl<'a, R: Read> ParseProto<'a, R> {
                 }
             }
             Token::CurlyL => {
-                self.table_constructor();
+                let desc = self.table_constructor();
+                self.discharge(ifunc+1, desc);
                 Some(1)
             }
             Token::String(s) => {
@@ -1406,6 +1728,7 @@ impl<'a, R: Read> ParseProto<'a, R> {
 
     // discharge @desc into @dst, and update self.sp=dst+1
     fn discharge(&mut self, dst: usize, desc: ExpDesc) {
+        check_reg(dst);
         let code = match desc {
             ExpDesc::Nil => ByteCode::LoadNil(dst as u8, 1),
             ExpDesc::Boolean(b) => ByteCode::LoadBool(dst as u8, b),
@@ -1413,10 +1736,17 @@ impl<'a, R: Read> ParseProto<'a, R> {
                 if let Ok(i) = i16::try_from(i) {
                     ByteCode::LoadInt(dst as u8, i)
                 } else {
-                    ByteCode::LoadConst(dst as u8, self.add_const(i) as u16)
+                    let iconst = self.add_const(i);
+                    return self.load_const(dst, iconst);
                 }
-            ExpDesc::Float(f) => ByteCode::LoadConst(dst as u8, self.add_const(f) as u16),
-            ExpDesc::String(s) => ByteCode::LoadConst(dst as u8, self.add_const(s) as u16),
+            ExpDesc::Float(f) => {
+                let iconst = self.add_const(f);
+                return self.load_const(dst, iconst);
+            }
+            ExpDesc::String(s) => {
+                let iconst = self.add_const(s);
+                return self.load_const(dst, iconst);
+            }
             ExpDesc::Local(src) =>
                 if dst != src {
                     ByteCode::Move(dst as u8, src as u8)
@@ -1429,11 +1759,16 @@ impl<'a, R: Read> ParseProto<'a, R> {
             ExpDesc::IndexInt(itable, ikey) => ByteCode::GetInt(dst as u8, itable as u8, ikey),
             ExpDesc::IndexUpField(itable, ikey) => ByteCode::GetUpField(dst as u8, itable as u8, ikey as u8),
             ExpDesc::VarArgs => ByteCode::VarArgs(dst as u8, 1),
-            ExpDesc::Function(f) => ByteCode::LoadConst(dst as u8, f as u16),
-            ExpDesc::Closure(f) => ByteCode::Closure(dst as u8, f as u16),
+            ExpDesc::Function(f) => return self.load_const(dst, f),
+            ExpDesc::Closure(f) => match u16::try_from(f) {
+                Ok(f) => ByteCode::Closure(dst as u8, f),
+                Err(_) => panic!("too many constants"),
+            }
             ExpDesc::Call(ifunc, narg_plus) => ByteCode::CallSet(dst as u8, ifunc as u8, narg_plus as u8),
             ExpDesc::UnaryOp(op, i) => op(dst as u8, i as u8),
             ExpDesc::BinaryOp(op, left, right) => op(dst as u8, left as u8, right as u8),
+            ExpDesc::Concat(first, n) => ByteCode::Concat(dst as u8, first as u8, n as u8),
+            ExpDesc::TableConst(i) => ByteCode::NewTableConst(dst as u8, i as u16),
             ExpDesc::Test(condition, true_list, false_list) => {
                 // fix TestSet list after discharging
                 self.discharge(dst, *condition);
@@ -1460,17 +1795,74 @@ impl<'a, R: Read> ParseProto<'a, R> {
     // for constant types, add @desc to constants;
     // otherwise, discharge @desc into stack
     fn discharge_const(&mut self, desc: ExpDesc) -> ConstStack {
-        match desc {
+        let iconst = match desc {
             // add const
-            ExpDesc::Nil => ConstStack::Const(self.add_const(())),
-            ExpDesc::Boolean(b) => ConstStack::Const(self.add_const(b)),
-            ExpDesc::Integer(i) => ConstStack::Const(self.add_const(i)),
-            ExpDesc::Float(f) => ConstStack::Const(self.add_const(f)),
-            ExpDesc::String(s) => ConstStack::Const(self.add_const(s)),
-            ExpDesc::Function(f) => ConstStack::Const(f),
+            ExpDesc::Nil => self.add_const(()),
+            ExpDesc::Boolean(b) => self.add_const(b),
+            ExpDesc::Integer(i) => self.add_const(i),
+            ExpDesc::Float(f) => self.add_const(f),
+            ExpDesc::String(s) => self.add_const(s),
+            ExpDesc::Function(f) => f,
 
             // discharge to stack
-            _ => ConstStack::Stack(self.discharge_any(desc)),
+            _ => return ConstStack::Stack(self.discharge_any(desc)),
+        };
+        self.const_operand(iconst)
+    }
+
+    // Constants are u8 operands in most byte codes. If @iconst
+    // overflows u8, load it into stack.
+    fn const_operand(&mut self, iconst: usize) -> ConstStack {
+        if iconst <= u8::MAX as usize {
+            ConstStack::Const(iconst)
+        } else {
+            let dst = self.sp;
+            self.load_const(dst, iconst);
+            ConstStack::Stack(dst)
+        }
+    }
+
+    // choose the constant-operand version @opk, or the stack-operand
+    // version @opr if the constant is loaded into stack
+    fn const_op<T>(&mut self, opr: T, opk: T, c: impl Into<Value>) -> (T, usize) {
+        let iconst = self.add_const(c);
+        match self.const_operand(iconst) {
+            ConstStack::Const(i) => (opk, i),
+            ConstStack::Stack(i) => (opr, i),
+        }
+    }
+
+    // load constant into @dst, by LoadConstX and ExtraArg if @iconst
+    // overflows u16
+    fn load_const(&mut self, dst: usize, iconst: usize) {
+        check_reg(dst);
+        if let Ok(i) = u16::try_from(iconst) {
+            self.fp.byte_codes.push(ByteCode::LoadConst(dst as u8, i));
+        } else {
+            let Ok(high) = u16::try_from(iconst >> 16) else {
+                panic!("too many constants");
+            };
+            self.fp.byte_codes.push(ByteCode::LoadConstX(dst as u8, (iconst & 0xffff) as u16));
+            self.fp.byte_codes.push(ByteCode::ExtraArg(high));
+        }
+        self.sp = dst + 1;
+    }
+
+    // `t.k` where the table is in stack, and key is constant
+    fn index_field(&mut self, itable: usize, ikey: usize) -> ExpDesc {
+        match self.const_operand(ikey) {
+            ConstStack::Const(ikey) => ExpDesc::IndexField(itable, ikey),
+            ConstStack::Stack(ikey) => ExpDesc::Index(itable, ikey),
+        }
+    }
+    // `t.k` where the table is upvalue, and key is constant
+    fn index_up_field(&mut self, itable: usize, ikey: usize) -> ExpDesc {
+        match self.const_operand(ikey) {
+            ConstStack::Const(ikey) => ExpDesc::IndexUpField(itable, ikey),
+            ConstStack::Stack(ikey) => {
+                let itable = self.discharge_any(ExpDesc::Upvalue(itable));
+                ExpDesc::Index(itable, ikey)
+            }
         }
     }
 
@@ -1505,31 +1897,51 @@ impl<'a, R: Read> ParseProto<'a, R> {
 
     fn table_constructor(&mut self) -> ExpDesc {
         let table = self.sp;
+        check_reg(table);
         self.sp += 1;
 
         let inew = self.fp.byte_codes.len();
         self.fp.byte_codes.push(ByteCode::NewTable(table as u8, 0, 0));
 
         enum TableEntry {
-            Map((FnBc3u8, FnBc3u8, usize)),
+            Map((FnBc3u8, FnBc3u8, usize), Option<Value>), // with constant key
             Array(ExpDesc),
         }
 
-        // record the last array entry and do not discharge it immediately,
-        // because it may be expanded as varargs or function call.
+        // record the last array entry and its register, and do not discharge
+        // it immediately, because it may be expanded as varargs or function call.
         let mut last_array_entry = None;
 
+        // Record the entries while they are all constants, and then the
+        // table is built at parse time as a template, see table_template().
+        // The byte codes and constants for the entries are dropped then.
+        let nconst = self.fp.constants.len();
+        let mut const_entries = Some(Vec::new());
+
         let mut narray: usize = 0;
         let mut nmap: usize = 0;
         loop {
+            if self.ctx.lex.peek() == &Token::CurlyR { // `}`
+                self.ctx.lex.next();
+                break;
+            }
+
+            // discharge the last array entry before parsing the next
+            // entry, since it is not the last one
+            if let Some((last, slot)) = last_array_entry.take() {
+                self.discharge(slot, last);
+
+                narray += 1;
+                if narray % 50 == 0 { // reset the array members every 50
+                    self.set_list(table, 50, narray / 50 - 1);
+                    self.sp = table + 1;
+                }
+            }
+
             let sp0 = self.sp;
 
             // parse entry of map or array?
             let entry = match self.ctx.lex.peek() {
-                Token::CurlyR => { // `}`
-                    self.ctx.lex.next();
-                    break;
-                }
                 Token::SqurL => { // `[` exp `]` `=` exp
                     self.ctx.lex.next();
 
@@ -1537,11 +1949,17 @@ impl<'a, R: Read> ParseProto<'a, R> {
                     self.ctx.lex.expect(Token::SqurR); // `]`
                     self.ctx.lex.expect(Token::Assign); // `=`
 
-                    TableEntry::Map(match key {
+                    // table can not be key in template, which is shared
+                    let key_value = if is_const_exp(&key) {
+                        self.const_value(&key)
+                    } else {
+                        None
+                    };
+
+                    let key: (FnBc3u8, FnBc3u8, usize) = match key {
                         ExpDesc::Local(i) =>
                             (ByteCode::SetTable, ByteCode::SetTableConst, i),
-                        ExpDesc::String(s) =>
-                            (ByteCode::SetField, ByteCode::SetFieldConst, self.add_const(s)),
+                        ExpDesc::String(s) => self.field_key(s),
                         ExpDesc::Integer(i) if u8::try_from(i).is_ok() =>
                             (ByteCode::SetInt, ByteCode::SetIntConst, i as usize),
                         ExpDesc::Nil =>
@@ -1549,13 +1967,15 @@ impl<'a, R: Read> ParseProto<'a, R> {
                         ExpDesc::Float(f) if f.is_nan() =>
                             panic!("NaN can not be table key"),
                         _ => (ByteCode::SetTable, ByteCode::SetTableConst, self.discharge_any(key)),
-                    })
+                    };
+                    TableEntry::Map(key, key_value)
                 }
                 Token::Name(_) => {
                     let name = self.read_name();
                     if self.ctx.lex.peek() == &Token::Assign { // Name `=` exp
                         self.ctx.lex.next();
-                        TableEntry::Map((ByteCode::SetField, ByteCode::SetFieldConst, self.add_const(name)))
+                        let key_value = Value::from(name.as_str());
+                        TableEntry::Map(self.field_key(name), Some(key_value))
                     } else { // Name
                         TableEntry::Array(self.exp_with_ahead(Token::Name(name)))
                     }
@@ -1567,8 +1987,12 @@ impl<'a, R: Read> ParseProto<'a, R> {
 
             // insert the entry into table
             match entry {
-                TableEntry::Map((op, opk, key)) => {
+                TableEntry::Map((op, opk, key), key_value) => {
                     let value = self.exp();
+                    match (&mut const_entries, key_value, self.const_value(&value)) {
+                        (Some(entries), Some(k), Some(v)) => entries.push((Some(k), v)),
+                        _ => const_entries = None,
+                    }
                     let code = match self.discharge_const(value) {
                         ConstStack::Const(i) => opk(table as u8, key as u8, i as u8),
                         ConstStack::Stack(i) => op(table as u8, key as u8, i as u8),
@@ -1579,15 +2003,11 @@ impl<'a, R: Read> ParseProto<'a, R> {
                     self.sp = sp0;
                 }
                 TableEntry::Array(desc) => {
-                    if let Some(last) = last_array_entry.replace(desc) {
-                        self.discharge(sp0, last);
-
-                        narray += 1;
-                        if narray % 50 == 0 { // reset the array members every 50
-                            self.fp.byte_codes.push(ByteCode::SetList(table as u8, 50));
-                            self.sp = table + 1;
-                        }
+                    match (&mut const_entries, self.const_value(&desc)) {
+                        (Some(entries), Some(v)) => entries.push((None, v)),
+                        _ => const_entries = None,
                     }
+                    last_array_entry = Some((desc, sp0));
                 }
             }
 
@@ -1599,26 +2019,83 @@ impl<'a, R: Read> ParseProto<'a, R> {
             }
         }
 
-        if let Some(last) = last_array_entry {
+        let nbatch = narray / 50;
+        if let Some((last, slot)) = last_array_entry {
+            self.sp = slot;
             let num = if self.discharge_try_expand(last, 0) {
                 // do not update @narray
                 0 // 0 is special, means all following values in stack
             } else {
+                // not self.sp, which is not updated if @last is at @slot
+                // already, e.g. a nested table constructor
                 narray += 1;
-                (self.sp - (table + 1)) as u8
+                (slot - table) as u8
             };
-            self.fp.byte_codes.push(ByteCode::SetList(table as u8, num));
+            self.set_list(table, num, nbatch);
+
+        } else if self.sp > table + 1 {
+            // the last entry is map entry, but the previous array
+            // entries are still in stack
+            self.set_list(table, (self.sp - (table + 1)) as u8, nbatch);
         }
 
         // reset narray and nmap
         self.fp.byte_codes[inew] = ByteCode::NewTable(table as u8,
-            u8::try_from(narray).unwrap_or(255),
-            u8::try_from(nmap).unwrap_or(255));
+            int2fb(narray), int2fb(nmap));
+
+        // all entries are constants, replace the byte codes by the template
+        if let Some(entries) = const_entries {
+            if !entries.is_empty() && nconst <= u16::MAX as usize {
+                self.fp.byte_codes.truncate(inew);
+                self.fp.constants.truncate(nconst);
+                let iconst = self.add_const(table_template(entries, narray, nmap));
+                self.sp = table;
+                return ExpDesc::TableConst(iconst);
+            }
+        }
 
         self.sp = table + 1;
         ExpDesc::Local(table)
     }
 
+    // SetList of @n values following @table in stack, which are stored
+    // after the @nbatch batches of 50 flushed before. The ExtraArg
+    // follows if @nbatch overflows u8, as LoadConstX does.
+    fn set_list(&mut self, table: usize, n: u8, nbatch: usize) {
+        if nbatch < u8::MAX as usize {
+            self.fp.byte_codes.push(ByteCode::SetList(table as u8, n, nbatch as u8));
+        } else {
+            let Ok(nbatch) = u16::try_from(nbatch) else {
+                panic!("table constructor too long");
+            };
+            self.fp.byte_codes.push(ByteCode::SetList(table as u8, n, u8::MAX));
+            self.fp.byte_codes.push(ByteCode::ExtraArg(nbatch));
+        }
+    }
+
+    // value of constant expression, used in table template
+    fn const_value(&self, desc: &ExpDesc) -> Option<Value> {
+        match desc {
+            ExpDesc::Nil => Some(Value::Nil),
+            &ExpDesc::Boolean(b) => Some(Value::Boolean(b)),
+            &ExpDesc::Integer(i) => Some(Value::Integer(i)),
+            &ExpDesc::Float(f) => Some(Value::Float(f)),
+            ExpDesc::String(s) => Some(s.as_slice().into()),
+            &ExpDesc::TableConst(i) => Some(self.fp.constant(i).clone()),
+            _ => None,
+        }
+    }
+
+    // SetField with constant key, or SetTable if the key is loaded
+    // into stack
+    fn field_key(&mut self, key: impl Into<Value>) -> (FnBc3u8, FnBc3u8, usize) {
+        let ikey = self.add_const(key);
+        match self.const_operand(ikey) {
+            ConstStack::Const(i) => (ByteCode::SetField, ByteCode::SetFieldConst, i),
+            ConstStack::Stack(i) => (ByteCode::SetTable, ByteCode::SetTableConst, i),
+        }
+    }
+
     fn read_name(&mut self) -> String {
         if let Token::Name(name) = self.ctx.lex.next() {
             name
@@ -1628,12 +2105,37 @@ impl<'a, R: Read> ParseProto<'a, R> {
     }
 }
 
-pub fn load(input: impl Read) -> FuncProto {
+// The main chunk is in a virtual outer level which has `_ENV` as its
+// only local variable, so `_ENV` becomes the first upvalue of the
+// main chunk, which is set by VM when loading.
+//
+// Errors are raised by panic!() in parsing. Catch them and return
+// the message, just like the official implementation by longjmp().
+pub fn load(input: impl Read, peephole: bool, optimize: bool) -> Result<FuncProto, String> {
     let mut ctx = ParseContext {
         lex: Lex::new(input),
-        levels: Default::default(),
+        peephole,
+        optimize,
+        levels: vec![Level {
+            locals: vec![LocalVar { name: "_ENV".into(), referred: true, attr: LocalAttr::Regular }],
+            upvalues: Vec::new(),
+        }],
     };
-    chunk(&mut ctx, false, vec!["_ENV".into()], Token::Eos) // XXX has_varargs->true
+
+    // do not print the message by the default panic hook
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| ()));
+    let result = panic::catch_unwind(AssertUnwindSafe(||
+        chunk(&mut ctx, true, Vec::new(), Token::Eos)));
+    panic::set_hook(hook);
+
+    result.map_err(|e| match e.downcast::<String>() {
+        Ok(msg) => *msg,
+        Err(e) => match e.downcast::<&str>() {
+            Ok(msg) => msg.to_string(),
+            Err(_) => String::from("unknown error"),
+        }
+    })
 }
 
 fn chunk(ctx: &mut ParseContext<impl Read>, has_varargs: bool, params: Vec<String>, end_token: Token) -> FuncProto {
@@ -1645,7 +2147,7 @@ fn chunk(ctx: &mut ParseContext<impl Read>, has_varargs: bool, params: Vec<Strin
     };
 
     ctx.levels.push(Level {
-        locals: params.into_iter().map(|p|(p, false)).collect(),
+        locals: params.into_iter().map(|p| LocalVar::new(p, LocalAttr::Regular)).collect(),
         upvalues: Vec::new(),
     });
 
@@ -1653,8 +2155,10 @@ fn chunk(ctx: &mut ParseContext<impl Read>, has_varargs: bool, params: Vec<Strin
         sp: 0,
         break_blocks: Vec::new(),
         continue_blocks: Vec::new(),
+        loop_nvars: Vec::new(),
         gotos: Vec::new(),
         labels: Vec::new(),
+        long_tests: Vec::new(),
 
         fp,
         ctx,
@@ -1671,13 +2175,31 @@ fn chunk(ctx: &mut ParseContext<impl Read>, has_varargs: bool, params: Vec<Strin
     }
 
     // clear
-    let ParseProto { mut fp, ctx, ..} = proto;
+    let ParseProto { mut fp, ctx, long_tests, ..} = proto;
 
     let level = ctx.levels.pop().unwrap();
+    let env = level.upvalues.iter().position(|(name, _)| name == "_ENV");
     fp.upindexes = level.upvalues.into_iter().map(|u| u.1).collect();
 
     fp.byte_codes.push(ByteCode::Return0);
 
+    if !long_tests.is_empty() {
+        let mut ph = Peephole::new(&fp.byte_codes);
+        for (pc, to) in long_tests {
+            ph.targets[pc] = Some(to);
+        }
+        ph.expand_long_tests();
+        fp.byte_codes = ph.encode().unwrap_or_else(|| panic!("control structure too long"));
+    }
+
+    if ctx.optimize {
+        ir::optimize(&mut fp, env);
+    }
+    if ctx.peephole {
+        peephole::optimize(&mut fp);
+    }
+    fp.caches = vec![Cell::new(0); fp.byte_codes.len()];
+
     println!("constants: {:?}", &fp.constants);
     println!("upindexes: {:?}", &fp.upindexes);
     println!("byte_codes:");
@@ -1711,13 +2233,71 @@ fn is_block_end(t: &Token) -> bool {
     matches!(t, Token::End | Token::Elseif | Token::Else | Token::Until | Token::Eos)
 }
 
+// registers are u8 operands in byte codes
+fn check_reg(reg: usize) {
+    if reg > u8::MAX as usize {
+        panic!("function or expression needs too many registers");
+    }
+}
+
+fn check_upvalues(upvalues: &[(String, UpIndex)]) {
+    if upvalues.len() >= 255 {
+        panic!("too many upvalues (limit is 255)");
+    }
+}
+
+// Jump, or LongJump if @jmp overflows i16
+pub fn jump_code(jmp: isize) -> ByteCode {
+    if let Ok(jmp) = i16::try_from(jmp) {
+        return ByteCode::Jump(jmp);
+    }
+    let Ok(high) = i8::try_from(jmp >> 16) else {
+        panic!("control structure too long");
+    };
+    ByteCode::LongJump(high, (jmp & 0xffff) as u16)
+}
+
+// Build the table by the constant entries, in the same way as the
+// byte codes do: map entries are set one by one, while array entries
+// are stored by SetList right after every 50, and at the end.
+fn table_template(entries: Vec<(Option<Value>, Value)>, narray: usize, nmap: usize) -> Table {
+    let mut table = Table::new(fb2int(int2fb(narray)), fb2int(int2fb(nmap)));
+    let mut array = Vec::new();
+    let mut nflushed = 0;
+    for (key, value) in entries.into_iter() {
+        match key {
+            Some(key) => table.new_index(key, value),
+            None => {
+                array.push(value);
+                if array.len() == 50 {
+                    table.set_list(nflushed, array.drain(..));
+                    nflushed += 50;
+                }
+            }
+        }
+    }
+    table.set_list(nflushed, array);
+    table
+}
+
+fn is_const_exp(desc: &ExpDesc) -> bool {
+    matches!(desc, ExpDesc::Nil | ExpDesc::Boolean(_) | ExpDesc::Integer(_)
+        | ExpDesc::Float(_) | ExpDesc::String(_))
+}
+
 fn fold_const(binop: &Token, left: &ExpDesc, right: &ExpDesc) -> Option<ExpDesc> {
+    // leave the error of integer division by zero to runtime
+    if matches!(binop, Token::Mod | Token::Idiv)
+            && matches!((left, right), (ExpDesc::Integer(_), ExpDesc::Integer(0))) {
+        return None;
+    }
+
     match binop {
-        Token::Add => do_fold_const(left, right, |a,b|a+b, |a,b|a+b),
-        Token::Sub => do_fold_const(left, right, |a,b|a-b, |a,b|a-b),
-        Token::Mul => do_fold_const(left, right, |a,b|a*b, |a,b|a*b),
-        Token::Mod => do_fold_const(left, right, |a,b|a%b, |a,b|a%b),
-        Token::Idiv => do_fold_const(left, right, |a,b|a/b, |a,b|a/b),
+        Token::Add => do_fold_const(left, right, i64::wrapping_add, |a,b|a+b),
+        Token::Sub => do_fold_const(left, right, i64::wrapping_sub, |a,b|a-b),
+        Token::Mul => do_fold_const(left, right, i64::wrapping_mul, |a,b|a*b),
+        Token::Mod => do_fold_const(left, right, mod_int, mod_float),
+        Token::Idiv => do_fold_const(left, right, idiv_int, idiv_float),
 
         Token::Div => do_fold_const_float(left, right, |a,b|a/b),
         Token::Pow => do_fold_const_float(left, right, |a,b|a.powf(b)),
@@ -1725,8 +2305,8 @@ fn fold_const(binop: &Token, left: &ExpDesc, right: &ExpDesc) -> Option<ExpDesc>
         Token::BitAnd => do_fold_const_int(left, right, |a,b|a&b),
         Token::BitNot => do_fold_const_int(left, right, |a,b|a^b),
         Token::BitOr  => do_fold_const_int(left, right, |a,b|a|b),
-        Token::ShiftL => do_fold_const_int(left, right, |a,b|a<<b),
-        Token::ShiftR => do_fold_const_int(left, right, |a,b|a>>b),
+        Token::ShiftL => do_fold_const_int(left, right, shift_left),
+        Token::ShiftR => do_fold_const_int(left, right, shift_right),
 
         Token::Concat => {
             if let (ExpDesc::String(s1), ExpDesc::String(s2)) = (left, right) {
@@ -1750,12 +2330,13 @@ fn do_fold_const(left: &ExpDesc, right: &ExpDesc, arith_i: fn(i64,i64)->i64, ari
     }
 }
 
+// floats without exact integer values are left to raise error at runtime
 fn do_fold_const_int(left: &ExpDesc, right: &ExpDesc, arith_i: fn(i64,i64)->i64) -> Option<ExpDesc> {
     let (i1, i2) = match (left, right) {
         (&ExpDesc::Integer(i1), &ExpDesc::Integer(i2)) => (i1, i2),
-        (&ExpDesc::Float(f1), &ExpDesc::Float(f2)) => (ftoi(f1).unwrap(), ftoi(f2).unwrap()),
-        (&ExpDesc::Float(f1), &ExpDesc::Integer(i2)) => (ftoi(f1).unwrap(), i2),
-        (&ExpDesc::Integer(i1), &ExpDesc::Float(f2)) => (i1, ftoi(f2).unwrap()),
+        (&ExpDesc::Float(f1), &ExpDesc::Float(f2)) => (ftoi(f1)?, ftoi(f2)?),
+        (&ExpDesc::Float(f1), &ExpDesc::Integer(i2)) => (ftoi(f1)?, i2),
+        (&ExpDesc::Integer(i1), &ExpDesc::Float(f2)) => (i1, ftoi(f2)?),
         (_, _) => return None,
     };
     Some(ExpDesc::Integer(arith_i(i1, i2)))
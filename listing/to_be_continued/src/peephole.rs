@@ -0,0 +1,409 @@
+use crate::bytecode::ByteCode;
+use crate::parse::{FuncProto, jump_code};
+
+// Peephole optimizer over the byte codes of one function, which runs
+// after the single-pass parsing if enabled (`-p` option).
+//
+// Jump offsets are converted into absolute targets first, so byte codes
+// can be removed or fused freely, and then are re-patched at last. The
+// passes only remove byte codes, so the jump distances never grow and
+// the offsets still fit in their operands.
+//
+// Some byte codes skip the following one, e.g. the comparisons skip the
+// following Jump, while LoadConstX consumes the following ExtraArg. So
+// the byte code after them must be kept alone, and must not be removed
+// or fused with the next one.
+pub fn optimize(fp: &mut FuncProto) {
+    let mut ph = Peephole::new(&fp.byte_codes);
+    ph.simplify();
+    ph.merge_load_nils();
+    ph.remove_redundant_moves();
+    ph.fuse_superinstructions();
+
+    // the passes never make jumps longer, so the offsets always fit
+    fp.byte_codes = ph.encode().expect("jump out of range after optimization");
+}
+
+// Byte codes with absolute jump targets. It is used by the IR
+// optimizer (`ir.rs`) too.
+pub struct Peephole {
+    pub codes: Vec<ByteCode>,
+    pub targets: Vec<Option<usize>>, // absolute jump targets
+}
+
+impl Peephole {
+    pub fn new(byte_codes: &[ByteCode]) -> Self {
+        let targets = byte_codes.iter().enumerate()
+            .map(|(pc, code)| jump_offset(code).map(|jmp| (pc as isize + 1 + jmp) as usize))
+            .collect();
+        Peephole { codes: byte_codes.to_vec(), targets }
+    }
+
+    // Thread jumps, remove dead codes and useless jumps, until nothing
+    // changes.
+    pub fn simplify(&mut self) {
+        loop {
+            let mut changed = self.thread_jumps();
+            changed |= self.remove_dead_codes();
+            changed |= self.remove_useless_jumps();
+            if !changed {
+                break;
+            }
+        }
+    }
+
+    // Redirect jumps to unconditional Jump, to its final target.
+    fn thread_jumps(&mut self) -> bool {
+        let mut changed = false;
+        for pc in 0..self.codes.len() {
+            let Some(mut target) = self.targets[pc] else {
+                continue;
+            };
+            // limit the hops, in case of dead loop
+            for _ in 0..self.codes.len() {
+                match (self.codes.get(target), self.targets.get(target)) {
+                    (Some(ByteCode::Jump(_) | ByteCode::LongJump(_, _)), Some(&Some(t))) if t != target => target = t,
+                    _ => break,
+                }
+            }
+            if Some(target) != self.targets[pc] && self.can_jump(pc, target) {
+                self.targets[pc] = Some(target);
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    // Whether the byte code at @pc can jump to @target, according to the
+    // type of offset operand. Only Jump and TestXxxJump are threaded.
+    fn can_jump(&self, pc: usize, target: usize) -> bool {
+        let jmp = target as isize - pc as isize - 1;
+        match self.codes[pc] {
+            ByteCode::Jump(_) | ByteCode::LongJump(_, _) => i8::try_from(jmp >> 16).is_ok(),
+            ByteCode::TestAndJump(_, _) | ByteCode::TestOrJump(_, _) => i16::try_from(jmp).is_ok(),
+            ByteCode::TestAndSetJump(_, _, _) | ByteCode::TestOrSetJump(_, _, _) => u8::try_from(jmp).is_ok(),
+            _ => false,
+        }
+    }
+
+    // Remove byte codes unreachable from the entry, e.g. Jump after Return.
+    fn remove_dead_codes(&mut self) -> bool {
+        let mut reachable = vec![false; self.codes.len()];
+        let mut pending = vec![0];
+        while let Some(pc) = pending.pop() {
+            if pc >= self.codes.len() || reachable[pc] {
+                continue;
+            }
+            reachable[pc] = true;
+            pending.extend(self.successors(pc));
+        }
+        self.compact(&reachable)
+    }
+
+    // SetFalseSkip always skips the following LoadBool, however it is
+    // taken as a successor too, so it is kept even if nobody jumps to it.
+    pub fn successors(&self, pc: usize) -> Vec<usize> {
+        match self.codes[pc] {
+            ByteCode::Jump(_) | ByteCode::LongJump(_, _) => vec![self.targets[pc].unwrap()],
+            ByteCode::Return0 | ByteCode::Return(_, _) | ByteCode::TailCall(_, _) => vec![],
+            _ if skips_next(&self.codes[pc]) => vec![pc + 1, pc + 2],
+            _ => match self.targets[pc] {
+                Some(target) => vec![pc + 1, target],
+                None => vec![pc + 1],
+            }
+        }
+    }
+
+    // Remove Jump to the next byte code.
+    fn remove_useless_jumps(&mut self) -> bool {
+        let mut keep = vec![true; self.codes.len()];
+        for pc in 0..self.codes.len() {
+            if matches!(self.codes[pc], ByteCode::Jump(_) | ByteCode::LongJump(_, _))
+                    && !self.after_skip(pc)
+                    && self.next_kept(pc, &keep) == self.targets[pc].unwrap() {
+                keep[pc] = false;
+            }
+        }
+        self.compact(&keep)
+    }
+
+    // the first byte code after @pc which is kept
+    fn next_kept(&self, pc: usize, keep: &[bool]) -> usize {
+        (pc + 1 .. keep.len()).find(|&i| keep[i]).unwrap_or(keep.len())
+    }
+
+    // LoadNil(a, n) + LoadNil(a+n, m) => LoadNil(a, n+m)
+    fn merge_load_nils(&mut self) {
+        let is_target = self.jump_targets();
+        let mut keep = vec![true; self.codes.len()];
+        let mut head = 0; // the last kept LoadNil, merge into it
+        for pc in 1..self.codes.len() {
+            if keep[pc - 1] {
+                head = pc - 1;
+            }
+            if let (ByteCode::LoadNil(a, n), ByteCode::LoadNil(b, m)) = (self.codes[head], self.codes[pc]) {
+                if b as usize == a as usize + n as usize && n as usize + m as usize <= u8::MAX as usize
+                        && !self.after_skip(head) && !is_target[pc] {
+                    self.codes[head] = ByteCode::LoadNil(a, n + m);
+                    keep[pc] = false;
+                }
+            }
+        }
+        self.compact(&keep);
+    }
+
+    // Remove:
+    //   Move(a, a);
+    //   Move(a, b) + Move(b, a) => Move(a, b)
+    //   Move(a, b) + Move(a, c) => Move(a, c)
+    fn remove_redundant_moves(&mut self) {
+        let is_target = self.jump_targets();
+        let mut keep = vec![true; self.codes.len()];
+        for pc in 0..self.codes.len() {
+            let ByteCode::Move(a, b) = self.codes[pc] else {
+                continue;
+            };
+            if !keep[pc] {
+                continue;
+            }
+            if a == b {
+                keep[pc] = self.after_skip(pc);
+                continue;
+            }
+            match self.codes.get(pc + 1) {
+                Some(&ByteCode::Move(b2, a2)) if b2 == b && a2 == a && self.can_fuse(pc, &is_target) => {
+                    keep[pc + 1] = false;
+                }
+                Some(&ByteCode::Move(a2, c)) if a2 == a && c != a && !self.after_skip(pc) => {
+                    keep[pc] = false;
+                }
+                _ => (),
+            }
+        }
+        self.compact(&keep);
+    }
+
+    // Fuse pairs of hot byte codes into one:
+    //   Move(a, b) + Move(a+1, c) => Move2(a, b, c)
+    //   comparison + Jump => comparison-and-jump
+    //   GetField(a, t, k) + Call(a, 1, 0) => CallField(a, t, k)
+    //   GetUpField(a, u, k) + Call(a, 1, 0) => CallUpField(a, u, k)
+    //
+    // The arguments are loaded between GetField and Call, so only the
+    // calls without argument are fused, which need no more operands.
+    fn fuse_superinstructions(&mut self) {
+        let is_target = self.jump_targets();
+        let mut keep = vec![true; self.codes.len()];
+        let mut pc = 0;
+        while pc + 1 < self.codes.len() {
+            if !self.can_fuse(pc, &is_target) {
+                pc += 1;
+                continue;
+            }
+            let fused = match (self.codes[pc], self.codes[pc + 1]) {
+                (ByteCode::Move(a, b), ByteCode::Move(a1, c)) if a1 as usize == a as usize + 1 => {
+                    Some(ByteCode::Move2(a, b, c))
+                }
+                (ByteCode::GetField(a, t, k), ByteCode::Call(func, 1, 0)) if func == a => {
+                    Some(ByteCode::CallField(a, t, k))
+                }
+                (ByteCode::GetUpField(a, u, k), ByteCode::Call(func, 1, 0)) if func == a => {
+                    Some(ByteCode::CallUpField(a, u, k))
+                }
+                (cmp, ByteCode::Jump(_)) => {
+                    // the new offset is not larger than this
+                    let target = self.targets[pc + 1].unwrap();
+                    match (compare_jump(cmp), i8::try_from(target as isize - pc as isize - 1)) {
+                        (Some(code), Ok(_)) => {
+                            self.targets[pc] = Some(target);
+                            Some(code)
+                        }
+                        _ => None,
+                    }
+                }
+                _ => None,
+            };
+            if let Some(code) = fused {
+                self.codes[pc] = code;
+                keep[pc + 1] = false;
+                pc += 2;
+            } else {
+                pc += 1;
+            }
+        }
+        self.compact(&keep);
+    }
+
+    // Whether the byte codes at @pc and @pc+1 can be fused into one:
+    // nobody jumps to @pc+1 or skips @pc.
+    fn can_fuse(&self, pc: usize, is_target: &[bool]) -> bool {
+        !self.after_skip(pc) && !is_target[pc + 1]
+    }
+
+    fn jump_targets(&self) -> Vec<bool> {
+        let mut is_target = vec![false; self.codes.len() + 1];
+        for &t in self.targets.iter().flatten() {
+            is_target[t] = true;
+        }
+        is_target
+    }
+
+    pub fn after_skip(&self, pc: usize) -> bool {
+        pc > 0 && skips_next(&self.codes[pc - 1])
+    }
+
+    // Remove the byte codes not kept. Targets of removed byte codes
+    // are redirected to the next kept one.
+    pub fn compact(&mut self, keep: &[bool]) -> bool {
+        if keep.iter().all(|&k| k) {
+            return false;
+        }
+        let mut new_pc = Vec::with_capacity(keep.len() + 1);
+        let mut n = 0;
+        for &k in keep.iter() {
+            new_pc.push(n);
+            if k {
+                n += 1;
+            }
+        }
+        new_pc.push(n);
+
+        let mut i = 0;
+        self.codes.retain(|_| { i += 1; keep[i - 1] });
+        let mut i = 0;
+        self.targets.retain(|_| { i += 1; keep[i - 1] });
+        for t in self.targets.iter_mut().flatten() {
+            *t = new_pc[*t];
+        }
+        true
+    }
+
+    // Expand the TestAndJump/TestOrJump whose offsets overflow i16 into
+    // the inverted test, which skips the following Jump (or LongJump)
+    // to the target. The inserted Jumps make other jumps longer, so
+    // repeat until all tests fit.
+    pub fn expand_long_tests(&mut self) {
+        loop {
+            let long: Vec<bool> = self.codes.iter().zip(&self.targets).enumerate()
+                .map(|(pc, (code, target))| match (code, target) {
+                    (ByteCode::TestAndJump(_, _) | ByteCode::TestOrJump(_, _), &Some(target)) =>
+                        i16::try_from(target as isize - pc as isize - 1).is_err(),
+                    _ => false,
+                })
+                .collect();
+            if !long.contains(&true) {
+                return;
+            }
+
+            let mut new_pc = Vec::with_capacity(self.codes.len() + 1);
+            let mut count = 0;
+            for &l in long.iter() {
+                new_pc.push(count);
+                count += 1 + l as usize;
+            }
+            new_pc.push(count);
+
+            let mut codes = Vec::with_capacity(count);
+            let mut targets = Vec::with_capacity(count);
+            for (pc, &l) in long.iter().enumerate() {
+                let target = self.targets[pc].map(|t| new_pc[t]);
+                if l {
+                    codes.push(match self.codes[pc] {
+                        ByteCode::TestAndJump(c, _) => ByteCode::TestOrJump(c, 0),
+                        ByteCode::TestOrJump(c, _) => ByteCode::TestAndJump(c, 0),
+                        _ => unreachable!(),
+                    });
+                    targets.push(Some(new_pc[pc] + 2));
+                    codes.push(ByteCode::Jump(0));
+                    targets.push(target);
+                } else {
+                    codes.push(self.codes[pc]);
+                    targets.push(target);
+                }
+            }
+            self.codes = codes;
+            self.targets = targets;
+        }
+    }
+
+    // Convert the targets back into offsets. Return None if any offset
+    // does not fit in its operand.
+    pub fn encode(&self) -> Option<Vec<ByteCode>> {
+        self.codes.iter().zip(&self.targets).enumerate().map(|(pc, (&code, &target))| {
+            let Some(target) = target else {
+                return Some(code);
+            };
+            let jmp = target as isize - pc as isize - 1;
+            Some(match code {
+                ByteCode::Jump(_) | ByteCode::LongJump(_, _) => jump_code(jmp),
+                ByteCode::TestAndJump(c, _) => ByteCode::TestAndJump(c, checked(jmp)?),
+                ByteCode::TestOrJump(c, _) => ByteCode::TestOrJump(c, checked(jmp)?),
+                ByteCode::TestAndSetJump(d, c, _) => ByteCode::TestAndSetJump(d, c, checked(jmp)?),
+                ByteCode::TestOrSetJump(d, c, _) => ByteCode::TestOrSetJump(d, c, checked(jmp)?),
+                ByteCode::ForPrepare(i, _) => ByteCode::ForPrepare(i, checked(jmp)?),
+                ByteCode::ForLoop(i, _) => ByteCode::ForLoop(i, checked(-jmp)?),
+                ByteCode::ForCallLoop(i, n, _) => ByteCode::ForCallLoop(i, n, checked(-jmp)?),
+                ByteCode::EqualJump(a, b, _) => ByteCode::EqualJump(a, b, checked(jmp)?),
+                ByteCode::EqualIntJump(a, b, _) => ByteCode::EqualIntJump(a, b, checked(jmp)?),
+                ByteCode::EqualConstJump(a, b, _) => ByteCode::EqualConstJump(a, b, checked(jmp)?),
+                ByteCode::LessJump(a, b, _) => ByteCode::LessJump(a, b, checked(jmp)?),
+                ByteCode::LesEqJump(a, b, _) => ByteCode::LesEqJump(a, b, checked(jmp)?),
+                ByteCode::LessIntJump(a, b, _) => ByteCode::LessIntJump(a, b, checked(jmp)?),
+                ByteCode::GreaterIntJump(a, b, _) => ByteCode::GreaterIntJump(a, b, checked(jmp)?),
+                _ => panic!("invalid jump"),
+            })
+        }).collect()
+    }
+}
+
+fn checked<T: TryFrom<isize>>(jmp: isize) -> Option<T> {
+    T::try_from(jmp).ok()
+}
+
+// offset of jump byte codes, relative to the next byte code
+fn jump_offset(code: &ByteCode) -> Option<isize> {
+    match *code {
+        ByteCode::Jump(jmp) => Some(jmp as isize),
+        ByteCode::LongJump(high, low) => Some(high as isize * 0x10000 + low as isize),
+        ByteCode::TestAndJump(_, jmp) | ByteCode::TestOrJump(_, jmp) => Some(jmp as isize),
+        ByteCode::TestAndSetJump(_, _, jmp) | ByteCode::TestOrSetJump(_, _, jmp) => Some(jmp as isize),
+        ByteCode::ForPrepare(_, jmp) => Some(jmp as isize),
+        ByteCode::ForLoop(_, jmp) => Some(-(jmp as isize)),
+        // 0 means to skip the following Jump
+        ByteCode::ForCallLoop(_, _, jmp) if jmp != 0 => Some(-(jmp as isize)),
+        ByteCode::EqualJump(_, _, jmp) | ByteCode::EqualIntJump(_, _, jmp)
+            | ByteCode::EqualConstJump(_, _, jmp) | ByteCode::LessJump(_, _, jmp)
+            | ByteCode::LesEqJump(_, _, jmp) | ByteCode::LessIntJump(_, _, jmp)
+            | ByteCode::GreaterIntJump(_, _, jmp) => Some(jmp as isize),
+        _ => None,
+    }
+}
+
+// byte codes which may skip, or consume, the following one
+pub fn skips_next(code: &ByteCode) -> bool {
+    matches!(code, ByteCode::LoadConstX(_, _) | ByteCode::SetList(_, _, u8::MAX)
+        | ByteCode::SetFalseSkip(_)
+        | ByteCode::ForCallLoop(_, _, 0)
+        | ByteCode::Equal(_, _, _) | ByteCode::EqualInt(_, _, _) | ByteCode::EqualConst(_, _, _)
+        | ByteCode::NotEq(_, _, _) | ByteCode::NotEqInt(_, _, _) | ByteCode::NotEqConst(_, _, _)
+        | ByteCode::LesEq(_, _, _) | ByteCode::LesEqInt(_, _, _) | ByteCode::LesEqConst(_, _, _)
+        | ByteCode::GreEq(_, _, _) | ByteCode::GreEqInt(_, _, _) | ByteCode::GreEqConst(_, _, _)
+        | ByteCode::Less(_, _, _) | ByteCode::LessInt(_, _, _) | ByteCode::LessConst(_, _, _)
+        | ByteCode::Greater(_, _, _) | ByteCode::GreaterInt(_, _, _) | ByteCode::GreaterConst(_, _, _))
+}
+
+// The comparison skips the following Jump if the result matches, so
+// it jumps if the result does not match. Only the hot ones in
+// benchmarks are fused. The offset is fixed in encode().
+fn compare_jump(code: ByteCode) -> Option<ByteCode> {
+    match code {
+        ByteCode::Equal(a, b, true) => Some(ByteCode::EqualJump(a, b, 0)),
+        ByteCode::EqualInt(a, i, true) => Some(ByteCode::EqualIntJump(a, i, 0)),
+        ByteCode::EqualConst(a, k, true) => Some(ByteCode::EqualConstJump(a, k, 0)),
+        ByteCode::Less(a, b, true) => Some(ByteCode::LessJump(a, b, 0)),
+        ByteCode::LesEq(a, b, true) => Some(ByteCode::LesEqJump(a, b, 0)),
+        ByteCode::LessInt(a, i, true) => Some(ByteCode::LessIntJump(a, i, 0)),
+        ByteCode::GreaterInt(a, i, true) => Some(ByteCode::GreaterIntJump(a, i, 0)),
+        _ => None,
+    }
+}
@@ -1,22 +1,332 @@
 use std::cmp::Ordering;
+use std::hash::{BuildHasherDefault, Hash, Hasher};
 use crate::value::Value;
+use crate::packed::{Slot, to_slot};
 
+// convert float to integer only if it has an exact integer value
 pub fn ftoi(f: f64) -> Option<i64> {
-    let i = f as i64;
-    if i as f64 != f {
+    // `f as i64` saturates, so check the range [-2^63, 2^63) first
+    if f.fract() == 0.0 && (-9223372036854775808.0..9223372036854775808.0).contains(&f) {
+        Some(f as i64)
+    } else {
+        None
+    }
+}
+
+// Compare integer and float exactly, without converting the integer to
+// float which may lose precision. Return None if @f is NaN.
+pub fn cmp_int_float(i: i64, f: f64) -> Option<Ordering> {
+    if f.is_nan() {
         None
+    } else if f >= 9223372036854775808.0 {
+        Some(Ordering::Less)
+    } else if f < -9223372036854775808.0 {
+        Some(Ordering::Greater)
+    } else {
+        // @f is in range of i64 now, so truncation is exact
+        match i.cmp(&(f as i64)) {
+            Ordering::Equal => 0.0.partial_cmp(&f.fract()),
+            ord => Some(ord),
+        }
+    }
+}
+
+// Arithmetic operations following Lua 5.4 semantics. They are shared
+// by the virtual machine and constant folding in parser.
+
+// floor division of integers
+pub fn idiv_int(a: i64, b: i64) -> i64 {
+    if b == 0 {
+        panic!("attempt to perform 'n//0'");
+    }
+    let q = a.wrapping_div(b);
+    if a.wrapping_rem(b) != 0 && (a ^ b) < 0 {
+        q - 1 // round toward minus infinity
+    } else {
+        q
+    }
+}
+// floor modulo of integers, with the sign of divisor
+pub fn mod_int(a: i64, b: i64) -> i64 {
+    if b == 0 {
+        panic!("attempt to perform 'n%0'");
+    }
+    let r = a.wrapping_rem(b);
+    if r != 0 && (r ^ b) < 0 {
+        r + b
+    } else {
+        r
+    }
+}
+pub fn idiv_float(a: f64, b: f64) -> f64 {
+    (a / b).floor()
+}
+pub fn mod_float(a: f64, b: f64) -> f64 {
+    let r = a % b;
+    if (r > 0.0 && b < 0.0) || (r < 0.0 && b > 0.0) {
+        r + b
+    } else {
+        r
+    }
+}
+
+// logical shift, where negative @b shifts to right
+pub fn shift_left(a: i64, b: i64) -> i64 {
+    if b <= -64 || b >= 64 {
+        0
+    } else if b >= 0 {
+        ((a as u64) << b) as i64
+    } else {
+        ((a as u64) >> -b) as i64
+    }
+}
+pub fn shift_right(a: i64, b: i64) -> i64 {
+    shift_left(a, b.wrapping_neg())
+}
+
+// Encode size hint in a byte as "floating point byte" (eeeeexxx),
+// which is (1xxx) * 2^(eeeee-1) if eeeee > 0, otherwise xxx. Values
+// less than 16 are exact, and others are rounded up.
+pub fn int2fb(mut x: usize) -> u8 {
+    if x < 8 {
+        return x as u8;
+    }
+    let mut e = 0;
+    while x >= (8 << 4) {
+        x = (x + 0xf) >> 4;
+        e += 4;
+    }
+    while x >= (8 << 1) {
+        x = (x + 1) >> 1;
+        e += 1;
+    }
+    // sizes are far less than the limit 2^34, which would overflow
+    u8::try_from(((e + 1) << 3) | (x - 8)).unwrap_or(u8::MAX)
+}
+pub fn fb2int(x: u8) -> usize {
+    if x < 8 {
+        x as usize
     } else {
-        Some(i)
+        ((x as usize & 7) + 8) << ((x >> 3) - 1)
     }
 }
 
-pub fn set_vec(vec: &mut Vec<Value>, i: usize, value: Value) {
+pub fn set_vec(vec: &mut Vec<Slot>, i: usize, value: Slot) {
     match i.cmp(&vec.len()) {
         Ordering::Less => vec[i] = value,
         Ordering::Equal => vec.push(value),
         Ordering::Greater => {
-            vec.resize(i, Value::Nil);
+            vec.resize(i, to_slot(Value::Nil));
             vec.push(value);
         }
     }
 }
+
+// encode @x in UTF-8, extended to 6 bytes for values up to 0x7FFFFFFF
+// as Lua does, so surrogates and values above 0x10FFFF are allowed
+pub fn utf8_encode(mut x: u32) -> Vec<u8> {
+    if x < 0x80 {
+        return vec![x as u8];
+    }
+    let mut buf = Vec::with_capacity(6);
+    let mut mfb = 0x3f; // maximum that fits in first byte
+    loop {
+        buf.push(0x80 | (x & 0x3f) as u8);
+        x >>= 6;
+        mfb >>= 1;
+        if x <= mfb {
+            break;
+        }
+    }
+    buf.push(((!mfb << 1) | x) as u8);
+    buf.reverse();
+    buf
+}
+
+// Convert string to number, following Lua's syntax of numerals, with
+// optional leading/trailing spaces and sign. Hexadecimal integers wrap
+// around, while decimal integers which overflow are converted to float.
+pub fn str_to_number(s: &[u8]) -> Option<Value> {
+    let s = s.trim_ascii();
+    let (neg, digits) = match s.first() {
+        Some(b'-') => (true, &s[1..]),
+        Some(b'+') => (false, &s[1..]),
+        _ => (false, s),
+    };
+
+    if let Some(i) = str_to_int(digits, neg) {
+        return Some(Value::Integer(i));
+    }
+    let f = if let Some(hex) = digits.strip_prefix(b"0x").or_else(|| digits.strip_prefix(b"0X")) {
+        hex_to_float(hex)?
+    } else {
+        // Rust accepts "inf" and "nan", but Lua does not. And the sign
+        // is allowed only in the exponent, since the leading one is
+        // stripped above.
+        let valid = digits.iter().enumerate().all(|(i, b)| match b {
+            b'+' | b'-' => i > 0 && matches!(digits[i - 1], b'e' | b'E'),
+            b => b.is_ascii_digit() || b".eE".contains(b),
+        });
+        if !valid {
+            return None;
+        }
+        std::str::from_utf8(digits).ok()?.parse::<f64>().ok()?
+    };
+    Some(Value::Float(if neg { -f } else { f }))
+}
+
+fn str_to_int(digits: &[u8], neg: bool) -> Option<i64> {
+    let n = if let Some(hex) = digits.strip_prefix(b"0x").or_else(|| digits.strip_prefix(b"0X")) {
+        if hex.is_empty() {
+            return None;
+        }
+        let mut n: u64 = 0;
+        for &b in hex {
+            let d = (b as char).to_digit(16)?;
+            n = n.wrapping_mul(16).wrapping_add(d as u64);
+        }
+        n
+    } else {
+        if digits.is_empty() {
+            return None;
+        }
+        let mut n: u64 = 0;
+        for &b in digits {
+            let d = (b as char).to_digit(10)?;
+            n = n.checked_mul(10)?.checked_add(d as u64)?;
+        }
+        if n > i64::MAX as u64 + neg as u64 {
+            return None; // overflow, try float then
+        }
+        n
+    };
+    Some(if neg { 0_u64.wrapping_sub(n) as i64 } else { n as i64 })
+}
+
+// the hexadecimal float with "0x" stripped, e.g. "A.8p1" for 21.0
+fn hex_to_float(s: &[u8]) -> Option<f64> {
+    let mut r = 0.0;
+    let mut exp: i64 = 0;
+    let mut any_digit = false;
+    let mut has_dot = false;
+    let mut i = 0;
+    while i < s.len() {
+        match s[i] {
+            b'.' if has_dot => return None,
+            b'.' => has_dot = true,
+            b => match (b as char).to_digit(16) {
+                Some(d) => {
+                    r = r * 16.0 + d as f64;
+                    any_digit = true;
+                    if has_dot {
+                        exp -= 4; // each digit after dot
+                    }
+                }
+                None => break,
+            }
+        }
+        i += 1;
+    }
+    if !any_digit {
+        return None;
+    }
+
+    // optional binary exponent
+    if i < s.len() {
+        if s[i] != b'p' && s[i] != b'P' {
+            return None;
+        }
+        let e = &s[i+1..];
+        let digits = e.strip_prefix(b"-").or_else(|| e.strip_prefix(b"+")).unwrap_or(e);
+        if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+            return None;
+        }
+        let e = std::str::from_utf8(e).unwrap();
+        exp += e.parse::<i64>().unwrap_or(if e.starts_with('-') { -100000 } else { 100000 });
+    }
+    Some(r * (exp as f64).exp2())
+}
+
+// Format float as Lua's "%.14g", and add ".0" if it looks like an
+// integer, so floats are distinguishable from integers.
+pub fn fmt_float(f: f64) -> String {
+    if f.is_nan() {
+        return String::from(if f.is_sign_negative() { "-nan" } else { "nan" });
+    }
+    if f.is_infinite() {
+        return String::from(if f > 0.0 { "inf" } else { "-inf" });
+    }
+
+    // "%g" uses scientific notation if the exponent is less than -4
+    // or not less than the precision
+    const PRECISION: i32 = 14;
+    let sci = format!("{:.*e}", PRECISION as usize - 1, f);
+    let (mantissa, exp) = sci.split_once('e').unwrap();
+    let exp: i32 = exp.parse().unwrap();
+    let s = if !(-4..PRECISION).contains(&exp) {
+        let sign = if exp < 0 { '-' } else { '+' };
+        format!("{}e{sign}{:02}", trim_fraction_zeros(mantissa), exp.abs())
+    } else {
+        let s = format!("{:.*}", (PRECISION - 1 - exp) as usize, f);
+        trim_fraction_zeros(&s).to_string()
+    };
+
+    if s.bytes().all(|b| b == b'-' || b.is_ascii_digit()) {
+        s + ".0"
+    } else {
+        s
+    }
+}
+
+fn trim_fraction_zeros(s: &str) -> &str {
+    if s.contains('.') {
+        s.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        s
+    }
+}
+
+// Fast non-cryptographic hasher for the map part of tables, instead of
+// the default SipHash. It follows the FxHash used by rustc. The keys
+// are mostly integers, pointers and strings with cached hashes, so the
+// resistance to HashDoS does not pay off.
+#[derive(Default, Clone, Copy)]
+pub struct FastHasher(u64);
+
+pub type FastBuildHasher = BuildHasherDefault<FastHasher>;
+
+impl Hasher for FastHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        let mut chunks = bytes.chunks_exact(8);
+        for chunk in &mut chunks {
+            self.write_u64(u64::from_le_bytes(chunk.try_into().unwrap()));
+        }
+        let rest = chunks.remainder();
+        if !rest.is_empty() {
+            let mut buf = [0; 8];
+            buf[..rest.len()].copy_from_slice(rest);
+            self.write_u64(u64::from_le_bytes(buf));
+        }
+    }
+    fn write_u8(&mut self, i: u8) {
+        self.write_u64(i as u64);
+    }
+    fn write_u64(&mut self, i: u64) {
+        self.0 = self.0.wrapping_add(i).wrapping_mul(0xf1357aea2e62a9c5);
+    }
+    fn write_usize(&mut self, i: usize) {
+        self.write_u64(i as u64);
+    }
+    // the multiplication leaves the high bits better mixed, while the
+    // hash map takes the bucket index from the low bits
+    fn finish(&self) -> u64 {
+        self.0.rotate_left(26)
+    }
+}
+
+// hash of string content, cached in the heap strings
+pub fn str_hash(s: &[u8]) -> u64 {
+    let mut hasher = FastHasher::default();
+    s.hash(&mut hasher);
+    hasher.finish()
+}
@@ -1,12 +1,17 @@
 use std::fmt;
 use std::mem;
+use std::cmp::Ordering;
+use std::any::Any;
 use std::rc::Rc;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::hash::{Hash, Hasher};
-use std::collections::HashMap;
+use std::collections::HashSet;
 use crate::parse::FuncProto;
-use crate::vm::{ExeState, LuaClosure};
-use crate::utils::{ftoi, set_vec};
+use crate::vm::{ExeState, LuaClosure, LuaError};
+use crate::ordered_map::OrderedMap;
+use crate::packed::{Slot, ValueRef, to_slot, from_slot, slot_ref};
+use std::borrow::Cow;
+use crate::utils::{ftoi, fmt_float, str_to_number, cmp_int_float, str_hash, FastBuildHasher};
 
 const SHORT_STR_MAX: usize = 14; // sizeof(Value) - 1(tag) - 1(len)
 const MID_STR_MAX: usize = 48 - 1;
@@ -18,58 +23,459 @@ pub enum Value {
     Integer(i64),
     Float(f64),
     ShortStr(u8, [u8; SHORT_STR_MAX]),
-    MidStr(Rc<(u8, [u8; MID_STR_MAX])>),
-    LongStr(Rc<Vec<u8>>),
+    MidStr(Rc<MidStr>),
+    LongStr(Rc<LongStr>),
     Table(Rc<RefCell<Table>>),
-    RustFunction(fn (&mut ExeState) -> i32),
-    RustClosure(Rc<RefCell<Box<dyn FnMut (&mut ExeState) -> i32>>>),
+    RustFunction(fn (&mut ExeState) -> Result<i32, LuaError>),
+    RustClosure(Rc<RefCell<Box<dyn FnMut (&mut ExeState) -> Result<i32, LuaError>>>>),
     LuaFunction(Rc<FuncProto>),
     LuaClosure(Rc<LuaClosure>),
+    UserData(Rc<UserData>),
+}
+
+// The heap strings cache their hashes, so hashing them for table
+// access is cheap. MidStr computes it on creation, and LongStr on
+// the first use since it may never be a key.
+pub struct MidStr {
+    hash: u64,
+    len: u8,
+    buf: [u8; MID_STR_MAX],
+}
+
+impl MidStr {
+    fn new(v: &[u8]) -> Self {
+        let mut buf = [0; MID_STR_MAX];
+        buf[..v.len()].copy_from_slice(v);
+        MidStr { hash: str_hash(v), len: v.len() as u8, buf }
+    }
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len as usize]
+    }
+}
+
+pub struct LongStr {
+    hash: Cell<u64>, // 0 if not computed yet
+    buf: Vec<u8>,
+}
+
+impl LongStr {
+    fn new(buf: Vec<u8>) -> Self {
+        LongStr { hash: Cell::new(0), buf }
+    }
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf
+    }
+    fn hash_code(&self) -> u64 {
+        if self.hash.get() == 0 {
+            self.hash.set(str_hash(&self.buf));
+        }
+        self.hash.get()
+    }
+}
+
+// Interned strings of a state, so equal strings mostly share the same
+// allocation and are equal by pointer. Only MidStr are interned, since
+// ShortStr are stored inline and LongStr are seldom compared. Strings
+// referred only by the table are released when it grows.
+pub struct StrTable {
+    strs: HashSet<Value, FastBuildHasher>,
+    limit: usize, // sweep when the table reaches it
+}
+
+impl StrTable {
+    pub fn new() -> Self {
+        StrTable {
+            strs: HashSet::default(),
+            limit: 256,
+        }
+    }
+
+    pub fn intern(&mut self, v: Value) -> Value {
+        if !matches!(v, Value::MidStr(_)) {
+            return v;
+        }
+        if let Some(s) = self.strs.get(&v) {
+            return s.clone();
+        }
+        if self.strs.len() >= self.limit {
+            self.strs.retain(|s| matches!(s, Value::MidStr(s) if Rc::strong_count(s) > 1));
+            self.limit = self.limit.max(self.strs.len() * 2);
+        }
+        self.strs.insert(v.clone());
+        v
+    }
+
+    // Intern the string constants of function and its inner functions,
+    // including the ones in table templates.
+    pub fn intern_proto(&mut self, proto: &mut FuncProto) {
+        for c in proto.constants.iter_mut() {
+            // taken out of the slot, see packed.rs
+            let v = match from_slot(mem::replace(c, to_slot(Value::Nil))) {
+                v@Value::MidStr(_) => self.intern(v),
+                // just built by parser, so not shared
+                Value::LuaFunction(mut f) => {
+                    if let Some(f) = Rc::get_mut(&mut f) {
+                        self.intern_proto(f);
+                    }
+                    Value::LuaFunction(f)
+                }
+                Value::Table(t) => {
+                    self.intern_template(&mut t.borrow_mut());
+                    Value::Table(t)
+                }
+                v => v,
+            };
+            *c = to_slot(v);
+        }
+    }
+    fn intern_template(&mut self, t: &mut Table) {
+        let mut intern = |v: Value| match v {
+            Value::Table(t) => {
+                self.intern_template(&mut t.borrow_mut());
+                Value::Table(t)
+            }
+            v => self.intern(v),
+        };
+        let array: Vec<Slot> = t.array.drain(..).map(|v| to_slot(intern(from_slot(v)))).collect();
+        t.array = array;
+        let entries: Vec<(Value, Value)> = t.map.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+        t.map.clear();
+        for (k, v) in entries {
+            t.map.insert(intern(k), intern(v));
+        }
+    }
+}
+
+// Rust value with a metatable. It is created by Rust code with an
+// initialized value, since Rust does not allow uninitialized memory
+// as the official implementation does.
+pub struct UserData {
+    pub metatable: Option<Rc<RefCell<Table>>>,
+    pub data: RefCell<Box<dyn Any>>,
+}
+
+impl UserData {
+    pub fn new_value(data: impl Any, metatable: Option<Rc<RefCell<Table>>>) -> Value {
+        Value::UserData(Rc::new(UserData {
+            metatable,
+            data: RefCell::new(Box::new(data)),
+        }))
+    }
 }
 
 pub struct Table {
-    pub array: Vec<Value>,
-    pub map: HashMap<Value, Value>,
+    pub array: Vec<Slot>, // see packed.rs
+    pub map: OrderedMap<Value, Value>,
+    pub metatable: Option<Rc<RefCell<Table>>>,
 }
 
 impl Table {
     pub fn new(narray: usize, nmap: usize) -> Self {
         Table {
             array: Vec::with_capacity(narray),
-            map: HashMap::with_capacity(nmap),
+            map: OrderedMap::with_capacity(nmap),
+            metatable: None,
+        }
+    }
+
+    // Store values at indexes from @start+1, for SetList. They override
+    // the same integer keys set by the keyed entries before, which are
+    // in array part or map part.
+    pub fn set_list(&mut self, start: usize, values: impl IntoIterator<Item = Value>) {
+        if start == self.array.len() && self.map.is_empty() {
+            self.array.extend(values.into_iter().map(to_slot));
+            return;
+        }
+        for (i, value) in (start + 1..).zip(values) {
+            if i <= self.array.len() {
+                self.array[i - 1] = to_slot(value);
+            } else if i == self.array.len() + 1 {
+                self.array.push(to_slot(value));
+            } else {
+                // the array part has been shrunk by rehash
+                self.map_insert(Value::Integer(i as i64), value);
+                continue;
+            }
+            self.map.remove(&Value::Integer(i as i64));
+        }
+    }
+
+    // Create a table by the template built at parse time, with
+    // nested templates cloned too.
+    pub fn clone_template(&self) -> Table {
+        let copy = |v: &Value| match v {
+            Value::Table(t) => Value::from(t.borrow().clone_template()),
+            v => v.clone(),
+        };
+        let mut table = Table::new(self.array.capacity(), self.map.capacity());
+        table.array.extend(self.array.iter().map(|v| to_slot(copy(&slot_ref(v)))));
+        for (k, v) in self.map.iter() {
+            table.map.insert(k.clone(), copy(v));
         }
+        table
     }
 
-    pub fn index(&self, key: &Value) -> &Value {
+    pub fn index(&self, key: &Value) -> ValueRef<'_> {
         match key {
-            // TODO float
-            &Value::Integer(i) => self.index_array(i),
-            _ => self.map.get(key).unwrap_or(&Value::Nil),
+            Value::Integer(i) => self.index_array(*i),
+            // float key with integer value is normalized to integer,
+            // so `t[2.0]` and `t[2]` are the same entry
+            Value::Float(f) => match ftoi(*f) {
+                Some(i) => self.index_array(i),
+                None => self.map.get(key).unwrap_or(&Value::Nil).into(),
+            }
+            _ => self.map.get(key).unwrap_or(&Value::Nil).into(),
         }
     }
-    pub fn index_array(&self, i: i64) -> &Value {
-        self.array.get(i as usize - 1)
-            .unwrap_or_else(|| self.map.get(&Value::Integer(i as i64))
-                .unwrap_or(&Value::Nil))
+    pub fn index_array(&self, i: i64) -> ValueRef<'_> {
+        // `i as usize - 1` overflows for non-positive @i
+        match (i as usize).checked_sub(1).and_then(|i| self.array.get(i)) {
+            Some(v) => slot_ref(v),
+            None => self.map.get(&Value::Integer(i)).unwrap_or(&Value::Nil).into(),
+        }
+    }
+
+    // Same with index() and new_index(), but with a hint of the entry
+    // position in map part, see OrderedMap::get_hinted(). They are used
+    // by the byte codes with constant keys, which are mostly strings.
+    pub fn index_hinted(&self, key: &Value, hint: &Cell<u32>) -> ValueRef<'_> {
+        match key {
+            Value::Integer(_) | Value::Float(_) => self.index(key),
+            _ => self.map.get_hinted(key, hint).unwrap_or(&Value::Nil).into(),
+        }
+    }
+    pub fn new_index_hinted(&mut self, key: &Value, value: Value, hint: &Cell<u32>) {
+        match key {
+            Value::Integer(_) | Value::Float(_) | Value::Nil => self.new_index(key.clone(), value),
+            // existing entry is updated in place, even for nil, as
+            // map_insert() does
+            _ => match self.map.get_mut_hinted(key, hint) {
+                Some(v) => *v = value,
+                None => self.map_insert(key.clone(), value),
+            }
+        }
     }
 
     pub fn new_index(&mut self, key: Value, value: Value) {
         match key {
-            // TODO float
             Value::Integer(i) => self.new_index_array(i, value),
-            _ => {
-                self.map.insert(key, value);
+            Value::Float(f) => match ftoi(f) {
+                Some(i) => self.new_index_array(i, value),
+                None if f.is_nan() => panic!("table index is NaN"),
+                None => self.map_insert(key, value),
             }
+            Value::Nil => panic!("table index is nil"),
+            _ => self.map_insert(key, value),
         }
     }
     pub fn new_index_array(&mut self, i: i64, value: Value) {
-        // this is not same with Lua's official implement
-        if i > 0 && (i < 4 || i < self.array.capacity() as i64 * 2) {
-            set_vec(&mut self.array, i as usize - 1, value);
+        let len = self.array.len();
+        if i > 0 && i as usize <= len {
+            self.array[i as usize - 1] = to_slot(value);
+
+        } else if i > 0 && i as usize == len + 1 && value != Value::Nil {
+            // append to array part, and move the following keys in
+            // map part if any, e.g. when the table is filled in reverse
+            self.array.push(to_slot(value));
+            if !self.map.is_empty() {
+                self.map.remove(&Value::Integer(i));
+                let mut k = i + 1;
+                while let Some(v) = self.map.remove(&Value::Integer(k)) {
+                    self.array.push(to_slot(v));
+                    k += 1;
+                }
+            }
         } else {
-            self.map.insert(Value::Integer(i), value);
+            self.map_insert(Value::Integer(i), value);
+        }
+    }
+
+    // Insert into map part. If the map is full, rehash first as Lua does,
+    // which may move integer keys between array part and map part.
+    fn map_insert(&mut self, key: Value, value: Value) {
+        if value == Value::Nil {
+            // do not create new entry for nil, but keep the existing
+            // entry, so that the key is still valid for traversal
+            if let Some(v) = self.map.get_mut(&key) {
+                *v = Value::Nil;
+            }
+            return;
+        }
+
+        if self.map.len() == self.map.capacity() && !self.map.contains_key(&key) {
+            self.rehash(&key);
+
+            // the key may belong to the array part now
+            if let Value::Integer(i) = key {
+                if i > 0 && i as usize <= self.array.len() {
+                    self.array[i as usize - 1] = to_slot(value);
+                    return;
+                }
+            }
         }
+        self.map.insert(key, value);
     }
+
+    // Count integer keys, including the @new_key to be inserted, and
+    // compute the new size of array part following Lua's `computesizes()`:
+    // the largest n, a power of 2, such that more than half of slots
+    // from 1 to n are in use. Then move keys between the two parts.
+    fn rehash(&mut self, new_key: &Value) {
+        // nums[i] is the number of keys k where 2^(i-1) < k <= 2^i
+        let mut nums = [0_usize; 64];
+        let mut total = 0;
+        let mut count = |k: i64| {
+            if k > 0 {
+                nums[ceil_log2(k as u64)] += 1;
+                total += 1;
+            }
+        };
+        for (i, v) in self.array.iter().enumerate() {
+            if v != &Value::Nil {
+                count(i as i64 + 1);
+            }
+        }
+        for (k, v) in self.map.iter() {
+            if let (&Value::Integer(k), false) = (k, v == &Value::Nil) {
+                count(k);
+            }
+        }
+        if let &Value::Integer(k) = new_key {
+            count(k);
+        }
+
+        let mut size = 0;
+        let mut a = 0; // number of keys not greater than 2^i
+        for (i, &n) in nums.iter().enumerate() {
+            let twotoi = 1_usize << i;
+            if total <= twotoi / 2 {
+                break;
+            }
+            a += n;
+            if a > twotoi / 2 {
+                size = twotoi;
+            }
+        }
+        self.resize_array(size);
+    }
+
+    fn resize_array(&mut self, size: usize) {
+        let old_size = self.array.len();
+        if size < old_size {
+            // move the vanishing slice into map part
+            for (i, v) in self.array.drain(size..).enumerate() {
+                if v != Value::Nil {
+                    self.map.insert(Value::Integer((size + i + 1) as i64), from_slot(v));
+                }
+            }
+        } else {
+            self.array.resize(size, to_slot(Value::Nil));
+        }
+
+        // move integer keys in range to array part, and clear nil entries
+        let array = &mut self.array;
+        self.map.retain(|k, v| match k {
+            _ if v == &Value::Nil => false,
+            &Value::Integer(i) if i > old_size as i64 && i <= size as i64 => {
+                array[i as usize - 1] = to_slot(mem::replace(v, Value::Nil));
+                false
+            }
+            _ => true,
+        });
+
+        // keep some free space, to avoid rehashing again soon
+        self.map.shrink_to(self.map.len() * 2);
+    }
+
+    // Return the entry following @key for `next()`, traversing the array
+    // part and then the map part in insertion order. Entries with nil
+    // value are skipped, so it is allowed to assign nil to existing
+    // fields during traversal.
+    pub fn next(&self, key: &Value) -> Option<(Value, Value)> {
+        // the position in array part to start from
+        let start = match key {
+            Value::Nil => Some(0),
+            &Value::Integer(i) if i > 0 && i as usize <= self.array.len() => Some(i as usize),
+            _ => None,
+        };
+
+        let map_key = if let Some(start) = start {
+            for (i, v) in self.array.iter().enumerate().skip(start) {
+                if v != &Value::Nil {
+                    return Some((Value::Integer(i as i64 + 1), slot_ref(v).clone()));
+                }
+            }
+            None
+        } else {
+            Some(key)
+        };
+
+        let mut entries = self.map.iter_after(map_key)
+            .unwrap_or_else(|| panic!("invalid key to 'next'"));
+        entries.find(|(_, v)| v != &&Value::Nil)
+            .map(|(k, v)| (k.clone(), v.clone()))
+    }
+
+    // Return a border for the length operator `#`, that is a non-negative
+    // integer n where t[n] is not nil (or n is 0) and t[n+1] is nil. It
+    // follows Lua's `luaH_getn()`, searching the array part first and
+    // then the integer keys in map part.
+    pub fn border(&self) -> i64 {
+        let len = self.array.len();
+        if len > 0 && self.array[len - 1] == Value::Nil {
+            // binary search in array part, where array[i-1] is not nil
+            // (or i is 0) and array[j-1] is nil
+            let (mut i, mut j) = (0, len);
+            while j - i > 1 {
+                let m = (i + j) / 2;
+                if self.array[m - 1] == Value::Nil {
+                    j = m;
+                } else {
+                    i = m;
+                }
+            }
+            return i as i64;
+        }
+
+        let len = len as i64;
+        if self.map.is_empty() || *self.index_array(len + 1) == Value::Nil {
+            return len;
+        }
+
+        // unbound search in map part: find an absent j by doubling,
+        // and then binary search between present i and absent j
+        let mut i;
+        let mut j = len + 1; // present
+        loop {
+            i = j;
+            if j <= i64::MAX / 2 {
+                j *= 2;
+            } else {
+                j = i64::MAX;
+                if *self.index_array(j) == Value::Nil {
+                    break;
+                }
+                return j; // well, max integer is a border
+            }
+            if *self.index_array(j) == Value::Nil {
+                break;
+            }
+        }
+        while j - i > 1 {
+            let m = i + (j - i) / 2;
+            if *self.index_array(m) == Value::Nil {
+                j = m;
+            } else {
+                i = m;
+            }
+        }
+        i
+    }
+}
+
+// the i such that 2^(i-1) < k <= 2^i, for k > 0
+fn ceil_log2(k: u64) -> usize {
+    (k - 1).checked_ilog2().map_or(0, |l| l as usize + 1)
 }
 
 impl fmt::Display for Value {
@@ -78,15 +484,16 @@ impl fmt::Display for Value {
             Value::Nil => write!(f, "nil"),
             Value::Boolean(b) => write!(f, "{b}"),
             Value::Integer(i) => write!(f, "{i}"),
-            Value::Float(n) => write!(f, "{n:?}"),
+            Value::Float(n) => write!(f, "{}", fmt_float(*n)),
             Value::ShortStr(len, buf) => write!(f, "{}", String::from_utf8_lossy(&buf[..*len as usize])),
-            Value::MidStr(s) => write!(f, "{}", String::from_utf8_lossy(&s.1[..s.0 as usize])),
-            Value::LongStr(s) => write!(f, "{}", String::from_utf8_lossy(s)),
+            Value::MidStr(s) => write!(f, "{}", String::from_utf8_lossy(s.as_bytes())),
+            Value::LongStr(s) => write!(f, "{}", String::from_utf8_lossy(s.as_bytes())),
             Value::Table(t) => write!(f, "table: {:?}", Rc::as_ptr(t)),
             Value::RustFunction(_) => write!(f, "function"),
             Value::RustClosure(_) => write!(f, "function"),
             Value::LuaFunction(l) => write!(f, "function: {:?}", Rc::as_ptr(l)),
             Value::LuaClosure(l) => write!(f, "function: {:?}", Rc::as_ptr(l)),
+            Value::UserData(u) => write!(f, "userdata: {:?}", Rc::as_ptr(u)),
         }
     }
 }
@@ -99,8 +506,8 @@ impl fmt::Debug for Value {
             Value::Integer(i) => write!(f, "{i}"),
             Value::Float(n) => write!(f, "{n:?}"),
             Value::ShortStr(len, buf) => write!(f, "'{}'", String::from_utf8_lossy(&buf[..*len as usize])),
-            Value::MidStr(s) => write!(f, "\"{}\"", String::from_utf8_lossy(&s.1[..s.0 as usize])),
-            Value::LongStr(s) => write!(f, "'''{}'''", String::from_utf8_lossy(s)),
+            Value::MidStr(s) => write!(f, "\"{}\"", String::from_utf8_lossy(s.as_bytes())),
+            Value::LongStr(s) => write!(f, "'''{}'''", String::from_utf8_lossy(s.as_bytes())),
             Value::Table(t) => {
                 let t = t.borrow();
                 write!(f, "table:{}:{}", t.array.len(), t.map.len())
@@ -109,6 +516,7 @@ impl fmt::Debug for Value {
             Value::RustClosure(_) => write!(f, "rust closure"),
             Value::LuaFunction(_) => write!(f, "Lua function"),
             Value::LuaClosure(_) => write!(f, "Lua closure"),
+            Value::UserData(_) => write!(f, "userdata"),
         }
     }
 }
@@ -120,16 +528,20 @@ impl PartialEq for Value {
             (&Value::Boolean(b1), &Value::Boolean(b2)) => b1 == b2,
             (&Value::Integer(i1), &Value::Integer(i2)) => i1 == i2,
             (&Value::Integer(i), &Value::Float(f)) |
-            (&Value::Float(f), &Value::Integer(i)) => i as f64 == f && i == f as i64,
+            (&Value::Float(f), &Value::Integer(i)) => ftoi(f) == Some(i),
             (&Value::Float(f1), &Value::Float(f2)) => f1 == f2,
             (Value::ShortStr(len1, s1), Value::ShortStr(len2, s2)) => s1[..*len1 as usize] == s2[..*len2 as usize],
-            (Value::MidStr(s1), Value::MidStr(s2)) => s1.1[..s1.0 as usize] == s2.1[..s2.0 as usize],
-            (Value::LongStr(s1), Value::LongStr(s2)) => s1 == s2,
+            // interned strings are equal by pointer, and different
+            // strings mostly have different hashes
+            (Value::MidStr(s1), Value::MidStr(s2)) => Rc::ptr_eq(s1, s2)
+                || (s1.hash == s2.hash && s1.as_bytes() == s2.as_bytes()),
+            (Value::LongStr(s1), Value::LongStr(s2)) => Rc::ptr_eq(s1, s2) || s1.buf == s2.buf,
             (Value::Table(t1), Value::Table(t2)) => Rc::as_ptr(t1) == Rc::as_ptr(t2),
-            (Value::RustFunction(f1), Value::RustFunction(f2)) => std::ptr::eq(f1, f2),
+            (Value::RustFunction(f1), Value::RustFunction(f2)) => *f1 as usize == *f2 as usize,
             (Value::RustClosure(f1), Value::RustClosure(f2)) => Rc::as_ptr(f1) == Rc::as_ptr(f2),
             (Value::LuaFunction(f1), Value::LuaFunction(f2)) => Rc::as_ptr(f1) == Rc::as_ptr(f2),
             (Value::LuaClosure(f1), Value::LuaClosure(f2)) => Rc::as_ptr(f1) == Rc::as_ptr(f2),
+            (Value::UserData(u1), Value::UserData(u2)) => Rc::as_ptr(u1) == Rc::as_ptr(u2),
             (_, _) => false,
         }
     }
@@ -138,26 +550,26 @@ impl PartialEq for Value {
 impl Eq for Value {}
 
 impl PartialOrd for Value {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         match (self, other) {
             // numbers
             (Value::Integer(i1), Value::Integer(i2)) => Some(i1.cmp(i2)),
-            (Value::Integer(i), Value::Float(f)) => (*i as f64).partial_cmp(f),
-            (Value::Float(f), Value::Integer(i)) => f.partial_cmp(&(*i as f64)),
+            (Value::Integer(i), Value::Float(f)) => cmp_int_float(*i, *f),
+            (Value::Float(f), Value::Integer(i)) => cmp_int_float(*i, *f).map(Ordering::reverse),
             (Value::Float(f1), Value::Float(f2)) => f1.partial_cmp(f2),
 
             // strings
             (Value::ShortStr(len1, s1), Value::ShortStr(len2, s2)) => Some(s1[..*len1 as usize].cmp(&s2[..*len2 as usize])),
-            (Value::MidStr(s1), Value::MidStr(s2)) => Some(s1.1[..s1.0 as usize].cmp(&s2.1[..s2.0 as usize])),
-            (Value::LongStr(s1), Value::LongStr(s2)) => Some(s1.cmp(s2)),
+            (Value::MidStr(s1), Value::MidStr(s2)) => Some(s1.as_bytes().cmp(s2.as_bytes())),
+            (Value::LongStr(s1), Value::LongStr(s2)) => Some(s1.as_bytes().cmp(s2.as_bytes())),
 
             // strings of different types
-            (Value::ShortStr(len1, s1), Value::MidStr(s2)) => Some(s1[..*len1 as usize].cmp(&s2.1[..s2.0 as usize])),
-            (Value::ShortStr(len1, s1), Value::LongStr(s2)) => Some(s1[..*len1 as usize].cmp(s2)),
-            (Value::MidStr(s1), Value::ShortStr(len2, s2)) => Some(s1.1[..s1.0 as usize].cmp(&s2[..*len2 as usize])),
-            (Value::MidStr(s1), Value::LongStr(s2)) => Some(s1.1[..s1.0 as usize].cmp(s2)),
-            (Value::LongStr(s1), Value::ShortStr(len2, s2)) => Some(s1.as_ref().as_slice().cmp(&s2[..*len2 as usize])),
-            (Value::LongStr(s1), Value::MidStr(s2)) => Some(s1.as_ref().as_slice().cmp(&s2.1[..s2.0 as usize])),
+            (Value::ShortStr(len1, s1), Value::MidStr(s2)) => Some(s1[..*len1 as usize].cmp(s2.as_bytes())),
+            (Value::ShortStr(len1, s1), Value::LongStr(s2)) => Some(s1[..*len1 as usize].cmp(s2.as_bytes())),
+            (Value::MidStr(s1), Value::ShortStr(len2, s2)) => Some(s1.as_bytes().cmp(&s2[..*len2 as usize])),
+            (Value::MidStr(s1), Value::LongStr(s2)) => Some(s1.as_bytes().cmp(s2.as_bytes())),
+            (Value::LongStr(s1), Value::ShortStr(len2, s2)) => Some(s1.as_bytes().cmp(&s2[..*len2 as usize])),
+            (Value::LongStr(s1), Value::MidStr(s2)) => Some(s1.as_bytes().cmp(s2.as_bytes())),
 
             (_, _) => None,
         }
@@ -166,8 +578,12 @@ impl PartialOrd for Value {
 
 impl Value {
     pub fn same(&self, other: &Self) -> bool {
-        // eliminate Integer and Float with same number value
-        mem::discriminant(self) == mem::discriminant(other) && self == other
+        match (self, other) {
+            // distinguish 0.0 and -0.0
+            (Value::Float(f1), Value::Float(f2)) => f1.to_bits() == f2.to_bits(),
+            // eliminate Integer and Float with same number value
+            _ => mem::discriminant(self) == mem::discriminant(other) && self == other,
+        }
     }
     pub fn ty(&self) -> &'static str {
         match self {
@@ -183,15 +599,38 @@ impl Value {
             &Value::RustClosure(_) => "function",
             &Value::LuaFunction(_) => "function",
             &Value::LuaClosure(_) => "function",
+            &Value::UserData(_) => "userdata",
+        }
+    }
+
+    pub fn metatable(&self) -> Option<Rc<RefCell<Table>>> {
+        match self {
+            Value::Table(t) => t.borrow().metatable.clone(),
+            Value::UserData(u) => u.metatable.clone(),
+            _ => None,
         }
     }
 
     pub fn index(&self, key: &Value) -> Value {
         match self {
             Value::Table(t) => t.borrow().index(key).clone(),
+            Value::UserData(u) => {
+                // only table-type __index is supported now
+                let Some(meta) = &u.metatable else {
+                    panic!("attempt to index a userdata value");
+                };
+                let meta_index = meta.borrow().index(&"__index".into()).clone();
+                meta_index.index(key)
+            }
             _ => todo!("meta __index"),
         }
     }
+    pub fn index_hinted(&self, key: &Value, hint: &Cell<u32>) -> Value {
+        match self {
+            Value::Table(t) => t.borrow().index_hinted(key, hint).clone(),
+            _ => self.index(key),
+        }
+    }
     pub fn index_array(&self, i: i64) -> Value {
         match self {
             Value::Table(t) => t.borrow().index_array(i).clone(),
@@ -205,6 +644,12 @@ impl Value {
             _ => todo!("meta __index"),
         }
     }
+    pub fn new_index_hinted(&self, key: &Value, value: Value, hint: &Cell<u32>) {
+        match self {
+            Value::Table(t) => t.borrow_mut().new_index_hinted(key, value, hint),
+            _ => self.new_index(key.clone(), value),
+        }
+    }
     pub fn new_index_array(&self, i: i64, value: Value) {
         match self {
             Value::Table(t) => t.borrow_mut().new_index_array(i, value),
@@ -212,25 +657,68 @@ impl Value {
         }
     }
 
-    pub fn concat(&self, v2: &Self) -> Self {
-        let s1: &[u8] = self.as_ref();
+    // Convert numbers and numeric strings to number, following the
+    // syntax of Lua numerals. This is used by `tonumber` and the
+    // coercions in arithmetic.
+    pub fn to_number(&self) -> Option<Value> {
+        match self {
+            Value::Integer(_) | Value::Float(_) => Some(self.clone()),
+            Value::ShortStr(_, _) | Value::MidStr(_) | Value::LongStr(_) =>
+                str_to_number(self.as_ref()),
+            _ => None,
+        }
+    }
+    pub fn to_float(&self) -> Option<f64> {
+        match self.to_number()? {
+            Value::Integer(i) => Some(i as f64),
+            Value::Float(f) => Some(f),
+            _ => unreachable!(),
+        }
+    }
+    // float is converted only if it has an exact integer representation
+    pub fn to_integer(&self) -> Option<i64> {
+        match self.to_number()? {
+            Value::Integer(i) => Some(i),
+            Value::Float(f) => ftoi(f),
+            _ => unreachable!(),
+        }
+    }
 
-        match v2 {
-            Value::Integer(_) => todo!("int"),
-            Value::Float(_) => todo!("float"),
-            _ => {
-                let s2: &[u8] = v2.as_ref();
-                let l1 = s1.len();
-                let l2 = s2.len();
-                if l1 + l2 < MID_STR_MAX {
-                    let mut buf = [0; MID_STR_MAX];
-                    buf[..l1].copy_from_slice(s1);
-                    buf[l1..l1+l2].copy_from_slice(s2);
-                    buf[..l1+l2].into()
-                } else {
-                    [s1, s2].concat().into()
-                }
+    // Convert strings and numbers to string, for `..` and `tostring`.
+    // Integers are formatted as "%d", and floats as "%.14g".
+    pub fn to_str(&self) -> Option<Cow<'_, [u8]>> {
+        match self {
+            Value::ShortStr(_, _) | Value::MidStr(_) | Value::LongStr(_) =>
+                Some(Cow::Borrowed(self.as_ref())),
+            Value::Integer(i) => Some(Cow::Owned(i.to_string().into_bytes())),
+            Value::Float(f) => Some(Cow::Owned(fmt_float(*f).into_bytes())),
+            _ => None,
+        }
+    }
+
+    // Concatenate all values, for `..`. The output length is counted
+    // first, so the string type is decided and the buffer is allocated
+    // only once.
+    pub fn concat<'a>(vs: impl IntoIterator<Item = &'a Value>) -> Self {
+        let strs: Vec<_> = vs.into_iter()
+            .map(|v| v.to_str().unwrap_or_else(|| panic!("attempt to concatenate a {} value", v.ty())))
+            .collect();
+
+        let len = strs.iter().map(|s| s.len()).sum();
+        if len < MID_STR_MAX {
+            let mut buf = [0; MID_STR_MAX];
+            let mut i = 0;
+            for s in strs.iter() {
+                buf[i..i+s.len()].copy_from_slice(s);
+                i += s.len();
             }
+            buf[..len].into()
+        } else {
+            let mut buf = Vec::with_capacity(len);
+            for s in strs.iter() {
+                buf.extend_from_slice(s);
+            }
+            buf.into()
         }
     }
 }
@@ -248,17 +736,24 @@ impl Hash for Value {
                     (f.to_bits() as i64).hash(state)
                 }
             Value::ShortStr(len, buf) => buf[..*len as usize].hash(state),
-            Value::MidStr(s) => s.1[..s.0 as usize].hash(state),
-            Value::LongStr(s) => s.hash(state),
+            Value::MidStr(s) => s.hash.hash(state),
+            Value::LongStr(s) => s.hash_code().hash(state),
             Value::Table(t) => Rc::as_ptr(t).hash(state),
             Value::RustFunction(f) => (*f as *const usize).hash(state),
             Value::RustClosure(f) => Rc::as_ptr(f).hash(state),
             Value::LuaFunction(f) => Rc::as_ptr(f).hash(state),
             Value::LuaClosure(f) => Rc::as_ptr(f).hash(state),
+            Value::UserData(u) => Rc::as_ptr(u).hash(state),
         }
     }
 }
 
+impl From<Table> for Value {
+    fn from(t: Table) -> Self {
+        Value::Table(Rc::new(RefCell::new(t)))
+    }
+}
+
 impl From<()> for Value {
     fn from(_: ()) -> Self {
         Value::Nil
@@ -286,7 +781,7 @@ impl From<i64> for Value {
 // convert &[u8], Vec<u8>, &str and String into Value
 impl From<&[u8]> for Value {
     fn from(v: &[u8]) -> Self {
-        vec_to_short_mid_str(v).unwrap_or_else(||Value::LongStr(Rc::new(v.to_vec())))
+        vec_to_short_mid_str(v).unwrap_or_else(||Value::LongStr(Rc::new(LongStr::new(v.to_vec()))))
     }
 }
 impl From<&str> for Value {
@@ -297,7 +792,7 @@ impl From<&str> for Value {
 
 impl From<Vec<u8>> for Value {
     fn from(v: Vec<u8>) -> Self {
-        vec_to_short_mid_str(&v).unwrap_or_else(||Value::LongStr(Rc::new(v)))
+        vec_to_short_mid_str(&v).unwrap_or_else(||Value::LongStr(Rc::new(LongStr::new(v))))
     }
 }
 impl From<String> for Value {
@@ -314,9 +809,7 @@ fn vec_to_short_mid_str(v: &[u8]) -> Option<Value> {
         Some(Value::ShortStr(len as u8, buf))
 
     } else if len <= MID_STR_MAX {
-        let mut buf = [0; MID_STR_MAX];
-        buf[..len].copy_from_slice(v);
-        Some(Value::MidStr(Rc::new((len as u8, buf))))
+        Some(Value::MidStr(Rc::new(MidStr::new(v))))
 
     } else {
         None
@@ -327,8 +820,8 @@ impl AsRef<[u8]> for Value {
     fn as_ref(&self) -> &[u8] {
         match self {
             Value::ShortStr(len, buf) => &buf[..*len as usize],
-            Value::MidStr(s) => &s.1[..s.0 as usize],
-            Value::LongStr(s) => s,
+            Value::MidStr(s) => s.as_bytes(),
+            Value::LongStr(s) => s.as_bytes(),
             _ => panic!("invalid string Value"),
         }
     }
@@ -351,10 +844,12 @@ impl From<&Value> for i64 {
         match v {
             Value::Integer(i) => *i,
             Value::Float(f) => *f as i64,
-            Value::ShortStr(_, _) => todo!("tonumber"),
-            Value::MidStr(_) => todo!("tonumber"),
-            Value::LongStr(_) => todo!("tonumber"),
-            _ => panic!("invalid string Value"),
+            Value::ShortStr(_, _) | Value::MidStr(_) | Value::LongStr(_) =>
+                match v.to_number() {
+                    Some(n) => i64::from(&n),
+                    None => panic!("invalid number string"),
+                }
+            _ => panic!("invalid number Value"),
         }
     }
 }
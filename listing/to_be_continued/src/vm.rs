@@ -1,61 +1,74 @@
 use std::rc::Rc;
 use std::cell::RefCell;
 use std::cmp::Ordering;
+use std::io::Read;
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
 use crate::bytecode::ByteCode;
-use crate::value::{Value, Table};
-use crate::parse::{FuncProto, UpIndex};
-use crate::utils::{ftoi, set_vec};
+use crate::value::{Value, Table, StrTable};
+use crate::parse::{self, FuncProto, UpIndex};
+use crate::packed::{Slot, ValueRef, to_slot, from_slot, slot_ref, slot_str};
+use crate::utils::{ftoi, fb2int, set_vec, idiv_int, idiv_float, mod_int, mod_float, shift_left, shift_right};
+use crate::{lib_base, lib_package, lib_math, lib_io, lib_os, lib_utf8};
+use crate::lib_package::ModuleLoader;
 
 // TODO move these library functions out
-fn lib_print(state: &mut ExeState) -> i32 {
+fn lib_print(state: &mut ExeState) -> Result<i32, LuaError> {
     for i in 1 ..= state.get_top() {
         if i != 1 {
             print!("\t");
         }
-        print!("{}", state.get::<&Value>(i).to_string());
+        print!("{}", *state.arg(i));
     }
     println!("");
-    0
+    Ok(0)
 }
-fn lib_type(state: &mut ExeState) -> i32 {
-    let ty = state.get::<&Value>(1).ty();
+fn lib_type(state: &mut ExeState) -> Result<i32, LuaError> {
+    let ty = state.arg(1).ty();
     state.push(ty);
-    1
+    Ok(1)
 }
-fn test_new_counter(state: &mut ExeState) -> i32 {
+fn test_new_counter(state: &mut ExeState) -> Result<i32, LuaError> {
     let mut i = 0_i32;
     let c = move |_: &mut ExeState| {
         i += 1;
         println!("counter: {i}");
-        0
+        Ok(0)
     };
     state.push(Value::RustClosure(Rc::new(RefCell::new(Box::new(c)))));
-    1
+    Ok(1)
 }
-fn ipairs_aux(state: &mut ExeState) -> i32 {
-    let table = match state.get::<&Value>(1) {
+fn ipairs_aux(state: &mut ExeState) -> Result<i32, LuaError> {
+    let table = state.arg(1);
+    let table = match &*table {
         Value::Table(t) => t.borrow(),
         _ => panic!("ipairs non-table"),
     };
 
+    // stop at the first nil value
     let i: i64 = state.get(2);
-    if i < 0 || i as usize >= table.array.len() {
-        return 0;
-    }
-
-    let v = table.array[i as usize].clone();
+    let v = table.index_array(i + 1).clone();
     drop(table);
+    if v == Value::Nil {
+        return Ok(0);
+    }
 
     state.push(i + 1);
     state.push(v);
-    2
+    Ok(2)
 }
 
-fn ipairs(state: &mut ExeState) -> i32 {
+fn ipairs(state: &mut ExeState) -> Result<i32, LuaError> {
     state.push(Value::RustFunction(ipairs_aux));
-    state.push(state.get::<&Value>(1).clone());
+    state.push(state.arg(1).clone());
     state.push(0);
-    3
+    Ok(3)
+}
+
+// errors which unwind the Lua stack back to the host
+#[derive(Debug, PartialEq)]
+pub enum LuaError {
+    Exit(i32), // by `os.exit()`
 }
 
 #[derive(Debug, PartialEq)]
@@ -65,15 +78,15 @@ pub enum Upvalue {
 }
 
 impl Upvalue {
-    fn get<'a>(&'a self, stack: &'a Vec<Value>) -> &'a Value {
+    fn get<'a>(&'a self, stack: &'a Vec<Slot>) -> ValueRef<'a> {
         match self {
-            Upvalue::Open(i) => &stack[*i],
-            Upvalue::Closed(v) => &v,
+            Upvalue::Open(i) => slot_ref(&stack[*i]),
+            Upvalue::Closed(v) => v.into(),
         }
     }
-    fn set(&mut self, stack: &mut Vec<Value>, value: Value) {
+    fn set(&mut self, stack: &mut Vec<Slot>, value: Value) {
         match self {
-            Upvalue::Open(i) => stack[*i] = value,
+            Upvalue::Open(i) => stack[*i] = to_slot(value),
             Upvalue::Closed(v) => *v = value,
         }
     }
@@ -103,8 +116,14 @@ pub struct LuaClosure {
 
 // global execute state
 pub struct ExeState {
-    stack: Vec::<Value>,
+    stack: Vec::<Slot>, // see packed.rs
     base: usize, // stack base of current function
+    tbc_list: Vec<usize>, // stack indexes of to-be-closed variables
+    globals: Rc<RefCell<Table>>,
+    loaded: Rc<RefCell<Table>>, // loaded modules, same with `package.loaded`
+    peephole: bool, // optimize byte codes of loaded chunks
+    optimize: bool, // compile loaded chunks through the IR optimizer
+    strings: RefCell<StrTable>, // interned strings, see StrTable
 }
 
 impl ExeState {
@@ -115,17 +134,41 @@ impl ExeState {
         env.map.insert("type".into(), Value::RustFunction(lib_type));
         env.map.insert("ipairs".into(), Value::RustFunction(ipairs));
         env.map.insert("new_counter".into(), Value::RustFunction(test_new_counter));
+        lib_base::open(&mut env);
 
-        ExeState {
-            // 0: un-used entry function, 1: `_ENV` argument
-            stack: vec![Value::Nil, Value::Table(Rc::new(RefCell::new(env)))],
+        let loaded = Rc::new(RefCell::new(Table::new(0, 8)));
+        let package = lib_package::open(&loaded);
+        env.map.insert("require".into(), Value::RustFunction(lib_package::lib_require));
+
+        let libs = [
+            ("package", package),
+            ("math", lib_math::open()),
+            ("io", lib_io::open()),
+            ("os", lib_os::open()),
+            ("utf8", lib_utf8::open()),
+        ];
+        for (name, lib) in libs {
+            loaded.borrow_mut().map.insert(name.into(), lib.clone());
+            env.map.insert(name.into(), lib);
+        }
 
-            // always an entry function, even not used
-            base: 1,
+        let globals = Rc::new(RefCell::new(env));
+        globals.borrow_mut().map.insert("_G".into(), Value::Table(globals.clone()));
+        loaded.borrow_mut().map.insert("_G".into(), Value::Table(globals.clone()));
+
+        ExeState {
+            stack: Vec::new(),
+            base: 0,
+            tbc_list: Vec::new(),
+            globals,
+            loaded,
+            peephole: false,
+            optimize: false,
+            strings: RefCell::new(StrTable::new()),
         }
     }
 
-    pub fn execute(&mut self, proto: &FuncProto, upvalues: &Vec<Rc<RefCell<Upvalue>>>) -> usize {
+    pub fn execute(&mut self, proto: &FuncProto, upvalues: &Vec<Rc<RefCell<Upvalue>>>) -> Result<usize, LuaError> {
 
         // open brokers between local variables and upvalues
         let mut open_brokers: Vec<OpenBroker> = Vec::new();
@@ -149,12 +192,22 @@ impl ExeState {
                 // local variable
                 ByteCode::LoadConst(dst, c) => {
                     let v = proto.constants[c as usize].clone();
-                    self.set_stack(dst, v);
+                    self.set_stack_slot(dst, v);
+                }
+                ByteCode::LoadConstX(dst, low) => {
+                    // the high 16 bits of constant index are in ExtraArg
+                    pc += 1;
+                    let ByteCode::ExtraArg(high) = proto.byte_codes[pc] else {
+                        panic!("expect ExtraArg");
+                    };
+                    let c = (high as usize) << 16 | low as usize;
+                    let v = proto.constants[c].clone();
+                    self.set_stack_slot(dst, v);
                 }
                 ByteCode::LoadNil(dst, n) => {
                     let begin = self.base + dst as usize;
                     if begin < self.stack.len() {
-                        self.stack[begin..].fill(Value::Nil);
+                        self.stack[begin..].fill(to_slot(Value::Nil));
                     }
                     self.fill_stack_nil(dst, n as usize);
                 }
@@ -165,8 +218,14 @@ impl ExeState {
                     self.set_stack(dst, Value::Integer(i as i64));
                 }
                 ByteCode::Move(dst, src) => {
-                    let v = self.get_stack(src).clone();
-                    self.set_stack(dst, v);
+                    let v = self.get_stack_slot(src).clone();
+                    self.set_stack_slot(dst, v);
+                }
+                ByteCode::Move2(dst, src1, src2) => {
+                    let v = self.get_stack_slot(src1).clone();
+                    self.set_stack_slot(dst, v);
+                    let v = self.get_stack_slot(src2).clone();
+                    self.set_stack_slot(dst + 1, v);
                 }
 
                 // upvalues
@@ -179,7 +238,7 @@ impl ExeState {
                     upvalues[dst as usize].borrow_mut().set(&mut self.stack, v);
                 }
                 ByteCode::SetUpvalueConst(dst, src) => {
-                    let v = proto.constants[src as usize].clone();
+                    let v = proto.constant(src as usize).clone();
                     upvalues[dst as usize].borrow_mut().set(&mut self.stack, v);
                 }
                 ByteCode::Close(ilocal) => {
@@ -187,11 +246,30 @@ impl ExeState {
                     let from = open_brokers.binary_search_by_key(&ilocal, |b| b.ilocal)
                         .unwrap_or_else(|i| i);
                     self.close_brokers(open_brokers.drain(from..));
+                    self.close_tbc(ilocal, &Value::Nil)?;
+                }
+                ByteCode::Tbc(ilocal) => {
+                    // nil and false are ignored
+                    let v = self.get_stack(ilocal);
+                    if bool::from(&*v) {
+                        if close_metamethod(&v) == Value::Nil {
+                            panic!("variable got a non-closable value");
+                        }
+                        self.tbc_list.push(self.base + ilocal as usize);
+                    }
                 }
 
                 // table
                 ByteCode::NewTable(dst, narray, nmap) => {
-                    let table = Table::new(narray as usize, nmap as usize);
+                    let table = Table::new(fb2int(narray), fb2int(nmap));
+                    self.set_stack(dst, Value::Table(Rc::new(RefCell::new(table))));
+                }
+                ByteCode::NewTableConst(dst, i) => {
+                    let template = proto.constant(i as usize);
+                    let Value::Table(template) = &*template else {
+                        panic!("invalid table template");
+                    };
+                    let table = template.borrow().clone_template();
                     self.set_stack(dst, Value::Table(Rc::new(RefCell::new(table))));
                 }
                 ByteCode::SetTable(t, k, v) => {
@@ -200,9 +278,9 @@ impl ExeState {
                     self.get_stack(t).new_index(key, value);
                 }
                 ByteCode::SetField(t, k, v) => {
-                    let key = proto.constants[k as usize].clone();
+                    let key = &proto.constant(k as usize);
                     let value = self.get_stack(v).clone();
-                    self.get_stack(t).new_index(key, value);
+                    self.get_stack(t).new_index_hinted(key, value, &proto.caches[pc]);
                 }
                 ByteCode::SetInt(t, i, v) => {
                     let value = self.get_stack(v).clone();
@@ -210,19 +288,29 @@ impl ExeState {
                 }
                 ByteCode::SetTableConst(t, k, v) => {
                     let key = self.get_stack(k).clone();
-                    let value = proto.constants[v as usize].clone();
+                    let value = proto.constant(v as usize).clone();
                     self.get_stack(t).new_index(key, value);
                 }
                 ByteCode::SetFieldConst(t, k, v) => {
-                    let key = proto.constants[k as usize].clone();
-                    let value = proto.constants[v as usize].clone();
-                    self.get_stack(t).new_index(key, value);
+                    let key = &proto.constant(k as usize);
+                    let value = proto.constant(v as usize).clone();
+                    self.get_stack(t).new_index_hinted(key, value, &proto.caches[pc]);
                 }
                 ByteCode::SetIntConst(t, i, v) => {
-                    let value = proto.constants[v as usize].clone();
+                    let value = proto.constant(v as usize).clone();
                     self.get_stack(t).new_index_array(i as i64, value);
                 }
-                ByteCode::SetList(table, n) => {
+                ByteCode::SetList(table, n, nbatch) => {
+                    // the number of batches overflowing u8 is in ExtraArg
+                    let nbatch = if nbatch == u8::MAX {
+                        pc += 1;
+                        let ByteCode::ExtraArg(nbatch) = proto.byte_codes[pc] else {
+                            panic!("expect ExtraArg");
+                        };
+                        nbatch as usize
+                    } else {
+                        nbatch as usize
+                    };
                     let ivalue = self.base + table as usize + 1;
                     let Value::Table(table) = self.get_stack(table).clone() else {
                         panic!("not table");
@@ -233,17 +321,17 @@ impl ExeState {
                     } else {
                         ivalue + n as usize
                     };
-                    let values = self.stack.drain(ivalue .. end);
-                    table.borrow_mut().array.extend(values);
+                    let values = self.stack.drain(ivalue .. end).map(from_slot);
+                    table.borrow_mut().set_list(nbatch * 50, values);
                 }
                 ByteCode::GetTable(dst, t, k) => {
                     let key = self.get_stack(k);
-                    let value = self.get_stack(t).index(key);
+                    let value = self.get_stack(t).index(&key);
                     self.set_stack(dst, value);
                 }
                 ByteCode::GetField(dst, t, k) => {
-                    let key = &proto.constants[k as usize];
-                    let value = self.get_stack(t).index(key);
+                    let key = &proto.constant(k as usize);
+                    let value = self.get_stack(t).index_hinted(key, &proto.caches[pc]);
                     self.set_stack(dst, value);
                 }
                 ByteCode::GetInt(dst, t, k) => {
@@ -252,8 +340,8 @@ impl ExeState {
                 }
                 ByteCode::GetFieldSelf(dst, t, k) => {
                     let table = self.get_stack(t).clone();
-                    let key = &proto.constants[k as usize];
-                    let value = table.index(key).clone();
+                    let key = &proto.constant(k as usize);
+                    let value = table.index_hinted(key, &proto.caches[pc]);
                     self.set_stack(dst, value);
                     self.set_stack(dst+1, table);
                 }
@@ -265,21 +353,21 @@ impl ExeState {
                 // I do not know how to move this piece of code into a
                 // function because of the `borrow()`.
                 ByteCode::SetUpField(t, k, v) => {
-                    let key = proto.constants[k as usize].clone();
+                    let key = &proto.constant(k as usize);
                     let value = self.get_stack(v).clone();
                     upvalues[t as usize].borrow().get(&self.stack)
-                        .new_index(key, value);
+                        .new_index_hinted(key, value, &proto.caches[pc]);
                 }
                 ByteCode::SetUpFieldConst(t, k, v) => {
-                    let key = proto.constants[k as usize].clone();
-                    let value = proto.constants[v as usize].clone();
+                    let key = &proto.constant(k as usize);
+                    let value = proto.constant(v as usize).clone();
                     upvalues[t as usize].borrow().get(&self.stack)
-                        .new_index(key, value);
+                        .new_index_hinted(key, value, &proto.caches[pc]);
                 }
                 ByteCode::GetUpField(dst, t, k) => {
-                    let key = &proto.constants[k as usize];
+                    let key = &proto.constant(k as usize);
                     let value = upvalues[t as usize].borrow().get(&self.stack)
-                        .index(key).clone();
+                        .index_hinted(key, &proto.caches[pc]);
                     self.set_stack(dst, value);
                 }
 
@@ -287,26 +375,30 @@ impl ExeState {
                 ByteCode::Jump(jmp) => {
                     pc = (pc as isize + jmp as isize) as usize;
                 }
+                ByteCode::LongJump(high, low) => {
+                    let jmp = high as isize * 0x10000 + low as isize;
+                    pc = (pc as isize + jmp) as usize;
+                }
                 ByteCode::TestAndJump(icondition, jmp) => {
-                    if self.get_stack(icondition).into() { // jump if true
+                    if bool::from(&*self.get_stack(icondition)) { // jump if true
                         pc = (pc as isize + jmp as isize) as usize;
                     }
                 }
                 ByteCode::TestOrJump(icondition, jmp) => {
-                    if self.get_stack(icondition).into() {} else { // jump if false
+                    if bool::from(&*self.get_stack(icondition)) {} else { // jump if false
                         pc = (pc as isize + jmp as isize) as usize;
                     }
                 }
                 ByteCode::TestAndSetJump(dst, icondition, jmp) => {
                     let condition = self.get_stack(icondition);
-                    if condition.into() { // set and jump if true
+                    if bool::from(&*condition) { // set and jump if true
                         self.set_stack(dst, condition.clone());
                         pc += jmp as usize;
                     }
                 }
                 ByteCode::TestOrSetJump(dst, icondition, jmp) => {
                     let condition = self.get_stack(icondition);
-                    if condition.into() {} else { // set and jump if false
+                    if bool::from(&*condition) {} else { // set and jump if false
                         self.set_stack(dst, condition.clone());
                         pc += jmp as usize;
                     }
@@ -317,29 +409,31 @@ impl ExeState {
                     // clear into 2 cases: integer and float
                     // stack: i, limit, step
                     if let (&Value::Integer(mut i), &Value::Integer(step)) =
-                            (self.get_stack(dst), self.get_stack(dst + 2)) {
+                            (&*self.get_stack(dst), &*self.get_stack(dst + 2)) {
                         // integer case
                         if step == 0 {
                             panic!("0 step in numerical for");
                         }
-                        let limit = match self.get_stack(dst + 1) {
+                        let limit = match &*self.get_stack(dst + 1) {
                             &Value::Integer(limit) => limit,
-                            &Value::Float(limit) => {
-                                let limit = for_int_limit(limit, step>0, &mut i);
+                            v => {
+                                let limit = match v.to_number() {
+                                    Some(Value::Integer(limit)) => limit,
+                                    Some(Value::Float(limit)) => for_int_limit(limit, step>0, &mut i),
+                                    _ => panic!("'for' limit must be a number"),
+                                };
                                 self.set_stack(dst+1, Value::Integer(limit));
                                 limit
                             }
-                            // TODO convert string
-                            _ => panic!("invalid limit type"),
                         };
                         if !for_check(i, limit, step>0) {
                             pc += jmp as usize;
                         }
                     } else {
                         // float case
-                        let i = self.make_float(dst);
-                        let limit = self.make_float(dst+1);
-                        let step = self.make_float(dst+2);
+                        let i = self.make_float(dst, "initial");
+                        let limit = self.make_float(dst+1, "limit");
+                        let step = self.make_float(dst+2, "step");
                         if step == 0.0 {
                             panic!("0 step in numerical for");
                         }
@@ -350,22 +444,27 @@ impl ExeState {
                 }
                 ByteCode::ForLoop(dst, jmp) => {
                     // stack: i, limit, step
-                    match (self.get_stack(dst + 1), self.get_stack(dst + 2)) {
+                    match (&*self.get_stack(dst + 1), &*self.get_stack(dst + 2)) {
                         (&Value::Integer(limit), &Value::Integer(step)) => {
-                            let Value::Integer(i) = self.get_stack_mut(dst) else {
+                            let Value::Integer(i) = *self.get_stack(dst) else {
                                 panic!("xxx");
                             };
-                            *i += step;
-                            if for_check(*i, limit, step>0) {
-                                pc -= jmp as usize;
+                            // overflow means passing the limit
+                            match i.checked_add(step) {
+                                Some(next) if for_check(next, limit, step>0) => {
+                                    self.set_stack(dst, Value::Integer(next));
+                                    pc -= jmp as usize;
+                                }
+                                _ => (),
                             }
                         }
                         (&Value::Float(limit), &Value::Float(step)) => {
-                            let Value::Float(i) = self.get_stack_mut(dst) else {
+                            let Value::Float(i) = *self.get_stack(dst) else {
                                 panic!("xxx");
                             };
-                            *i += step;
-                            if for_check(*i, limit, step>0.0) {
+                            let i = i + step;
+                            self.set_stack(dst, Value::Float(i));
+                            if for_check(i, limit, step>0.0) {
                                 pc -= jmp as usize;
                             }
                         }
@@ -381,7 +480,7 @@ impl ExeState {
                     //     iter-func, state, ctrl-var, ..., return-values
                     // - update ctrl-var, and clear middle values
                     //     iter-func, state, ctrl-var*, return-values
-                    let nret = self.call_function(iter, 2+1);
+                    let nret = self.call_function(iter, 2+1)?;
                     let iret = self.stack.len() - nret;
 
                     if nret > 0 && self.stack[iret] != Value::Nil {
@@ -389,10 +488,19 @@ impl ExeState {
                         // duplicate the first return value as ctrl-var,
                         // so it could be changed during loop.
                         let first_ret = self.stack[iret].clone();
-                        self.set_stack(iter + 2, first_ret);
 
-                        // move return values to @iter+3
-                        self.stack.drain(self.base + iter as usize + 3 .. iret);
+                        // move return values to @iter+3. They may lay
+                        // below @iter+3 if the iterator function has less
+                        // than 2 parameters, since they overwrite arguments.
+                        let ivar = self.base + iter as usize + 3;
+                        if iret >= ivar {
+                            self.stack.drain(ivar .. iret);
+                        } else {
+                            let rets: Vec<Slot> = self.stack.drain(iret..).collect();
+                            self.stack.resize(ivar, to_slot(Value::Nil));
+                            self.stack.extend(rets);
+                        }
+                        self.set_stack_slot(iter + 2, first_ret);
                         self.fill_stack_nil(iter + 3, nvar as usize);
 
                         // jump back to loop
@@ -406,7 +514,7 @@ impl ExeState {
 
                 // define closure
                 ByteCode::Closure(dst, inner) => {
-                    let Value::LuaFunction(inner_proto) = proto.constants[inner as usize].clone() else {
+                    let Value::LuaFunction(inner_proto) = proto.constant(inner as usize).clone() else {
                         panic!("must be funcproto");
                     };
 
@@ -433,7 +541,7 @@ impl ExeState {
 
                 // function call
                 ByteCode::Call(func, narg_plus, want_nret) => {
-                    let nret = self.call_function(func, narg_plus);
+                    let nret = self.call_function(func, narg_plus)?;
 
                     // move return values to @func
                     let iret = self.stack.len() - nret;
@@ -448,8 +556,29 @@ impl ExeState {
                         self.fill_stack_nil(func, want_nret);
                     }
                 }
+                ByteCode::CallField(func, t, k) => {
+                    let key = &proto.constant(k as usize);
+                    let value = self.get_stack(t).index_hinted(key, &proto.caches[pc]);
+                    self.set_stack(func, value);
+
+                    // same with Call(func, 1, 0)
+                    let nret = self.call_function(func, 1)?;
+                    let iret = self.stack.len() - nret;
+                    self.stack.drain(self.base+func as usize .. iret);
+                }
+                ByteCode::CallUpField(func, t, k) => {
+                    let key = &proto.constant(k as usize);
+                    let value = upvalues[t as usize].borrow().get(&self.stack)
+                        .index_hinted(key, &proto.caches[pc]);
+                    self.set_stack(func, value);
+
+                    // same with Call(func, 1, 0)
+                    let nret = self.call_function(func, 1)?;
+                    let iret = self.stack.len() - nret;
+                    self.stack.drain(self.base+func as usize .. iret);
+                }
                 ByteCode::CallSet(dst, func, narg_plus) => {
-                    let nret = self.call_function(func, narg_plus);
+                    let nret = self.call_function(func, narg_plus)?;
 
                     // set first return value to @dst directly
                     if nret == 0 {
@@ -474,6 +603,7 @@ impl ExeState {
 
                 ByteCode::Return(iret, nret) => {
                     self.close_brokers(open_brokers);
+                    self.close_tbc(self.base, &Value::Nil)?;
 
                     // if nret==0, return stack[iret .. ];
                     // otherwise, return stack[iret .. iret+nret] and truncate
@@ -487,15 +617,16 @@ impl ExeState {
                     //   #return-values by stack top.
                     let iret = self.base + iret as usize;
                     if nret == 0 {
-                        return self.stack.len() - iret;
+                        return Ok(self.stack.len() - iret);
                     } else {
                         self.stack.truncate(iret + nret as usize);
-                        return nret as usize;
+                        return Ok(nret as usize);
                     }
                 }
                 ByteCode::Return0 => {
                     self.close_brokers(open_brokers);
-                    return 0;
+                    self.close_tbc(self.base, &Value::Nil)?;
+                    return Ok(0);
                 }
 
                 ByteCode::VarArgs(dst, want) => {
@@ -519,15 +650,19 @@ impl ExeState {
 
                 // unops
                 ByteCode::Neg(dst, src) => {
-                    let value = match &self.get_stack(src) {
-                        Value::Integer(i) => Value::Integer(-i),
+                    let value = match &*self.get_stack(src) {
+                        Value::Integer(i) => Value::Integer(i.wrapping_neg()),
                         Value::Float(f) => Value::Float(-f),
-                        _ => panic!("invalid -"),
+                        v => match v.to_number() {
+                            Some(Value::Integer(i)) => Value::Integer(i.wrapping_neg()),
+                            Some(Value::Float(f)) => Value::Float(-f),
+                            _ => panic!("attempt to perform arithmetic on a {} value", v.ty()),
+                        }
                     };
                     self.set_stack(dst, value);
                 }
                 ByteCode::Not(dst, src) => {
-                    let value = match &self.get_stack(src) {
+                    let value = match &*self.get_stack(src) {
                         Value::Nil => Value::Boolean(true),
                         Value::Boolean(b) => Value::Boolean(!b),
                         _ => Value::Boolean(false),
@@ -535,82 +670,82 @@ impl ExeState {
                     self.set_stack(dst, value);
                 }
                 ByteCode::BitNot(dst, src) => {
-                    let value = match &self.get_stack(src) {
+                    let value = match &*self.get_stack(src) {
                         Value::Integer(i) => Value::Integer(!i),
-                        _ => panic!("invalid ~"),
+                        v => Value::Integer(!bitwise_coerce(v, &Value::Integer(0)).0),
                     };
                     self.set_stack(dst, value);
                 }
                 ByteCode::Len(dst, src) => {
-                    let value = match &self.get_stack(src) {
+                    let value = match &*self.get_stack(src) {
                         Value::ShortStr(len, _) => Value::Integer(*len as i64),
-                        Value::MidStr(s) => Value::Integer(s.0 as i64),
-                        Value::LongStr(s) => Value::Integer(s.len() as i64),
-                        Value::Table(t) => Value::Integer(t.borrow().array.len() as i64),
-                        _ => panic!("invalid -"),
+                        Value::MidStr(s) => Value::Integer(s.as_bytes().len() as i64),
+                        Value::LongStr(s) => Value::Integer(s.as_bytes().len() as i64),
+                        Value::Table(t) => Value::Integer(t.borrow().border()),
+                        v => panic!("attempt to get length of a {} value", v.ty()),
                     };
                     self.set_stack(dst, value);
                 }
 
                 // binops
                 ByteCode::Add(dst, a, b) => {
-                    let r = exe_binop(&self.get_stack(a), &self.get_stack(b), |a,b|a+b, |a,b|a+b);
+                    let r = exe_binop(&self.get_stack(a), &self.get_stack(b), i64::wrapping_add, |a,b|a+b);
                     self.set_stack(dst, r);
                 }
                 ByteCode::AddConst(dst, a, b) => {
-                    let r = exe_binop(&self.get_stack(a), &proto.constants[b as usize], |a,b|a+b, |a,b|a+b);
+                    let r = exe_binop(&self.get_stack(a), &proto.constant(b as usize), i64::wrapping_add, |a,b|a+b);
                     self.set_stack(dst, r);
                 }
                 ByteCode::AddInt(dst, a, i) => {
-                    let r = exe_binop_int(&self.get_stack(a), i, |a,b|a+b, |a,b|a+b);
+                    let r = exe_binop_int(&self.get_stack(a), i, i64::wrapping_add, |a,b|a+b);
                     self.set_stack(dst, r);
                 }
                 ByteCode::Sub(dst, a, b) => {
-                    let r = exe_binop(&self.get_stack(a), &self.get_stack(b), |a,b|a-b, |a,b|a-b);
+                    let r = exe_binop(&self.get_stack(a), &self.get_stack(b), i64::wrapping_sub, |a,b|a-b);
                     self.set_stack(dst, r);
                 }
                 ByteCode::SubConst(dst, a, b) => {
-                    let r = exe_binop(&self.get_stack(a), &proto.constants[b as usize], |a,b|a-b, |a,b|a-b);
+                    let r = exe_binop(&self.get_stack(a), &proto.constant(b as usize), i64::wrapping_sub, |a,b|a-b);
                     self.set_stack(dst, r);
                 }
                 ByteCode::SubInt(dst, a, i) => {
-                    let r = exe_binop_int(&self.get_stack(a), i, |a,b|a-b, |a,b|a-b);
+                    let r = exe_binop_int(&self.get_stack(a), i, i64::wrapping_sub, |a,b|a-b);
                     self.set_stack(dst, r);
                 }
                 ByteCode::Mul(dst, a, b) => {
-                    let r = exe_binop(&self.get_stack(a), &self.get_stack(b), |a,b|a*b, |a,b|a*b);
+                    let r = exe_binop(&self.get_stack(a), &self.get_stack(b), i64::wrapping_mul, |a,b|a*b);
                     self.set_stack(dst, r);
                 }
                 ByteCode::MulConst(dst, a, b) => {
-                    let r = exe_binop(&self.get_stack(a), &proto.constants[b as usize], |a,b|a*b, |a,b|a*b);
+                    let r = exe_binop(&self.get_stack(a), &proto.constant(b as usize), i64::wrapping_mul, |a,b|a*b);
                     self.set_stack(dst, r);
                 }
                 ByteCode::MulInt(dst, a, i) => {
-                    let r = exe_binop_int(&self.get_stack(a), i, |a,b|a*b, |a,b|a*b);
+                    let r = exe_binop_int(&self.get_stack(a), i, i64::wrapping_mul, |a,b|a*b);
                     self.set_stack(dst, r);
                 }
                 ByteCode::Mod(dst, a, b) => {
-                    let r = exe_binop(&self.get_stack(a), &self.get_stack(b), |a,b|a%b, |a,b|a%b);
+                    let r = exe_binop(&self.get_stack(a), &self.get_stack(b), mod_int, mod_float);
                     self.set_stack(dst, r);
                 }
                 ByteCode::ModConst(dst, a, b) => {
-                    let r = exe_binop(&self.get_stack(a), &proto.constants[b as usize], |a,b|a%b, |a,b|a%b);
+                    let r = exe_binop(&self.get_stack(a), &proto.constant(b as usize), mod_int, mod_float);
                     self.set_stack(dst, r);
                 }
                 ByteCode::ModInt(dst, a, i) => {
-                    let r = exe_binop_int(&self.get_stack(a), i, |a,b|a%b, |a,b|a%b);
+                    let r = exe_binop_int(&self.get_stack(a), i, mod_int, mod_float);
                     self.set_stack(dst, r);
                 }
                 ByteCode::Idiv(dst, a, b) => {
-                    let r = exe_binop(&self.get_stack(a), &self.get_stack(b), |a,b|a/b, |a,b|a/b);
+                    let r = exe_binop(&self.get_stack(a), &self.get_stack(b), idiv_int, idiv_float);
                     self.set_stack(dst, r);
                 }
                 ByteCode::IdivConst(dst, a, b) => {
-                    let r = exe_binop(&self.get_stack(a), &proto.constants[b as usize], |a,b|a/b, |a,b|a/b);
+                    let r = exe_binop(&self.get_stack(a), &proto.constant(b as usize), idiv_int, idiv_float);
                     self.set_stack(dst, r);
                 }
                 ByteCode::IdivInt(dst, a, i) => {
-                    let r = exe_binop_int(&self.get_stack(a), i, |a,b|a/b, |a,b|a/b);
+                    let r = exe_binop_int(&self.get_stack(a), i, idiv_int, idiv_float);
                     self.set_stack(dst, r);
                 }
                 ByteCode::Div(dst, a, b) => {
@@ -618,7 +753,7 @@ impl ExeState {
                     self.set_stack(dst, r);
                 }
                 ByteCode::DivConst(dst, a, b) => {
-                    let r = exe_binop_f(&self.get_stack(a), &proto.constants[b as usize], |a,b|a/b);
+                    let r = exe_binop_f(&self.get_stack(a), &proto.constant(b as usize), |a,b|a/b);
                     self.set_stack(dst, r);
                 }
                 ByteCode::DivInt(dst, a, i) => {
@@ -630,7 +765,7 @@ impl ExeState {
                     self.set_stack(dst, r);
                 }
                 ByteCode::PowConst(dst, a, b) => {
-                    let r = exe_binop_f(&self.get_stack(a), &proto.constants[b as usize], |a,b|a.powf(b));
+                    let r = exe_binop_f(&self.get_stack(a), &proto.constant(b as usize), |a,b|a.powf(b));
                     self.set_stack(dst, r);
                 }
                 ByteCode::PowInt(dst, a, i) => {
@@ -642,7 +777,7 @@ impl ExeState {
                     self.set_stack(dst, r);
                 }
                 ByteCode::BitAndConst(dst, a, b) => {
-                    let r = exe_binop_i(&self.get_stack(a), &proto.constants[b as usize], |a,b|a&b);
+                    let r = exe_binop_i(&self.get_stack(a), &proto.constant(b as usize), |a,b|a&b);
                     self.set_stack(dst, r);
                 }
                 ByteCode::BitAndInt(dst, a, i) => {
@@ -654,7 +789,7 @@ impl ExeState {
                     self.set_stack(dst, r);
                 }
                 ByteCode::BitOrConst(dst, a, b) => {
-                    let r = exe_binop_i(&self.get_stack(a), &proto.constants[b as usize], |a,b|a|b);
+                    let r = exe_binop_i(&self.get_stack(a), &proto.constant(b as usize), |a,b|a|b);
                     self.set_stack(dst, r);
                 }
                 ByteCode::BitOrInt(dst, a, i) => {
@@ -666,7 +801,7 @@ impl ExeState {
                     self.set_stack(dst, r);
                 }
                 ByteCode::BitXorConst(dst, a, b) => {
-                    let r = exe_binop_i(&self.get_stack(a), &proto.constants[b as usize], |a,b|a^b);
+                    let r = exe_binop_i(&self.get_stack(a), &proto.constant(b as usize), |a,b|a^b);
                     self.set_stack(dst, r);
                 }
                 ByteCode::BitXorInt(dst, a, i) => {
@@ -674,184 +809,215 @@ impl ExeState {
                     self.set_stack(dst, r);
                 }
                 ByteCode::ShiftL(dst, a, b) => {
-                    let r = exe_binop_i(&self.get_stack(a), &self.get_stack(b), |a,b|a<<b);
+                    let r = exe_binop_i(&self.get_stack(a), &self.get_stack(b), shift_left);
                     self.set_stack(dst, r);
                 }
                 ByteCode::ShiftLConst(dst, a, b) => {
-                    let r = exe_binop_i(&self.get_stack(a), &proto.constants[b as usize], |a,b|a<<b);
+                    let r = exe_binop_i(&self.get_stack(a), &proto.constant(b as usize), shift_left);
                     self.set_stack(dst, r);
                 }
                 ByteCode::ShiftLInt(dst, a, i) => {
-                    let r = exe_binop_int_i(&self.get_stack(a), i, |a,b|a<<b);
+                    let r = exe_binop_int_i(&self.get_stack(a), i, shift_left);
                     self.set_stack(dst, r);
                 }
                 ByteCode::ShiftR(dst, a, b) => {
-                    let r = exe_binop_i(&self.get_stack(a), &self.get_stack(b), |a,b|a>>b);
+                    let r = exe_binop_i(&self.get_stack(a), &self.get_stack(b), shift_right);
                     self.set_stack(dst, r);
                 }
                 ByteCode::ShiftRConst(dst, a, b) => {
-                    let r = exe_binop_i(&self.get_stack(a), &proto.constants[b as usize], |a,b|a>>b);
+                    let r = exe_binop_i(&self.get_stack(a), &proto.constant(b as usize), shift_right);
                     self.set_stack(dst, r);
                 }
                 ByteCode::ShiftRInt(dst, a, i) => {
-                    let r = exe_binop_int_i(&self.get_stack(a), i, |a,b|a>>b);
+                    let r = exe_binop_int_i(&self.get_stack(a), i, shift_right);
                     self.set_stack(dst, r);
                 }
 
                 ByteCode::Equal(a, b, r) => {
-                    if (self.get_stack(a) == self.get_stack(b)) == r {
+                    if (*self.get_stack(a) == *self.get_stack(b)) == r {
                         pc += 1;
                     }
                 }
                 ByteCode::EqualConst(a, b, r) => {
-                    if (self.get_stack(a) == &proto.constants[b as usize]) == r {
+                    if (*self.get_stack(a) == *proto.constant(b as usize)) == r {
                         pc += 1;
                     }
                 }
                 ByteCode::EqualInt(a, i, r) => {
-                    if let &Value::Integer(ii) = self.get_stack(a) {
-                        if (ii == i as i64) == r {
-                            pc += 1;
-                        }
+                    if (*self.get_stack(a) == Value::Integer(i as i64)) == r {
+                        pc += 1;
                     }
                 }
                 ByteCode::NotEq(a, b, r) => {
-                    if (self.get_stack(a) != self.get_stack(b)) == r {
+                    if (*self.get_stack(a) != *self.get_stack(b)) == r {
                         pc += 1;
                     }
                 }
                 ByteCode::NotEqConst(a, b, r) => {
-                    if (self.get_stack(a) != &proto.constants[b as usize]) == r {
+                    if (*self.get_stack(a) != *proto.constant(b as usize)) == r {
                         pc += 1;
                     }
                 }
                 ByteCode::NotEqInt(a, i, r) => {
-                    if let &Value::Integer(ii) = self.get_stack(a) {
-                        if (ii != i as i64) == r {
-                            pc += 1;
-                        }
+                    if (*self.get_stack(a) != Value::Integer(i as i64)) == r {
+                        pc += 1;
                     }
                 }
                 ByteCode::LesEq(a, b, r) => {
-                    let cmp = self.get_stack(a).partial_cmp(self.get_stack(b)).unwrap();
-                    if !matches!(cmp, Ordering::Greater) == r {
+                    let cmp = compare(&self.get_stack(a), &self.get_stack(b));
+                    if (matches!(cmp, Some(Ordering::Less | Ordering::Equal))) == r {
                         pc += 1;
                     }
                 }
                 ByteCode::LesEqConst(a, b, r) => {
-                    let cmp = self.get_stack(a).partial_cmp(&proto.constants[b as usize]).unwrap();
-                    if !matches!(cmp, Ordering::Greater) == r {
+                    let cmp = compare(&self.get_stack(a), &proto.constant(b as usize));
+                    if (matches!(cmp, Some(Ordering::Less | Ordering::Equal))) == r {
                         pc += 1;
                     }
                 }
                 ByteCode::LesEqInt(a, i, r) => {
-                    let a = match self.get_stack(a) {
-                        &Value::Integer(i) => i,
-                        &Value::Float(f) => f as i64,
-                        _ => panic!("invalid compare"),
-                    };
-                    if (a <= i as i64) == r {
+                    let cmp = compare(&self.get_stack(a), &Value::Integer(i as i64));
+                    if (matches!(cmp, Some(Ordering::Less | Ordering::Equal))) == r {
                         pc += 1;
                     }
                 }
                 ByteCode::GreEq(a, b, r) => {
-                    let cmp = self.get_stack(a).partial_cmp(self.get_stack(b)).unwrap();
-                    if !matches!(cmp, Ordering::Less) == r {
+                    let cmp = compare(&self.get_stack(a), &self.get_stack(b));
+                    if (matches!(cmp, Some(Ordering::Greater | Ordering::Equal))) == r {
                         pc += 1;
                     }
                 }
                 ByteCode::GreEqConst(a, b, r) => {
-                    let cmp = self.get_stack(a).partial_cmp(&proto.constants[b as usize]).unwrap();
-                    if !matches!(cmp, Ordering::Less) == r {
+                    let cmp = compare(&self.get_stack(a), &proto.constant(b as usize));
+                    if (matches!(cmp, Some(Ordering::Greater | Ordering::Equal))) == r {
                         pc += 1;
                     }
                 }
                 ByteCode::GreEqInt(a, i, r) => {
-                    let a = match self.get_stack(a) {
-                        &Value::Integer(i) => i,
-                        &Value::Float(f) => f as i64,
-                        _ => panic!("invalid compare"),
-                    };
-                    if (a >= i as i64) == r {
+                    let cmp = compare(&self.get_stack(a), &Value::Integer(i as i64));
+                    if (matches!(cmp, Some(Ordering::Greater | Ordering::Equal))) == r {
                         pc += 1;
                     }
                 }
                 ByteCode::Less(a, b, r) => {
-                    let cmp = self.get_stack(a).partial_cmp(self.get_stack(b)).unwrap();
-                    if matches!(cmp, Ordering::Less) == r {
+                    let cmp = compare(&self.get_stack(a), &self.get_stack(b));
+                    if (cmp == Some(Ordering::Less)) == r {
                         pc += 1;
                     }
                 }
                 ByteCode::LessConst(a, b, r) => {
-                    let cmp = self.get_stack(a).partial_cmp(&proto.constants[b as usize]).unwrap();
-                    if matches!(cmp, Ordering::Less) == r {
+                    let cmp = compare(&self.get_stack(a), &proto.constant(b as usize));
+                    if (cmp == Some(Ordering::Less)) == r {
                         pc += 1;
                     }
                 }
                 ByteCode::LessInt(a, i, r) => {
-                    let a = match self.get_stack(a) {
-                        &Value::Integer(i) => i,
-                        &Value::Float(f) => f as i64,
-                        _ => panic!("invalid compare"),
-                    };
-                    if (a < i as i64) == r {
+                    let cmp = compare(&self.get_stack(a), &Value::Integer(i as i64));
+                    if (cmp == Some(Ordering::Less)) == r {
                         pc += 1;
                     }
                 }
                 ByteCode::Greater(a, b, r) => {
-                    let cmp = self.get_stack(a).partial_cmp(self.get_stack(b)).unwrap();
-                    if matches!(cmp, Ordering::Greater) == r {
+                    let cmp = compare(&self.get_stack(a), &self.get_stack(b));
+                    if (cmp == Some(Ordering::Greater)) == r {
                         pc += 1;
                     }
                 }
                 ByteCode::GreaterConst(a, b, r) => {
-                    let cmp = self.get_stack(a).partial_cmp(&proto.constants[b as usize]).unwrap();
-                    if matches!(cmp, Ordering::Greater) == r {
+                    let cmp = compare(&self.get_stack(a), &proto.constant(b as usize));
+                    if (cmp == Some(Ordering::Greater)) == r {
                         pc += 1;
                     }
                 }
                 ByteCode::GreaterInt(a, i, r) => {
-                    let a = match self.get_stack(a) {
-                        &Value::Integer(i) => i,
-                        &Value::Float(f) => f as i64,
-                        _ => panic!("invalid compare"),
-                    };
-                    if (a > i as i64) == r {
+                    let cmp = compare(&self.get_stack(a), &Value::Integer(i as i64));
+                    if (cmp == Some(Ordering::Greater)) == r {
                         pc += 1;
                     }
                 }
 
+                ByteCode::EqualJump(a, b, jmp) => {
+                    if *self.get_stack(a) != *self.get_stack(b) {
+                        pc = (pc as isize + jmp as isize) as usize;
+                    }
+                }
+                ByteCode::EqualIntJump(a, i, jmp) => {
+                    if *self.get_stack(a) != Value::Integer(i as i64) {
+                        pc = (pc as isize + jmp as isize) as usize;
+                    }
+                }
+                ByteCode::EqualConstJump(a, b, jmp) => {
+                    if *self.get_stack(a) != *proto.constant(b as usize) {
+                        pc = (pc as isize + jmp as isize) as usize;
+                    }
+                }
+                ByteCode::LessJump(a, b, jmp) => {
+                    let cmp = compare(&self.get_stack(a), &self.get_stack(b));
+                    if cmp != Some(Ordering::Less) {
+                        pc = (pc as isize + jmp as isize) as usize;
+                    }
+                }
+                ByteCode::LesEqJump(a, b, jmp) => {
+                    let cmp = compare(&self.get_stack(a), &self.get_stack(b));
+                    if !matches!(cmp, Some(Ordering::Less | Ordering::Equal)) {
+                        pc = (pc as isize + jmp as isize) as usize;
+                    }
+                }
+                ByteCode::LessIntJump(a, i, jmp) => {
+                    let cmp = compare(&self.get_stack(a), &Value::Integer(i as i64));
+                    if cmp != Some(Ordering::Less) {
+                        pc = (pc as isize + jmp as isize) as usize;
+                    }
+                }
+                ByteCode::GreaterIntJump(a, i, jmp) => {
+                    let cmp = compare(&self.get_stack(a), &Value::Integer(i as i64));
+                    if cmp != Some(Ordering::Greater) {
+                        pc = (pc as isize + jmp as isize) as usize;
+                    }
+                }
+
                 ByteCode::SetFalseSkip(dst) => {
                     self.set_stack(dst, Value::Boolean(false));
                     pc += 1;
                 }
 
-                ByteCode::Concat(dst, a, b) => {
-                    let r = self.get_stack(a).concat(self.get_stack(b));
+                ByteCode::Concat(dst, first, n) => {
+                    let first = self.base + first as usize;
+                    let vs: Vec<ValueRef> = self.stack[first .. first + n as usize].iter().map(slot_ref).collect();
+                    let r = Value::concat(vs.iter().map(|v| &**v));
+                    let r = self.strings.get_mut().intern(r);
                     self.set_stack(dst, r);
                 }
+
+                // consumed by the previous byte code
+                ByteCode::ExtraArg(_) => panic!("unexpected ExtraArg"),
             }
 
             pc += 1;
         }
     }
 
-    fn get_stack(&self, dst: u8) -> &Value {
-        &self.stack[self.base + dst as usize]
-    }
-    fn get_stack_mut(&mut self, dst: u8) -> &mut Value {
-        &mut self.stack[self.base + dst as usize]
+    fn get_stack(&self, dst: u8) -> ValueRef<'_> {
+        slot_ref(&self.stack[self.base + dst as usize])
     }
     fn set_stack(&mut self, dst: u8, v: Value) {
-        set_vec(&mut self.stack, self.base + dst as usize, v);
+        set_vec(&mut self.stack, self.base + dst as usize, to_slot(v));
+    }
+    // access the slots directly to copy values, which saves decoding
+    // and encoding with nan-boxing
+    fn get_stack_slot(&self, dst: u8) -> &Slot {
+        &self.stack[self.base + dst as usize]
+    }
+    fn set_stack_slot(&mut self, dst: u8, s: Slot) {
+        set_vec(&mut self.stack, self.base + dst as usize, s);
     }
     fn fill_stack_nil(&mut self, base: u8, to: usize) {
-        self.stack.resize(self.base + base as usize + to, Value::Nil);
+        self.stack.resize(self.base + base as usize + to, to_slot(Value::Nil));
     }
 
     // call function
     // return the number of return values which are at the stack end
-    fn call_function(&mut self, func: u8, narg_plus: u8) -> usize {
+    fn call_function(&mut self, func: u8, narg_plus: u8) -> Result<usize, LuaError> {
         self.base += func as usize + 1; // get into new world
         let nret = self.do_call_function(narg_plus);
         self.base -= func as usize + 1; // come back
@@ -868,15 +1034,15 @@ impl ExeState {
     // After calling, the return values lay at the top of stack.
     //
     // Return the number of return values.
-    fn do_call_function(&mut self, narg_plus: u8) -> usize {
+    fn do_call_function(&mut self, narg_plus: u8) -> Result<usize, LuaError> {
         // drop potential temprary stack usage, for get_top()
         if narg_plus != 0 {
             self.stack.truncate(self.base + narg_plus as usize - 1);
         }
 
-        match self.stack[self.base - 1].clone() {
-            Value::RustFunction(f) => f(self) as usize,
-            Value::RustClosure(c) => c.borrow_mut()(self) as usize,
+        match slot_ref(&self.stack[self.base - 1]).clone() {
+            Value::RustFunction(f) => f(self).map(|n| n as usize),
+            Value::RustClosure(c) => c.borrow_mut()(self).map(|n| n as usize),
             Value::LuaFunction(f) => self.execute(&f, &Vec::new()),
             Value::LuaClosure(c) => self.execute(&c.proto, &c.upvalues),
             v => panic!("invalid function: {v:?}"),
@@ -885,35 +1051,215 @@ impl ExeState {
 
     fn close_brokers(&self, open_brokers: impl IntoIterator<Item = OpenBroker>) {
         for OpenBroker { ilocal, broker } in open_brokers {
-            let openi = broker.replace(Upvalue::Closed(self.stack[ilocal].clone()));
+            let openi = broker.replace(Upvalue::Closed(slot_ref(&self.stack[ilocal]).clone()));
             debug_assert_eq!(openi, Upvalue::Open(ilocal));
         }
     }
 
-    fn make_float(&mut self, dst: u8) -> f64 {
-        match self.get_stack(dst) {
+    // Call `__close` of to-be-closed variables at or above stack index
+    // @from, in reverse order. @err is the error object, or nil if
+    // closing normally.
+    fn close_tbc(&mut self, from: usize, err: &Value) -> Result<(), LuaError> {
+        while let Some(&i) = self.tbc_list.last() {
+            if i < from {
+                break;
+            }
+            self.tbc_list.pop();
+            let v = slot_ref(&self.stack[i]).clone();
+            let close = close_metamethod(&v);
+            if close == Value::Nil {
+                panic!("metamethod 'close' is not callable");
+            }
+            self.call(close, &[v, err.clone()])?;
+        }
+        Ok(())
+    }
+
+    // Close the to-be-closed variables above @ntbc in tbc_list when an
+    // error unwinds the frames. An error in `__close` replaces the
+    // original one, and the rest variables are closed still.
+    fn close_tbc_by_error(&mut self, ntbc: usize, mut e: Box<dyn Any + Send>) -> Box<dyn Any + Send> {
+        while self.tbc_list.len() > ntbc {
+            let err = error_value(&*e);
+            let from = *self.tbc_list.last().unwrap();
+            if let Err(e2) = panic::catch_unwind(AssertUnwindSafe(|| self.close_tbc(from, &err))) {
+                e = e2;
+            }
+        }
+        e
+    }
+
+    // convert the for-loop value to float, where @what is used in error message
+    fn make_float(&mut self, dst: u8, what: &str) -> f64 {
+        match &*self.get_stack(dst) {
             &Value::Float(f) => f,
-            &Value::Integer(i) => {
-                let f = i as f64;
+            v => {
+                let Some(f) = v.to_float() else {
+                    panic!("'for' {what} value must be a number");
+                };
                 self.set_stack(dst, Value::Float(f));
                 f
             }
-            // TODO convert string
-            ref v => panic!("not number {v:?}"),
         }
     }
 }
 
+impl Drop for ExeState {
+    fn drop(&mut self) {
+        // There are reference cycles through `package.loaded` and `_G`,
+        // e.g. `package.loaded.package` and `_G._G`. Break them, so the
+        // libraries and their resources (such as files of io) are released.
+        self.loaded.borrow_mut().map.clear();
+        self.globals.borrow_mut().map.clear();
+    }
+}
+
 // API
 impl<'a> ExeState {
     pub fn get_top(&self) -> usize {
         self.stack.len() - self.base
     }
-    pub fn get<T>(&'a self, i: usize) -> T where T: From<&'a Value> {
-        (&self.stack[self.base + i - 1]).into()
+    pub fn get<T>(&self, i: usize) -> T where T: for<'b> From<&'b Value> {
+        (&*slot_ref(&self.stack[self.base + i - 1])).into()
+    }
+    // the argument at @i, or nil if @i is out of range
+    pub fn arg(&'a self, i: usize) -> ValueRef<'a> {
+        self.stack.get(self.base + i - 1).map_or((&Value::Nil).into(), slot_ref)
+    }
+
+    // argument checkers for library functions
+    // numeric strings are accepted as numbers
+    pub fn check_number(&self, i: usize) -> f64 {
+        let v = self.arg(i);
+        v.to_float().unwrap_or_else(||
+            panic!("bad argument #{i} (number expected, got {})", v.ty()))
+    }
+    pub fn check_integer(&self, i: usize) -> i64 {
+        match self.arg(i).to_number() {
+            Some(Value::Integer(n)) => n,
+            Some(Value::Float(f)) => ftoi(f).unwrap_or_else(||
+                panic!("bad argument #{i} (number has no integer representation)")),
+            _ => panic!("bad argument #{i} (number expected, got {})", self.arg(i).ty()),
+        }
+    }
+    pub fn opt_integer(&self, i: usize, default: i64) -> i64 {
+        if *self.arg(i) == Value::Nil { default } else { self.check_integer(i) }
+    }
+    pub fn check_string(&'a self, i: usize) -> &'a [u8] {
+        match self.stack.get(self.base + i - 1).and_then(slot_str) {
+            Some(s) => s,
+            None => panic!("bad argument #{i} (string expected, got {})", self.arg(i).ty()),
+        }
     }
     pub fn push(&mut self, v: impl Into<Value>) {
-        self.stack.push(v.into());
+        self.stack.push(to_slot(v.into()));
+    }
+
+    pub fn set_peephole(&mut self, on: bool) {
+        self.peephole = on;
+    }
+    pub fn set_optimize(&mut self, on: bool) {
+        self.optimize = on;
+    }
+
+    pub fn set_global(&mut self, name: &str, v: impl Into<Value>) {
+        self.globals.borrow_mut().new_index(name.into(), v.into());
+    }
+    pub fn globals(&self) -> Value {
+        Value::Table(self.globals.clone())
+    }
+    pub fn loaded(&self) -> Value {
+        Value::Table(self.loaded.clone())
+    }
+
+    // call @func with @args from Rust, and return all the results
+    pub fn call(&mut self, func: Value, args: &[Value]) -> Result<Vec<Value>, LuaError> {
+        let ifunc = self.stack.len();
+        self.stack.push(to_slot(func));
+        self.stack.extend(args.iter().cloned().map(to_slot));
+
+        let base = self.base;
+        let ntbc = self.tbc_list.len();
+        self.base = ifunc + 1;
+        let nret = match panic::catch_unwind(AssertUnwindSafe(|| self.do_call_function(0))) {
+            Ok(nret) => nret,
+            Err(e) => {
+                // close to-be-closed variables in the unwound frames, whose
+                // values are still on the stack, and continue unwinding
+                self.base = base;
+                let e = self.close_tbc_by_error(ntbc, e);
+                self.stack.truncate(ifunc);
+                panic::resume_unwind(e);
+            }
+        };
+        self.base = base;
+        self.tbc_list.truncate(ntbc); // left by `os.exit()`
+
+        let rets = match nret {
+            Ok(nret) => self.stack.drain(self.stack.len() - nret ..).map(from_slot).collect(),
+            Err(_) => Vec::new(),
+        };
+        self.stack.truncate(ifunc);
+        nret.map(|_| rets)
+    }
+
+    // Compile the main chunk into a function, with @env as its `_ENV`
+    // upvalue, or the global environment if None. Return the error
+    // message, prefixed with @chunkname, if fails.
+    pub fn load(&self, input: impl Read, chunkname: &str, env: Option<Value>) -> Result<Value, String> {
+        let mut proto = parse::load(input, self.peephole, self.optimize).map_err(|msg| format!("{}: {msg}", chunk_id(chunkname)))?;
+        self.strings.borrow_mut().intern_proto(&mut proto);
+
+        let env = env.unwrap_or_else(|| self.globals());
+        let upvalues = proto.upindexes.iter().enumerate().map(|(i, _)| {
+            let v = if i == 0 { env.clone() } else { Value::Nil };
+            Rc::new(RefCell::new(Upvalue::Closed(v)))
+        }).collect();
+
+        let c = LuaClosure {
+            proto: Rc::new(proto),
+            upvalues,
+        };
+        Ok(Value::LuaClosure(Rc::new(c)))
+    }
+
+    // add a searcher for `require`, after the built-in ones
+    pub fn add_module_loader(&mut self, loader: impl ModuleLoader + 'static) {
+        let package = self.loaded.borrow().index(&"package".into()).clone();
+        lib_package::add_searcher(&package, loader);
+    }
+}
+
+fn close_metamethod(v: &Value) -> Value {
+    match v.metatable() {
+        Some(mt) => mt.borrow().index(&"__close".into()).clone(),
+        None => Value::Nil,
+    }
+}
+
+// error object from the panic payload
+fn error_value(e: &(dyn Any + Send)) -> Value {
+    if let Some(msg) = e.downcast_ref::<String>() {
+        msg.as_str().into()
+    } else if let Some(msg) = e.downcast_ref::<&str>() {
+        (*msg).into()
+    } else {
+        Value::Nil
+    }
+}
+
+// chunk name in messages: "=name" for literal name, "@name" for file
+// name, and otherwise the source code itself
+fn chunk_id(chunkname: &str) -> String {
+    if let Some(name) = chunkname.strip_prefix('=').or_else(|| chunkname.strip_prefix('@')) {
+        return name.to_string();
+    }
+    let line = chunkname.lines().next().unwrap_or("");
+    if line.len() < chunkname.len() || line.len() > 40 {
+        let end = (0..=line.len().min(40)).rev().find(|&i| line.is_char_boundary(i)).unwrap();
+        format!("[string \"{}...\"]", &line[..end])
+    } else {
+        format!("[string \"{line}\"]")
     }
 }
 
@@ -923,14 +1269,17 @@ fn exe_binop(v1: &Value, v2: &Value, arith_i: fn(i64,i64)->i64, arith_f: fn(f64,
         (&Value::Integer(i1), &Value::Float(f2)) => Value::Float(arith_f(i1 as f64, f2)),
         (&Value::Float(f1), &Value::Float(f2)) => Value::Float(arith_f(f1, f2)),
         (&Value::Float(f1), &Value::Integer(i2)) => Value::Float(arith_f(f1, i2 as f64)),
-        (_, _) => todo!("meta"),
+        (_, _) => {
+            let (n1, n2) = arith_coerce(v1, v2);
+            exe_binop(&n1, &n2, arith_i, arith_f)
+        }
     }
 }
 fn exe_binop_int(v1: &Value, i2: u8, arith_i: fn(i64,i64)->i64, arith_f: fn(f64,f64)->f64) -> Value {
     match v1 {
         &Value::Integer(i1) => Value::Integer(arith_i(i1, i2 as i64)),
         &Value::Float(f1) => Value::Float(arith_f(f1, i2 as f64)),
-        _ => todo!("meta"),
+        _ => exe_binop(v1, &Value::Integer(i2 as i64), arith_i, arith_f),
     }
 }
 
@@ -940,7 +1289,10 @@ fn exe_binop_f(v1: &Value, v2: &Value, arith_f: fn(f64,f64)->f64) -> Value {
         (&Value::Integer(i1), &Value::Float(f2)) => (i1 as f64, f2),
         (&Value::Float(f1), &Value::Float(f2)) => (f1, f2),
         (&Value::Float(f1), &Value::Integer(i2)) => (f1, i2 as f64),
-        (_, _) => todo!("meta"),
+        (_, _) => {
+            let (n1, n2) = arith_coerce(v1, v2);
+            return exe_binop_f(&n1, &n2, arith_f);
+        }
     };
     Value::Float(arith_f(f1, f2))
 }
@@ -948,7 +1300,7 @@ fn exe_binop_int_f(v1: &Value, i2: u8, arith_f: fn(f64,f64)->f64) -> Value {
     let f1 = match v1 {
         &Value::Integer(i1) => i1 as f64,
         &Value::Float(f1) => f1,
-        _ => todo!("meta"),
+        _ => return exe_binop_f(v1, &Value::Integer(i2 as i64), arith_f),
     };
     Value::Float(arith_f(f1, i2 as f64))
 }
@@ -956,22 +1308,56 @@ fn exe_binop_int_f(v1: &Value, i2: u8, arith_f: fn(f64,f64)->f64) -> Value {
 fn exe_binop_i(v1: &Value, v2: &Value, arith_i: fn(i64,i64)->i64) -> Value {
     let (i1, i2) = match (v1, v2) {
         (&Value::Integer(i1), &Value::Integer(i2)) => (i1, i2),
-        (&Value::Integer(i1), &Value::Float(f2)) => (i1, ftoi(f2).unwrap()),
-        (&Value::Float(f1), &Value::Float(f2)) => (ftoi(f1).unwrap(), ftoi(f2).unwrap()),
-        (&Value::Float(f1), &Value::Integer(i2)) => (ftoi(f1).unwrap(), i2),
-        (_, _) => todo!("meta"),
+        (_, _) => bitwise_coerce(v1, v2),
     };
     Value::Integer(arith_i(i1, i2))
 }
 fn exe_binop_int_i(v1: &Value, i2: u8, arith_i: fn(i64,i64)->i64) -> Value {
     let i1 = match v1 {
         &Value::Integer(i1) => i1,
-        &Value::Float(f1) => ftoi(f1).unwrap(),
-        _ => todo!("meta"),
+        _ => bitwise_coerce(v1, &Value::Integer(0)).0,
     };
     Value::Integer(arith_i(i1, i2 as i64))
 }
 
+// Convert string operands of arithmetic to numbers.
+// Other operand types need metamethods, which are not supported yet.
+fn arith_coerce(v1: &Value, v2: &Value) -> (Value, Value) {
+    match (v1.to_number(), v2.to_number()) {
+        (Some(n1), Some(n2)) => (n1, n2),
+        (None, _) => panic!("attempt to perform arithmetic on a {} value", v1.ty()),
+        (_, None) => panic!("attempt to perform arithmetic on a {} value", v2.ty()),
+    }
+}
+// Convert operands of bitwise operation to integers. Floats and
+// numeric strings are accepted only if they have exact integer values.
+fn bitwise_coerce(v1: &Value, v2: &Value) -> (i64, i64) {
+    match (v1.to_integer(), v2.to_integer()) {
+        (Some(i1), Some(i2)) => (i1, i2),
+        _ => {
+            let v = if v1.to_number().is_none() { v1 } else { v2 };
+            if v.to_number().is_none() {
+                panic!("attempt to perform bitwise operation on a {} value", v.ty());
+            }
+            panic!("number has no integer representation");
+        }
+    }
+}
+
+// Compare numbers or strings, and other types need metamethods, which
+// are not supported yet. Return None if any operand is NaN.
+fn compare(v1: &Value, v2: &Value) -> Option<Ordering> {
+    match (v1, v2) {
+        (Value::Integer(_) | Value::Float(_), Value::Integer(_) | Value::Float(_)) =>
+            v1.partial_cmp(v2),
+        (Value::ShortStr(_, _) | Value::MidStr(_) | Value::LongStr(_),
+                Value::ShortStr(_, _) | Value::MidStr(_) | Value::LongStr(_)) =>
+            v1.partial_cmp(v2),
+        _ if v1.ty() == v2.ty() => panic!("attempt to compare two {} values", v1.ty()),
+        _ => panic!("attempt to compare {} with {}", v1.ty(), v2.ty()),
+    }
+}
+
 fn for_check<T: PartialOrd>(i: T, limit: T, is_step_positive: bool) -> bool {
     if is_step_positive {
         i <= limit
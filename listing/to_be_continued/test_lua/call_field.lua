@@ -0,0 +1,39 @@
+-- calls without argument through fields, which are fused into
+-- CallField and CallUpField by the peephole optimizer
+
+local n = 0
+function inc() n = n + 1 end
+local t = {inc = inc, get = function() return n, n * 2 end}
+
+-- statements
+inc()
+t.inc()
+print(n)
+
+-- all return values are used
+print(t.get())
+print(t.get(), t.get())
+local a = {t.get()}
+print(#a, a[1], a[2])
+
+-- in loops
+for i = 1, 3 do
+  t.inc()
+  inc()
+end
+print(n)
+
+-- the called function changes the field
+t.swap = function() t.swap = inc end
+t.swap()
+t.swap()
+print(n)
+
+-- the callees are inlined by -O, with the table in an upvalue becoming
+-- a local variable
+local m = {f = inc}
+local function g() m.f() end
+local function h() inc() inc() end
+g()
+h()
+print(n)
@@ -0,0 +1,69 @@
+-- `<close>` variables, closed in reverse order when they go out of
+-- scope by the block end, break, goto, return or an error
+
+local mt = {__close = function(v, err) print("close", v[1], err) end}
+local function closable(name)
+  return setmetatable({name}, mt)
+end
+
+do
+  local a <close> = closable("a")
+  local b <close> = closable("b")
+  local c <close> = nil -- ignored
+  local d <close> = false -- ignored
+  print("block")
+end
+
+-- break and goto
+for i = 1, 3 do
+  local x <close> = closable("loop" .. i)
+  if i == 2 then
+    break
+  end
+  print("iteration", i)
+end
+
+do
+  local i = 1
+  ::again::
+  do
+    local y <close> = closable("goto" .. i)
+    i = i + 1
+    if i <= 2 then
+      goto again
+    end
+  end
+  print("after goto")
+end
+
+while true do
+  local z <close> = closable("while")
+  do
+    local w <close> = closable("nested")
+    break
+  end
+end
+
+-- return, after the return values are evaluated
+local function f()
+  local r <close> = closable("return")
+  return r[1] .. "ed"
+end
+print(f())
+
+local function g(n)
+  local t <close> = closable("tail" .. n)
+  if n > 0 then
+    return g(n - 1)
+  end
+  return n
+end
+print(g(2))
+
+-- error, which ends this script after closing the variables
+do
+  local e1 <close> = closable("error1")
+  local e2 <close> = closable("error2")
+  print("before error")
+  local _ = 1 + nil
+end
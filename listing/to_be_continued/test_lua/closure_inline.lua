@@ -0,0 +1,55 @@
+-- the same results with and without the optimizers (`-p` and `-O`)
+-- for closures and calls which may be inlined
+
+-- captured local in scope, which may be inlined
+local k = 3
+local function add(x) return x + k end
+local x, y = add(1), add(2)
+print(x, y)
+k = 10
+x = add(1)
+print(x)
+
+-- the captured local is closed, and its register is reused by `b`
+local f
+do
+  local a = 1
+  f = function() return a end
+end
+local b = 2
+local r = f()
+print(r, b)
+
+-- closed in loop
+local fs = {}
+for i = 1, 3 do
+  local v = i * 10
+  fs[i] = function() return v end
+end
+print(fs[1](), fs[2](), fs[3]())
+
+-- upvalue assigned by the callee
+local n = 0
+local function inc() n = n + 1 return n end
+inc() inc()
+local m = inc()
+print(m, n)
+
+-- closure created in a loop, and called after the block is closed
+local g
+for i = 1, 2 do
+  local c = i
+  g = function(x) return c + x end
+end
+local d = 100
+local e = g(1)
+print(e, d)
+
+-- recursive local function
+local function fib(n) if n < 2 then return n end return fib(n - 1) + fib(n - 2) end
+print(fib(15))
+
+-- more arguments and less than parameters
+local function sum3(a, b, c) return (a or 0) + (b or 0) + (c or 0) end
+local s1, s2, s3, s4 = sum3(1), sum3(1, 2), sum3(1, 2, 3), sum3(1, 2, 3, 4)
+print(s1, s2, s3, s4)
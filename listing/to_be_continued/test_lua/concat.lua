@@ -0,0 +1,40 @@
+-- long concatenation chains, merged into Concat byte codes which take
+-- at most 255 operands
+
+local function chain(n)
+  local code = "x = 'a'"
+  for i = 2, n do code = code .. " .. g" end
+  return load(code)
+end
+
+-- the global variable `g`, so all the 256 registers are for operands
+g = 'b'
+
+for _, n in ipairs({2, 254, 255, 256}) do
+  chain(n)()
+  local expect = 'a'
+  for i = 2, n do expect = expect .. 'b' end
+  print(n, #x, x == expect)
+end
+
+-- with numbers and constant folding
+local y = 1 .. 2 .. g .. 3.5 .. 'z' .. 4
+print(y, #y)
+
+-- concatenation as table key, whose operands are in the registers
+-- where the table would be loaded
+local s = "a"
+T = {}
+T[s .. s] = 1
+print(T[s .. s], T.aa)
+local t = {x = {}}
+t.x[s .. s] = 2
+print(t.x[s .. s], t.x.aa)
+local u = {}
+local function set() u[s .. s] = 3 end
+set()
+print(u[s .. s], u.aa)
+local function id(v) return v end
+T[id(1)] = 4
+T[t.x.aa + 1] = 5
+print(T[1], T[3], T[id(s) .. s])
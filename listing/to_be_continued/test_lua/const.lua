@@ -0,0 +1,41 @@
+-- `<const>` local variables, which are folded if initialized by
+-- constant expressions, and can not be assigned
+
+local N <const> = 10
+local S <const> = "str"
+local F <const> = N * 2 + 0.5
+local T <const> = {N, S} -- not compile-time constant
+local a, b <const>, c = 1, N + 1, 3
+print(N, S, F, T[1], T[2], a, b, c)
+
+-- the table is constant, but not its content
+T[1] = "changed"
+print(T[1])
+
+-- constants in nested functions and loops
+local function f(x)
+  return x * N, S
+end
+print(f(2))
+local sum = 0
+for i = 1, N do
+  sum = sum + i * b
+end
+print(sum)
+
+-- shadowed by a non-constant variable
+do
+  local N = 1
+  N = N + 1
+  print(N)
+end
+print(N)
+
+-- assignments are compile errors, returned by load()
+print(load("local x <const> = 1; x = 2"))
+print(load("local x <const> = {}; x = nil"))
+print(load("local x <const> = 1; local function f() x = 2 end"))
+print(load("local x <const> = 1; local function f() return function() x = 2 end end"))
+print(load("local y, x <const> = 1, 2; y, x = x, y"))
+print(load("local x <close> = nil; x = 1"))
+print(load("local x <foo> = 1"))
@@ -0,0 +1,67 @@
+-- inline caches of global and field accesses, which must see the
+-- changes of the tables and of `_ENV`
+
+-- keys added, changed and removed between executions of the same code
+local function get() return gv end
+print(get())
+gv = 1
+print(get())
+gv = 2
+print(get())
+gv = nil
+print(get())
+for i = 1, 3 do
+  other = i -- more keys, which may move the entries
+  gv = i * 10
+  print(get())
+end
+
+-- table fields, with the same code on different tables
+local function field(t) return t.k end
+local function setfield(t, v) t.k = v end
+local t1, t2 = {k = 1}, {a = 1, b = 2, k = 2}
+for i = 1, 2 do
+  print(field(t1), field(t2), field({}))
+end
+setfield(t1, "x")
+setfield(t2, nil)
+print(field(t1), field(t2))
+setfield(t2, "y")
+print(field(t1), field(t2))
+for i = 1, 20 do
+  t1["k" .. i] = i -- rehash
+end
+print(field(t1), t1.k20)
+
+-- _ENV reassigned in a function
+local function swap(env)
+  local _ENV = env
+  return function() return gv, print end
+end
+local envf = swap({gv = "env", print = "no print"})
+gv = "global"
+print(envf())
+print(get())
+
+-- _ENV reassigned in a loop, with the same instruction reading
+-- different tables
+local envs = {{name = "a"}, {name = "b", extra = 1}, {}}
+do
+  local p = print
+  for i = 1, 3 do
+    local _ENV = envs[i]
+    p(name)
+    name = "set" .. i
+  end
+end
+print(envs[1].name, envs[2].name, envs[3].name, name)
+
+-- the whole chunk switching to another environment
+local G = _G
+gv = "old"
+_ENV = {print = print, gv = "new"}
+print(get(), gv)
+gv = "changed"
+print(G.gv, gv)
+_ENV = G
+print(get(), gv)
@@ -0,0 +1,34 @@
+-- integer for-loops near the bounds must stop instead of overflowing
+local n = 0
+for i = math.maxinteger - 1, math.maxinteger do
+  print(i)
+  n = n + 1
+end
+print(n)
+
+n = 0
+for i = math.mininteger + 1, math.mininteger, -1 do
+  print(i)
+  n = n + 1
+end
+print(n)
+
+n = 0
+for i = math.maxinteger - 5, math.maxinteger, 4 do
+  print(i)
+  n = n + 1
+end
+print(n)
+
+n = 0
+for i = math.mininteger, math.mininteger + 2, math.maxinteger do
+  print(i)
+  n = n + 1
+end
+print(n)
+
+n = 0
+for i = 1, 3 do
+  n = n + i
+end
+print(n)
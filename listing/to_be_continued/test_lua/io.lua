@@ -0,0 +1,72 @@
+-- file handles of io library, with temporary files so the output
+-- does not depend on the working directory
+
+local name = os.tmpname()
+local NL = utf8.char(10) -- not in string constants, which are traced
+
+-- write and read back by formats
+local f = io.open(name, "w")
+print(io.type(f), io.type(io.stdout), io.type(42))
+print(f:write("line1" .. NL, 42, " ", 1.5, NL) == f)
+f:write("0x10 -3.5e1 rest", NL)
+f:write("last")
+f:close()
+print(io.type(f))
+
+f = io.open(name)
+print(f:read())
+print(f:read("L"))
+print(f:read("n", "*n"))
+print(f:read(5), f:read(0))
+print(f:read("a"))
+print(f:read("a"), f:read("l"), f:read(1))
+f:close()
+
+-- seek
+f = io.open(name, "r+")
+print(f:seek("end"))
+print(f:seek("set", 2), f:read(3))
+print(f:seek("cur"), f:seek())
+f:seek("set")
+f:write("LINE")
+f:seek("set")
+print(f:read())
+f:close()
+
+-- lines of a file, closed at the end
+for l in io.lines(name) do
+  print(l)
+end
+for a, b in io.lines(name, 1, "l") do
+  print(a, b)
+end
+
+-- lines of a handle, kept open
+f = io.open(name)
+for n in f:lines("n") do
+  print(n)
+end
+print(io.type(f), f:read("a"))
+f:close()
+
+-- default input and output
+io.output(name)
+io.write("via ", "io.write", NL)
+io.output():close()
+io.output(io.stdout)
+io.input(name)
+print(io.read("a"))
+io.input():close()
+io.input(io.stdin)
+
+-- temporary file, removed when closed
+local t = io.tmpfile()
+t:write("tmp", 1, 2)
+t:seek("set")
+print(t:read("a"))
+t:close()
+
+-- errors are returned
+print(io.open("/nonexistent/file"))
+print(os.remove(name))
+print(io.open(name) == nil)
@@ -0,0 +1,61 @@
+-- load(), loadfile() and dofile(), with the global environment or a
+-- custom one as the `_ENV` upvalue of the loaded chunk
+
+x = "global"
+
+-- string chunks
+local f = load("return x, ...")
+print(f(1, 2))
+local env = {x = "env"}
+f = load("y = x; return x", "=chunk", "t", env)
+print(f(), env.y, y)
+f = load("return _ENV", "chunk", "t", nil) -- nil environment
+print(f())
+
+-- the environment is shared by the functions of the chunk
+f = load("function get() return v end; function set(a) v = a end", "=c", "t", env)
+f()
+env.set(10)
+print(env.get(), env.v, get, v)
+
+-- reader functions, called until nil or empty string
+local pieces = {"return ", "x", " .. ", "'!'"}
+local i = 0
+f = load(function()
+  i = i + 1
+  return pieces[i]
+end, "=reader", "t", env)
+print(f())
+i = 0
+f = load(function()
+  i = i + 1
+  if i == 1 then return "return 1" end
+  return ""
+end)
+print(f())
+
+-- _ENV reassignment inside the chunk
+f = load("local t = {print = print}; _ENV = t; z = 5; print(z, x); return t")
+print(f().z, z)
+
+-- errors are returned
+print(load("x ="))
+print(load("return 1", "=text", "b"))
+print(load(function() return 1 end))
+print(loadfile("/nonexistent/file"))
+
+-- files
+local name = os.tmpname()
+local NL = utf8.char(10) -- not in string constants, which are traced
+local file = io.open(name, "w")
+file:write("local a = ... or 'none'", NL, "w = x", NL, "return a, x")
+file:close()
+
+print(dofile(name))
+print(w)
+f = loadfile(name, "t", env)
+print(f("arg"))
+print(env.w)
+f = loadfile(name, "bt", {x = "other"})
+print(f())
+os.remove(name)
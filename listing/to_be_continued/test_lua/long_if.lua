@@ -0,0 +1,47 @@
+-- `if` bodies and `and`/`or` operands longer than the i16 offsets of
+-- TestAndJump/TestOrJump
+
+-- @n statements of `n = n + 1`, by doubling
+local function body(n)
+  local s = " n = n + 1"
+  local b = ""
+  while n > 0 do
+    if n % 2 == 1 then b = b .. s end
+    s = s .. s
+    n = n // 2
+  end
+  return b
+end
+-- table constructor of @n entries
+local function array(n)
+  local s = "g, "
+  local b = "{"
+  while n > 0 do
+    if n % 2 == 1 then b = b .. s end
+    s = s .. s
+    n = n // 2
+  end
+  return b .. "}"
+end
+
+local long = body(40000)
+local f = load("local c, n = ... n = 0 if c then" .. long .. " end return n")
+print(f(true), f(false))
+
+f = load("local c, n = ... n = 0 if c == 1 then" .. long .. " elseif c == 2 then n = -1 elseif c then"
+  .. long .. long .. " else n = -2 end return n")
+print(f(1), f(2), f(3), f(false))
+
+f = load("local c, d, n = ... n = 0 while c do" .. long .. " if d then break end end return n")
+print(f(true, true), f(false))
+
+f = load("local c, n = ... n = 0 repeat" .. long .. " until c or n > 100000 return n")
+print(f(true), f(false))
+
+g = 1
+f = load("local c = ... local x = c and #" .. array(40000) .. " return x")
+print(f(true), f(false), f(nil))
+f = load("local c = ... local x = c or #" .. array(40000) .. " return x")
+print(f(true), f(false), f(nil))
+f = load("local c, d, n = ... n = 0 if c and d then" .. long .. " end return n")
+print(f(true, true), f(true, false), f(false, true))
@@ -0,0 +1,108 @@
+-- values of all types kept unchanged through the stack, constants,
+-- upvalues and tables, as they are packed with the `nan-boxing`
+-- feature: integers inside and out of 48 bits, special floats,
+-- booleans, nil, strings of each size, functions and tables
+
+local nan = 0 / 0
+local function f() return 1 end
+local t0 = {}
+local N = 24
+local values = {
+  0, 1, -1,
+  140737488355327, 140737488355328, -140737488355328, -140737488355329,
+  math.maxinteger, math.mininteger,
+  0.0, -0.0, 1.5, 2^53, 1e300 * 1e300, -1e300 * 1e300, nan,
+  true, false, nil,
+  "short", "a middle string, 15 to 47 bytes", "a long string, of 48 bytes or more than that, not interned",
+  print, f,
+}
+values[N + 1] = t0
+
+local function show(v)
+  if v ~= v then
+    return "nan"
+  elseif v == 0 and math.type(v) == "float" then
+    return 1 / v > 0 and "0.0" or "-0.0"
+  elseif v == print or v == f or v == t0 then
+    return type(v)
+  end
+  return tostring(v)
+end
+
+local function same(a, b)
+  if a ~= a then
+    return b ~= b
+  end
+  return a == b and math.type(a) == math.type(b) and show(a) == show(b)
+end
+
+for i = 1, N + 1 do
+  local v = values[i]
+  print(i, show(v), math.type(v) or type(v))
+end
+
+-- through the stack: arguments, returns and locals
+local function id(...) return ... end
+local ok = 0
+for i = 1, N + 1 do
+  local v = values[i]
+  local a, b = id(v, v)
+  if same(a, v) and same(b, v) then ok = ok + 1 end
+end
+print("stack", ok)
+
+-- through the array part, by index and by the constructor
+local t = {}
+for i = 1, N + 1 do
+  t[i] = values[i]
+end
+local ctor = {nil, 140737488355328, "s", id(-0.0, math.mininteger, nan)}
+ok = 0
+for i = 1, N + 1 do
+  if same(t[i], values[i]) then ok = ok + 1 end
+end
+print("array", ok, ctor[1], ctor[2], ctor[3], show(ctor[4]), ctor[5], show(ctor[6]))
+
+-- through the map part, as values and as keys
+local m = {}
+for i = 1, N + 1 do
+  m["k" .. i] = values[i]
+  if values[i] ~= nil and values[i] == values[i] then
+    m[values[i]] = i
+  end
+end
+ok = 0
+for i = 1, N + 1 do
+  local v = values[i]
+  if same(m["k" .. i], v) and (v == nil or v ~= v or m[v] == i or v == 0) then ok = ok + 1 end
+end
+print("map", ok, m[0], m[-0.0], m[1.0], m[2^53], m[math.mininteger])
+
+-- through upvalues, open and closed
+local function capture(v)
+  local function get() return v end
+  local a = get()
+  v = v
+  return a, get
+end
+ok = 0
+for i = 1, N + 1 do
+  local a, get = capture(values[i])
+  if same(a, values[i]) and same(get(), values[i]) then ok = ok + 1 end
+end
+print("upvalue", ok)
+
+-- constants compared with the values built at runtime
+print(140737488355328 == 2^47 // 1, -140737488355329 == -(2^47 // 1) - 1)
+print(9223372036854775807 == math.maxinteger, math.maxinteger + 1 == math.mininteger)
+print(140737488355327 + 1, -140737488355328 - 1, math.mininteger // -1)
+print(1e300 * 1e300 == math.huge, -0.0 == 0, nan == nan)
+
+-- nil holes in arrays
+local holes = {1, nil, 3, nil, nil, 6}
+holes[8] = 8
+local line = ""
+for i = 1, 8 do
+  line = line .. tostring(holes[i]) .. " "
+end
+print(line)
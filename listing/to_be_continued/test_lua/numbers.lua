@@ -8,3 +8,12 @@ print(123.0e10)
 print(123.0e-10)
 print(.123E-01)
 print(-.123E-01)
+
+-- string to number, the sign is allowed only at the beginning and in
+-- the exponent
+print(tonumber("-1"), tonumber(" +1 "), tonumber("-0x10"), tonumber("-1.5"))
+print(tonumber("1e+2"), tonumber("-1E-2"), tonumber("+.5e+1"), tonumber("0x1p-1"))
+print(tonumber("-+1"), tonumber("+-1"), tonumber("--1"), tonumber("++1"))
+print(tonumber("1-"), tonumber("1e2+"), tonumber("1.5-3"), tonumber("1e+-2"))
+print(tonumber("- 1"), tonumber("-"), tonumber("e+1"), tonumber("1e"))
+print("-1" + 0, "1e+2" + 0, " -0x10 " * 1)
@@ -0,0 +1,103 @@
+-- the same results with and without the IR optimizer (`-O`), for
+-- constant propagation, dead stores, hoisting and inlining
+
+-- constants propagated across statements and branches
+local a = 2
+local b = a * 3 + 1
+if b == 7 then
+  print("folded", b)
+else
+  print("not reached")
+end
+local c
+if b > 10 then c = 1 else c = 2 end
+print(c, c + b)
+local d = 1
+for i = 1, 3 do
+  d = d + i -- not constant in the loop
+end
+print(d)
+local s = "x"
+s = s .. "y"
+print(s, 7 // 2, 7 % -3, 2^10, 1 / 0, -(-9223372036854775807 - 1))
+
+-- dead stores
+local e = 1
+e = 2
+e = e + 1
+print(e)
+local unused = {}
+unused = nil
+print(unused)
+
+-- lookups of global variables hoisted out of loops
+g = 1
+local sum = 0
+for i = 1, 5 do
+  sum = sum + g + i
+end
+print(sum)
+
+-- but not if the loop writes the variable
+sum = 0
+for i = 1, 5 do
+  sum = sum + g
+  g = g + 1
+end
+print(sum, g)
+
+-- or calls a function, which may write it
+local function bump() g = g * 2 end
+sum = 0
+for i = 1, 4 do
+  sum = sum + g
+  bump()
+end
+print(sum, g)
+
+-- or through another name of the environment
+sum = 0
+local i = 0
+while i < 3 do
+  sum = sum + g
+  _ENV.g = 1
+  i = i + 1
+end
+print(sum, g)
+
+-- nested loops, where the inner one is hoisted out of the outer one
+h = 10
+sum = 0
+for i = 1, 3 do
+  for j = 1, 3 do
+    sum = sum + h * i + j
+  end
+end
+print(sum)
+
+-- loops that never run
+for i = 1, 0 do
+  print(undefined_global.x)
+end
+while false do
+  print(undefined_global.x)
+end
+
+-- small local functions inlined into their callers
+local function sq(x) return x * x end
+local function pair(x, y) return y, x end
+local function none() end
+print(sq(3), sq(sq(2)), sq(1.5))
+print(pair(1, 2))
+print(pair(1))
+print(pair(1, 2, 3), pair(4, 5))
+print(none(), none())
+local p, q = pair("p", "q")
+print(p, q)
+
+-- not inlined: recursive, escaping and vararg functions
+local function fact(n) if n <= 1 then return 1 end return n * fact(n - 1) end
+local function esc(x) return x + 1 end
+local t = {f = esc}
+local function va(...) return ... end
+print(fact(10), esc(1), t.f(2), va(1, 2, 3))
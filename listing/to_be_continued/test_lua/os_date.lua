@@ -0,0 +1,11 @@
+-- os.date() with UTC time, so the output does not depend on local timezone
+print(os.date("!%Y-%m-%d %H:%M:%S", 0))
+print(os.date("!%c", 86400 * 365))
+print(os.date("!%a %A %b %B %j %U %W %V %G", 1700000000))
+print(os.date("!é%Y", 0))
+print(os.date("!日付: %F ✓", 1700000000))
+local t = os.date("!*t", 1700000000)
+print(t.year, t.month, t.day, t.hour, t.min, t.sec, t.wday, t.yday)
+-- only exact "*t" returns a table, otherwise it is a format
+print(os.date("!*t!", 0), os.date("!*tx %Y", 0))
+print(os.difftime(1700000000, 1600000000), os.difftime(0, 60))
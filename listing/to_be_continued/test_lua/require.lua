@@ -0,0 +1,46 @@
+-- require, package.preload, package.path and package.searchpath
+
+-- preload, the loader is called with the name and ":preload:"
+package.preload.pre = function(name, extra)
+  return {name = name, extra = extra}
+end
+local m, extra = require "pre"
+print(m.name, m.extra, extra)
+print(require "pre" == m, package.loaded.pre == m)
+
+-- a module returning nothing is loaded as true
+package.preload.empty = function() end
+print(require "empty", package.loaded.empty)
+
+-- package.path, the loader is called with the name and file name
+local base = os.tmpname()
+local file = base .. "_mod.lua"
+local f = io.open(file, "w")
+f:write("local name, file = ... count = (count or 0) + 1 return {name = name, file = file}")
+f:close()
+
+package.path = "./no/such/?.lua;" .. base .. "_?.lua"
+print(package.searchpath("mod", package.path) == file)
+m, extra = require "mod"
+print(m.name, m.file == file, extra == file, count)
+require "mod"
+print(count)
+os.remove(file)
+os.remove(base)
+
+-- messages of searchers, joined by require when failing
+print(package.searchpath("a.b", "./no/such/?.lua;./no/such/?/init.lua"))
+print(package.searchpath("a.b", "./no/such/?.lua", ".", "_"))
+print(package.searchpath("a.b", "./no/such/?.lua", "", ""))
+print(package.searchers[1]("nosuch"))
+package.path = "./no/such/?.lua;./no/such/?.luac"
+print(package.searchers[2]("nosuch"))
+
+-- modules embedded in the interpreter, searched at last
+local dump
+dump, extra = require "dump"
+print(extra, #package.searchers, package.searchers[3]("nosuch"))
+print(dump({1, "a", {x = true}}))
+
+-- the globals
+print(package.loaded._G == _G, _G._G == _G, _G.print == print)
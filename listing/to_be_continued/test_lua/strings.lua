@@ -0,0 +1,85 @@
+-- string equality and table keys, for short (up to 14 bytes), middle
+-- (up to 47 bytes, interned) and long strings, built by constants,
+-- concatenation and other chunks
+
+local function rep(s, n)
+  local r = ""
+  for i = 1, n do
+    r = r .. s
+  end
+  return r
+end
+
+local short = "abcdefghijklmn" -- 14 bytes
+local mid1 = "abcdefghijklmno" -- 15 bytes
+local mid2 = "abcdefghijklmnopqrstuvwxyz0123456789abcdefghijk" -- 47 bytes
+local long = "abcdefghijklmnopqrstuvwxyz0123456789abcdefghijkl" -- 48 bytes
+print(#short, #mid1, #mid2, #long)
+
+-- constants against the same strings built at runtime
+local built = {
+  rep("a", 0) .. "abcdefg" .. "hijklmn",
+  "abcdefg" .. "hijklmno",
+  "abcdefghijklmnopqrstuvwxyz" .. "0123456789" .. "abcdefghijk",
+  "abcdefghijklmnopqrstuvwxyz" .. "0123456789" .. "abcdefghijkl",
+}
+local consts = {short, mid1, mid2, long}
+for i = 1, 4 do
+  print(i, built[i] == consts[i], consts[i] == built[i], built[i] ~= consts[i])
+end
+
+-- different strings of the same length
+print(mid2 == "abcdefghijklmnopqrstuvwxyz0123456789abcdefghijK")
+print(long == "abcdefghijklmnopqrstuvwxyz0123456789abcdefghijkL")
+print(short == mid1, mid1 == mid2, mid2 == long, long == long .. "")
+
+-- table keys, set by one version and read by the other
+local t = {}
+for i = 1, 4 do
+  t[consts[i]] = i
+end
+for i = 1, 4 do
+  print(t[built[i]], t[built[i] .. "x"])
+end
+t[built[2]] = "mid"
+t[built[4]] = "long"
+print(t[mid1], t[long], t.abcdefghijklmno)
+local n = 0
+for k, v in pairs(t) do
+  n = n + 1
+end
+print(n)
+
+-- strings from another chunk
+local f = load("return 'abcdefghijklmno', 'abcdefghijklmnopqrstuvwxyz0123456789abcdefghijkl'")
+local c1, c2 = f()
+print(c1 == mid1, c2 == long, t[c1], t[c2])
+
+-- many middle strings, more than the interning table keeps
+local keys = {}
+for i = 1, 1000 do
+  local k = "middle-string-key-number-" .. i
+  keys[i] = k
+  t[k] = i
+end
+local ok = 0
+for i = 1, 1000 do
+  if t["middle-string-key-number-" .. i] == i and keys[i] == "middle-string-key-number-" .. i then
+    ok = ok + 1
+  end
+end
+print(ok, t[mid1], t["middle-string-key-number-" .. 500])
+
+-- numbers and strings are different keys
+t = {}
+t[1] = "int"
+t["1"] = "str"
+t[2.0] = "float"
+print(t[1], t["1"], t[2], t[1.0])
+
+-- functions, equal only to themselves
+local function g() end
+local function h() end
+print(print == print, print == type, g == g, g == h, g == print)
+t = {[print] = "print", [g] = "g", [h] = "h"}
+print(t[print], t[g], t[h], t[type])
@@ -0,0 +1,53 @@
+-- table constructors with mixed array and keyed entries
+
+local a = 'v'
+
+-- array entries in stack are flushed even if the last entry is keyed
+local t = {a, a, x=1}
+print(t[1], t[2], t.x, #t)
+
+t = {a, x=1, a, y=2}
+print(t[1], t[2], t.x, t.y, #t)
+
+-- more than 50 array entries, flushed every 50
+local function count(t)
+  local n = 0
+  for _ in pairs(t) do n = n + 1 end
+  return n
+end
+t = {a,a,a,a,a,a,a,a,a,a, a,a,a,a,a,a,a,a,a,a, a,a,a,a,a,a,a,a,a,a,
+     a,a,a,a,a,a,a,a,a,a, a,a,a,a,a,a,a,a,a,a, a,a, k=a}
+print(#t, count(t), t[52], t.k)
+
+-- array entries are stored at their own indexes, overriding the keyed
+-- entries with the same keys, both by byte codes and by templates
+t = {'a', 'b', [1]='m'}
+print(t[1], t[2], t[3], #t)
+t = {a, 'b', [1]='m'}
+print(t[1], t[2], t[3], #t)
+
+-- the keyed entry after 50 array entries is set after they are stored
+t = {1,2,3,4,5,6,7,8,9,10, 11,12,13,14,15,16,17,18,19,20, 21,22,23,24,25,26,27,28,29,30,
+     31,32,33,34,35,36,37,38,39,40, 41,42,43,44,45,46,47,48,49,50, [1]='map'}
+print(t[1], t[2], t[50], t[51], #t)
+t = {a,2,3,4,5,6,7,8,9,10, 11,12,13,14,15,16,17,18,19,20, 21,22,23,24,25,26,27,28,29,30,
+     31,32,33,34,35,36,37,38,39,40, 41,42,43,44,45,46,47,48,49,50, [1]='map'}
+print(t[1], t[2], t[50], t[51], #t)
+t = {1,2,3,4,5,6,7,8,9,10, 11,12,13,14,15,16,17,18,19,20, 21,22,23,24,25,26,27,28,29,30,
+     31,32,33,34,35,36,37,38,39,40, 41,42,43,44,45,46,47,48,49,50, 51, [51]='map', [52]='x'}
+print(t[50], t[51], t[52], #t)
+
+-- so many array entries that the number of batches overflows SetList
+local code = "local a = ... return {[13000]='map'"
+for i = 1, 13001 do code = code .. ", a + " .. i end
+t = load(code .. ", [2]='map'}")(0)
+print(#t, t[1], t[2], t[12750], t[12751], t[13000], t[13001])
+
+-- nested table constructors as the last array entry
+local t = {{}, {{}}}
+print(#t, #t[1], #t[2], t[1] ~= t[2])
+local a = {}
+t = {a, {a}, {a, {a}}}
+print(#t, t[1] == a, t[2][1] == a, #t[3], t[3][2][1] == a)
+t = {1, {2, {3, {}}}}
+print(#t, t[2][1], #t[2], t[2][2][1], #t[2][2][2])
@@ -0,0 +1,39 @@
+-- utf8 library, where the functions are strict by default and accept
+-- surrogates and code points up to 2^31 in lax mode
+
+local s = "aé日\u{10348}"
+print(utf8.len(s), utf8.len(s, 2), utf8.len(s, -4), utf8.len(""))
+print(utf8.codepoint(s, 1, -1))
+print(utf8.offset(s, 3), utf8.offset(s, -1), utf8.offset(s, 0, 3), utf8.offset(s, 6))
+print(utf8.char(72, 0xe9, 0x65e5, 0x10348) == "Hé日\u{10348}", utf8.char())
+
+for p, c in utf8.codes(s) do
+  print(p, c)
+end
+
+-- invalid sequences make len() fail at their position
+print(utf8.len("ab\xffcd"))
+print(utf8.len("\xe6\x97"))
+print(utf8.len("a\x80"))
+print(utf8.len("a\xc0\x80")) -- overlong
+print(utf8.len("\xe0\x80\x80"))
+
+-- surrogates and values above 0x10FFFF, only in lax mode
+local sur = "\u{D800}"
+local big = "\u{7FFFFFFF}"
+print(#sur, #big, utf8.char(0xD800) == sur, utf8.char(0x7FFFFFFF) == big)
+print(utf8.len(sur))
+print(utf8.len("a" .. big))
+print(utf8.len("ab\u{110000}"))
+print(utf8.len(sur, 1, -1, true), utf8.len(big, 1, -1, true))
+print(utf8.codepoint(sur, 1, 1, true), utf8.codepoint(big, 1, 1, true))
+
+local lax = "x" .. sur .. big .. "\u{10FFFF}"
+for p, c in utf8.codes(lax, true) do
+  print(p, c)
+end
+for p, c in utf8.codes("\u{10FFFF}y") do
+  print(p, c)
+end
+
+print(utf8.charpattern == "[\0-\x7F\xC2-\xFD][\x80-\xBF]*")